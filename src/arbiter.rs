@@ -0,0 +1,167 @@
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    vec,
+    vec::Vec,
+};
+
+use crate::drain::{Drain, Identified};
+
+/// Shares one drain between several ports, forwarding at most one request per tick. The grant
+/// rotates between ports with pending requests, and port `i` may win up to `weights[i]`
+/// consecutive slots before it moves on.
+pub struct Arbiter<D: Drain> {
+    inner: D,
+    weights: Vec<usize>,
+    pending: Vec<VecDeque<D::Req>>,
+    ready: Vec<VecDeque<D::Resp>>,
+    // Request id -> port it came from
+    owners: BTreeMap<usize, usize>,
+
+    // Port holding the grant, and how many more slots it may win in a row
+    current: usize,
+    credits: usize,
+}
+
+impl<D: Drain> Arbiter<D>
+where
+    D::Req: Identified,
+    D::Resp: Identified,
+{
+    /// Plain round-robin between `ports` ports
+    pub fn new(inner: D, ports: usize) -> Arbiter<D> {
+        Self::with_weights(inner, vec![1; ports])
+    }
+
+    pub fn with_weights(inner: D, weights: Vec<usize>) -> Arbiter<D> {
+        assert!(
+            !weights.is_empty() && !weights.contains(&0),
+            "Arbitration weights must be positive"
+        );
+        let ports = weights.len();
+        Arbiter {
+            inner,
+            credits: weights[0],
+            weights,
+            pending: (0..ports).map(|_| VecDeque::new()).collect(),
+            ready: (0..ports).map(|_| VecDeque::new()).collect(),
+            owners: BTreeMap::new(),
+            current: 0,
+        }
+    }
+
+    pub fn ports(&self) -> usize {
+        self.weights.len()
+    }
+
+    pub fn pending(&self, port: usize) -> usize {
+        self.pending[port].len()
+    }
+
+    pub fn tick(&mut self) {
+        self.tick_begin();
+        self.tick_end();
+    }
+
+    /// See `Drain::tick_begin`
+    pub fn tick_begin(&mut self) {
+        self.inner.tick_begin();
+        self.arbitrate();
+    }
+
+    pub fn tick_end(&mut self) {
+        self.inner.tick_end();
+        self.collect();
+    }
+
+    pub fn push(&mut self, port: usize, req: D::Req) {
+        if self.owners.insert(req.id(), port).is_some() {
+            panic!("Duplicated inflight request id: {}", req.id());
+        }
+        self.pending[port].push_back(req);
+    }
+
+    pub fn pop(&mut self, port: usize) -> Option<D::Resp> {
+        self.collect();
+        self.ready[port].pop_front()
+    }
+
+    fn arbitrate(&mut self) {
+        // Going around once more lets the current port win again with fresh credits when it's
+        // the only one with pending requests
+        for _ in 0..=self.ports() {
+            if self.credits > 0 {
+                if let Some(req) = self.pending[self.current].pop_front() {
+                    self.credits -= 1;
+                    self.inner.push(req);
+                    return;
+                }
+            }
+            self.current = (self.current + 1) % self.ports();
+            self.credits = self.weights[self.current];
+        }
+    }
+
+    fn collect(&mut self) {
+        while let Some(resp) = self.inner.pop() {
+            let owner = if resp.is_last() {
+                self.owners.remove(&resp.id())
+            } else {
+                self.owners.get(&resp.id()).copied()
+            };
+            let port = owner.expect("Response for unknown request id");
+            self.ready[port].push_back(resp);
+        }
+    }
+}
+
+#[test]
+fn test_weighted_arbitration() {
+    use crate::mem::*;
+
+    let mem: Mem<_, 8> = Mem::new(FixedDelay::new(1));
+    let mut arbiter = Arbiter::with_weights(mem, vec![2, 1]);
+    for port in 0..2 {
+        for i in 0..1000 {
+            arbiter.push(
+                port,
+                MemReq::read(
+                    port * 1000 + i,
+                    0x80000000 + 0x1000 * port as u64 + 8 * (i as u64 % 16),
+                ),
+            );
+        }
+    }
+
+    let mut served = [0usize; 2];
+    for _ in 0..600 {
+        arbiter.tick();
+        for (port, served) in served.iter_mut().enumerate() {
+            while arbiter.pop(port).is_some() {
+                *served += 1;
+            }
+        }
+    }
+    assert_eq!(arbiter.pending(0) + arbiter.pending(1), 1400);
+    assert!(served[0].abs_diff(2 * served[1]) <= 2, "{:?}", served);
+}
+
+#[test]
+fn test_round_robin_work_conserving() {
+    use crate::mem::*;
+
+    let mem: Mem<_, 8> = Mem::new(NoDelay::default());
+    let mut arbiter = Arbiter::new(mem, 3);
+    for id in 0..4 {
+        arbiter.push(1, MemReq::read(id, 0x80000000));
+    }
+    // An idle port doesn't take up slots
+    for _ in 0..4 {
+        arbiter.tick();
+    }
+    assert_eq!(arbiter.pending(1), 0);
+    let mut ids = Vec::new();
+    while let Some(resp) = arbiter.pop(1) {
+        ids.push(resp.id);
+    }
+    assert_eq!(ids, [0, 1, 2, 3]);
+}
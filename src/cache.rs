@@ -0,0 +1,436 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+
+use crate::drain::{Drain, ProbeResult};
+use crate::mem::{MemReq, MemResp};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FillPolicy {
+    AllocateOnReadMiss,
+    NoAllocate,
+}
+
+/// Whether `req` reads the whole line, so that its response can be cached
+fn is_full_read<const WIDTH: usize>(req: &MemReq<WIDTH>) -> bool {
+    req.size == WIDTH && req.rbe.is_none_or(|rbe| !rbe.contains(&false))
+}
+
+/// `line` as `Mem` would return it for `req`, with disabled bytes and those past `size` zeroed
+fn masked<const WIDTH: usize>(mut line: [u8; WIDTH], req: &MemReq<WIDTH>) -> [u8; WIDTH] {
+    if let Some(rbe) = req.rbe {
+        for (c, be) in line.iter_mut().zip(rbe.iter()) {
+            if !*be {
+                *c = 0;
+            }
+        }
+    }
+    line[req.size..].fill(0);
+    line
+}
+
+pub struct WriteThroughCache<D, const WIDTH: usize>
+where
+    D: Drain<Req = MemReq<WIDTH>, Resp = MemResp<WIDTH>>,
+{
+    inner: D,
+    capacity: usize,
+    policy: FillPolicy,
+
+    lines: BTreeMap<u64, [u8; WIDTH]>,
+    order: VecDeque<u64>,
+    fills: BTreeMap<usize, u64>,
+    hit_resps: VecDeque<MemResp<WIDTH>>,
+
+    hits: u64,
+    misses: u64,
+    writethroughs: u64,
+}
+
+impl<D, const WIDTH: usize> WriteThroughCache<D, WIDTH>
+where
+    D: Drain<Req = MemReq<WIDTH>, Resp = MemResp<WIDTH>>,
+{
+    pub fn new(inner: D, capacity: usize, policy: FillPolicy) -> Self {
+        WriteThroughCache {
+            inner,
+            capacity,
+            policy,
+            lines: BTreeMap::new(),
+            order: VecDeque::new(),
+            fills: BTreeMap::new(),
+            hit_resps: VecDeque::new(),
+            hits: 0,
+            misses: 0,
+            writethroughs: 0,
+        }
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    pub fn writethroughs(&self) -> u64 {
+        self.writethroughs
+    }
+
+    fn fill(&mut self, addr: u64, data: [u8; WIDTH]) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.lines.insert(addr, data).is_none() {
+            self.order.push_back(addr);
+            if self.order.len() > self.capacity {
+                let victim = self.order.pop_front().unwrap();
+                self.lines.remove(&victim);
+            }
+        }
+    }
+}
+
+impl<D, const WIDTH: usize> Drain for WriteThroughCache<D, WIDTH>
+where
+    D: Drain<Req = MemReq<WIDTH>, Resp = MemResp<WIDTH>>,
+{
+    type Req = MemReq<WIDTH>;
+    type Resp = MemResp<WIDTH>;
+
+    fn tick(&mut self) {
+        self.inner.tick();
+    }
+
+    fn tick_begin(&mut self) {
+        self.inner.tick_begin();
+    }
+
+    fn tick_end(&mut self) {
+        self.inner.tick_end();
+    }
+
+    fn push(&mut self, req: MemReq<WIDTH>) {
+        if req.wbe.contains(&true) {
+            self.writethroughs += 1;
+            if let Some(line) = self.lines.get_mut(&req.addr) {
+                for (c, (w, be)) in line.iter_mut().zip(req.wdata.iter().zip(req.wbe.iter())) {
+                    if *be {
+                        *c = *w;
+                    }
+                }
+            }
+            // An outstanding fill for this line may carry data from before this write
+            self.fills.retain(|_, addr| *addr != req.addr);
+            self.inner.push(req);
+        } else if let Some(line) = self.lines.get(&req.addr) {
+            self.hits += 1;
+            self.hit_resps.push_back(MemResp {
+                id: req.id,
+                rdata: masked(*line, &req),
+                beat: 0,
+                last: true,
+                src: req.src,
+            });
+        } else {
+            self.misses += 1;
+            // A partial read doesn't bring in the whole line
+            if self.policy == FillPolicy::AllocateOnReadMiss && is_full_read(&req) {
+                self.fills.insert(req.id, req.addr);
+            }
+            self.inner.push(req);
+        }
+    }
+
+    fn pop(&mut self) -> Option<MemResp<WIDTH>> {
+        if let Some(resp) = self.hit_resps.pop_front() {
+            return Some(resp);
+        }
+        let resp = self.inner.pop()?;
+        if let Some(addr) = self.fills.remove(&resp.id) {
+            self.fill(addr, resp.rdata);
+        }
+        Some(resp)
+    }
+
+    fn cancel(&mut self, id: usize) -> bool {
+        if let Some(pos) = self.hit_resps.iter().position(|resp| resp.id == id) {
+            self.hit_resps.remove(pos);
+            return true;
+        }
+        self.fills.remove(&id);
+        self.inner.cancel(id)
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        self.inner.probe(addr)
+    }
+
+    fn kind_name(&self) -> &'static str {
+        self.inner.kind_name()
+    }
+
+    fn write_dot(&self, node: &str, out: &mut String) {
+        self.inner.write_dot(node, out);
+    }
+}
+
+/// Small fully-associative buffer holding lines evicted from an upstream cache, which hands them
+/// over with `insert_victim`. Reads that hit are answered from the buffer and the line leaves it,
+/// going back up into the cache. Victims are expected to be clean, so the oldest one is simply
+/// dropped when the buffer is full.
+pub struct VictimBuffer<D, const WIDTH: usize>
+where
+    D: Drain<Req = MemReq<WIDTH>, Resp = MemResp<WIDTH>>,
+{
+    inner: D,
+    capacity: usize,
+
+    lines: BTreeMap<u64, [u8; WIDTH]>,
+    order: VecDeque<u64>,
+    hit_resps: VecDeque<MemResp<WIDTH>>,
+
+    hits: u64,
+    misses: u64,
+}
+
+impl<D, const WIDTH: usize> VictimBuffer<D, WIDTH>
+where
+    D: Drain<Req = MemReq<WIDTH>, Resp = MemResp<WIDTH>>,
+{
+    pub fn new(inner: D, capacity: usize) -> Self {
+        VictimBuffer {
+            inner,
+            capacity,
+            lines: BTreeMap::new(),
+            order: VecDeque::new(),
+            hit_resps: VecDeque::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn insert_victim(&mut self, addr: u64, data: [u8; WIDTH]) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.lines.insert(addr, data).is_none() {
+            self.order.push_back(addr);
+            if self.order.len() > self.capacity {
+                let oldest = self.order.pop_front().unwrap();
+                self.lines.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        self.lines.contains_key(&addr)
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+}
+
+impl<D, const WIDTH: usize> Drain for VictimBuffer<D, WIDTH>
+where
+    D: Drain<Req = MemReq<WIDTH>, Resp = MemResp<WIDTH>>,
+{
+    type Req = MemReq<WIDTH>;
+    type Resp = MemResp<WIDTH>;
+
+    fn tick(&mut self) {
+        self.inner.tick();
+    }
+
+    fn tick_begin(&mut self) {
+        self.inner.tick_begin();
+    }
+
+    fn tick_end(&mut self) {
+        self.inner.tick_end();
+    }
+
+    fn push(&mut self, req: MemReq<WIDTH>) {
+        if req.wbe.contains(&true) {
+            if let Some(line) = self.lines.get_mut(&req.addr) {
+                for (c, (w, be)) in line.iter_mut().zip(req.wdata.iter().zip(req.wbe.iter())) {
+                    if *be {
+                        *c = *w;
+                    }
+                }
+            }
+            self.inner.push(req);
+        } else if let Some(line) = self.lines.remove(&req.addr) {
+            self.hits += 1;
+            self.order.retain(|addr| *addr != req.addr);
+            self.hit_resps.push_back(MemResp {
+                id: req.id,
+                rdata: masked(line, &req),
+                beat: 0,
+                last: true,
+                src: req.src,
+            });
+        } else {
+            self.misses += 1;
+            self.inner.push(req);
+        }
+    }
+
+    fn pop(&mut self) -> Option<MemResp<WIDTH>> {
+        self.hit_resps.pop_front().or_else(|| self.inner.pop())
+    }
+
+    fn cancel(&mut self, id: usize) -> bool {
+        if let Some(pos) = self.hit_resps.iter().position(|resp| resp.id == id) {
+            self.hit_resps.remove(pos);
+            return true;
+        }
+        self.inner.cancel(id)
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        self.inner.probe(addr)
+    }
+
+    fn kind_name(&self) -> &'static str {
+        self.inner.kind_name()
+    }
+
+    fn write_dot(&self, node: &str, out: &mut String) {
+        self.inner.write_dot(node, out);
+    }
+}
+
+#[cfg(test)]
+fn roundtrip<D: Drain<Req = MemReq<8>, Resp = MemResp<8>>>(
+    drain: &mut D,
+    req: MemReq<8>,
+) -> MemResp<8> {
+    drain.push(req);
+    loop {
+        if let Some(resp) = drain.pop() {
+            return resp;
+        }
+        drain.tick();
+    }
+}
+
+#[test]
+fn test_write_through_read_hit() {
+    use crate::mem::*;
+    let mem: Mem<_, 8> = Mem::new(NoDelay::default());
+    let mut cache = WriteThroughCache::new(mem, 4, FillPolicy::AllocateOnReadMiss);
+
+    roundtrip(&mut cache, MemReq::write(0, 0x1000, [true; 8], [7; 8]));
+    for id in 1..3 {
+        let resp = roundtrip(&mut cache, MemReq::read(id, 0x1000));
+        assert_eq!(resp.id, id);
+        assert_eq!(resp.rdata, [7; 8]);
+    }
+    assert_eq!(cache.misses(), 1);
+    assert_eq!(cache.hits(), 1);
+
+    let mem: Mem<_, 8> = Mem::new(NoDelay::default());
+    let mut uncached = WriteThroughCache::new(mem, 4, FillPolicy::NoAllocate);
+    for id in 0..2 {
+        roundtrip(&mut uncached, MemReq::read(id, 0x1000));
+    }
+    assert_eq!(uncached.misses(), 2);
+    assert_eq!(uncached.hits(), 0);
+}
+
+#[test]
+fn test_write_through_updates_line() {
+    use crate::mem::*;
+    let mem: Mem<_, 8> = Mem::new(NoDelay::default());
+    let mut cache = WriteThroughCache::new(mem, 4, FillPolicy::AllocateOnReadMiss);
+
+    roundtrip(&mut cache, MemReq::read(0, 0x1000));
+    roundtrip(
+        &mut cache,
+        MemReq::write(
+            1,
+            0x1000,
+            [true, true, false, false, false, false, false, false],
+            [3; 8],
+        ),
+    );
+    let resp = roundtrip(&mut cache, MemReq::read(2, 0x1000));
+    assert_eq!(cache.hits(), 1);
+    assert_eq!(resp.rdata, [3, 3, 0, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn test_masked_reads() {
+    use crate::mem::*;
+    let mem: Mem<_, 8> = Mem::new(NoDelay::default());
+    let mut cache = WriteThroughCache::new(mem, 4, FillPolicy::AllocateOnReadMiss);
+    let rbe = [true, true, false, false, false, false, false, false];
+    let masked_read = |id| MemReq {
+        rbe: Some(rbe),
+        ..MemReq::read(id, 0x1000)
+    };
+
+    roundtrip(
+        &mut cache,
+        MemReq::write(0, 0x1000, [true; 8], [1, 2, 3, 4, 5, 6, 7, 8]),
+    );
+    // Doesn't allocate the line with the disabled bytes zeroed
+    let resp = roundtrip(&mut cache, masked_read(1));
+    assert_eq!(resp.rdata, [1, 2, 0, 0, 0, 0, 0, 0]);
+    let resp = roundtrip(&mut cache, MemReq::read(2, 0x1000));
+    assert_eq!(resp.rdata, [1, 2, 3, 4, 5, 6, 7, 8]);
+    assert_eq!(cache.misses(), 2);
+
+    // Hits are masked like misses
+    let resp = roundtrip(&mut cache, masked_read(3));
+    assert_eq!(resp.rdata, [1, 2, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(cache.hits(), 1);
+}
+
+#[test]
+fn test_write_through_count() {
+    use crate::mem::*;
+    let mem: Mem<_, 8> = Mem::new(NoDelay::default());
+    let mut cache = WriteThroughCache::new(mem, 2, FillPolicy::AllocateOnReadMiss);
+
+    for id in 0..5 {
+        roundtrip(
+            &mut cache,
+            MemReq::write(id, 0x1000 + 8 * (id as u64 % 3), [true; 8], [id as u8; 8]),
+        );
+    }
+    roundtrip(&mut cache, MemReq::read(5, 0x1000));
+    assert_eq!(cache.writethroughs(), 5);
+}
+
+#[test]
+fn test_victim_buffer_hit() {
+    use crate::mem::*;
+    let mem: Mem<_, 8> = Mem::new(FixedDelay::new(10));
+    let mut victims = VictimBuffer::new(mem, 2);
+    victims.insert_victim(0x1000, [5; 8]);
+    assert!(victims.contains(0x1000));
+
+    // Answered without waiting on memory, which never saw the data
+    victims.push(MemReq::read(0, 0x1000));
+    let resp = victims.pop().unwrap();
+    assert_eq!((resp.id, resp.rdata), (0, [5; 8]));
+    assert_eq!(victims.hits(), 1);
+    assert!(!victims.contains(0x1000));
+
+    let resp = roundtrip(&mut victims, MemReq::read(1, 0x1000));
+    assert_eq!(resp.rdata, [0; 8]);
+    assert_eq!(victims.misses(), 1);
+
+    for (idx, addr) in [0x2000, 0x3000, 0x4000].into_iter().enumerate() {
+        victims.insert_victim(addr, [idx as u8; 8]);
+    }
+    assert!(!victims.contains(0x2000));
+    assert!(victims.contains(0x4000));
+}
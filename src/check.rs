@@ -0,0 +1,533 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use core::fmt::Debug;
+
+use crate::drain::{Drain, Identified, ProbeResult};
+use crate::mem::{MemReq, MemResp};
+
+/// Runs a reference drain `B` in lockstep with `A`, returning `A`'s responses and panicking as
+/// soon as both have answered a request id with differing responses
+pub struct Checker<A: Drain, B: Drain<Req = A::Req, Resp = A::Resp>> {
+    dut: A,
+    reference: B,
+
+    dut_resps: BTreeMap<usize, A::Resp>,
+    ref_resps: BTreeMap<usize, A::Resp>,
+}
+
+impl<A: Drain, B: Drain<Req = A::Req, Resp = A::Resp>> Checker<A, B>
+where
+    A::Req: Clone,
+    A::Resp: Identified + Clone + PartialEq + Debug,
+{
+    pub fn new(dut: A, reference: B) -> Self {
+        Checker {
+            dut,
+            reference,
+            dut_resps: BTreeMap::new(),
+            ref_resps: BTreeMap::new(),
+        }
+    }
+
+    fn drain_reference(&mut self) {
+        while let Some(resp) = self.reference.pop() {
+            match self.dut_resps.remove(&resp.id()) {
+                Some(dut) => Self::compare(&dut, &resp),
+                None => {
+                    self.ref_resps.insert(resp.id(), resp);
+                }
+            }
+        }
+    }
+
+    fn compare(dut: &A::Resp, reference: &A::Resp) {
+        if dut != reference {
+            panic!(
+                "Response mismatch for id {}: got {:?}, expected {:?}",
+                dut.id(),
+                dut,
+                reference
+            );
+        }
+    }
+}
+
+impl<A: Drain, B: Drain<Req = A::Req, Resp = A::Resp>> Drain for Checker<A, B>
+where
+    A::Req: Clone,
+    A::Resp: Identified + Clone + PartialEq + Debug,
+{
+    type Req = A::Req;
+    type Resp = A::Resp;
+
+    fn tick(&mut self) {
+        self.dut.tick();
+        self.reference.tick();
+        self.drain_reference();
+    }
+
+    fn tick_begin(&mut self) {
+        self.dut.tick_begin();
+        self.reference.tick_begin();
+    }
+
+    fn tick_end(&mut self) {
+        self.dut.tick_end();
+        self.reference.tick_end();
+        self.drain_reference();
+    }
+
+    fn push(&mut self, req: Self::Req) {
+        self.reference.push(req.clone());
+        self.dut.push(req);
+    }
+
+    fn pop(&mut self) -> Option<Self::Resp> {
+        self.drain_reference();
+        let resp = self.dut.pop()?;
+        match self.ref_resps.remove(&resp.id()) {
+            Some(reference) => Self::compare(&resp, &reference),
+            None => {
+                self.dut_resps.insert(resp.id(), resp.clone());
+            }
+        }
+        Some(resp)
+    }
+
+    fn cancel(&mut self, id: usize) -> bool {
+        let cancelled = self.dut.cancel(id);
+        if cancelled {
+            self.reference.cancel(id);
+            self.ref_resps.remove(&id);
+        }
+        cancelled
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        self.dut.probe(addr)
+    }
+}
+
+/// Panics as soon as more than `max` requests are outstanding in `D`, which upstream
+/// backpressure should never allow
+pub struct InflightGuard<D: Drain> {
+    inner: D,
+    max: usize,
+    outstanding: usize,
+}
+
+impl<D: Drain> InflightGuard<D> {
+    pub fn new(inner: D, max: usize) -> Self {
+        InflightGuard {
+            inner,
+            max,
+            outstanding: 0,
+        }
+    }
+
+    pub fn outstanding(&self) -> usize {
+        self.outstanding
+    }
+}
+
+impl<D: Drain> Drain for InflightGuard<D>
+where
+    D::Resp: Identified,
+{
+    type Req = D::Req;
+    type Resp = D::Resp;
+
+    fn tick(&mut self) {
+        self.inner.tick();
+    }
+
+    fn tick_begin(&mut self) {
+        self.inner.tick_begin();
+    }
+
+    fn tick_end(&mut self) {
+        self.inner.tick_end();
+    }
+
+    fn push(&mut self, req: Self::Req) {
+        self.outstanding += 1;
+        if self.outstanding > self.max {
+            panic!(
+                "{} outstanding requests exceed the maximum of {}",
+                self.outstanding, self.max
+            );
+        }
+        self.inner.push(req);
+    }
+
+    fn pop(&mut self) -> Option<Self::Resp> {
+        let resp = self.inner.pop()?;
+        if resp.is_last() {
+            self.outstanding -= 1;
+        }
+        Some(resp)
+    }
+
+    fn cancel(&mut self, id: usize) -> bool {
+        let cancelled = self.inner.cancel(id);
+        if cancelled {
+            self.outstanding -= 1;
+        }
+        cancelled
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        self.inner.probe(addr)
+    }
+
+    fn kind_name(&self) -> &'static str {
+        self.inner.kind_name()
+    }
+
+    fn write_dot(&self, node: &str, out: &mut String) {
+        self.inner.write_dot(node, out);
+    }
+}
+
+/// Panics when a pushed write disagrees with `golden` on any enabled byte. Writes to addresses
+/// absent from `golden` aren't checked.
+pub struct WriteAssert<D: Drain, const WIDTH: usize> {
+    inner: D,
+    golden: BTreeMap<u64, [u8; WIDTH]>,
+}
+
+impl<D, const WIDTH: usize> WriteAssert<D, WIDTH>
+where
+    D: Drain<Req = MemReq<WIDTH>, Resp = MemResp<WIDTH>>,
+{
+    pub fn new(inner: D, golden: BTreeMap<u64, [u8; WIDTH]>) -> Self {
+        WriteAssert { inner, golden }
+    }
+}
+
+impl<D, const WIDTH: usize> Drain for WriteAssert<D, WIDTH>
+where
+    D: Drain<Req = MemReq<WIDTH>, Resp = MemResp<WIDTH>>,
+{
+    type Req = MemReq<WIDTH>;
+    type Resp = MemResp<WIDTH>;
+
+    fn tick(&mut self) {
+        self.inner.tick();
+    }
+
+    fn tick_begin(&mut self) {
+        self.inner.tick_begin();
+    }
+
+    fn tick_end(&mut self) {
+        self.inner.tick_end();
+    }
+
+    fn push(&mut self, req: MemReq<WIDTH>) {
+        if let Some(golden) = self.golden.get(&req.addr) {
+            for (i, ((w, g), be)) in req.wdata.iter().zip(golden).zip(req.wbe).enumerate() {
+                if be && w != g {
+                    panic!(
+                        "Write {} to {:#x} has byte {} = {:#04x}, expected {:#04x}",
+                        req.id, req.addr, i, w, g
+                    );
+                }
+            }
+        }
+        self.inner.push(req);
+    }
+
+    fn pop(&mut self) -> Option<MemResp<WIDTH>> {
+        self.inner.pop()
+    }
+
+    fn cancel(&mut self, id: usize) -> bool {
+        self.inner.cancel(id)
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        self.inner.probe(addr)
+    }
+
+    fn kind_name(&self) -> &'static str {
+        self.inner.kind_name()
+    }
+
+    fn write_dot(&self, node: &str, out: &mut String) {
+        self.inner.write_dot(node, out);
+    }
+}
+
+/// Panics when a response overtakes an earlier request of the same stream. `stream` assigns each
+/// request to a stream, requests within which must complete in the order they were pushed.
+pub struct SeqCheck<D: Drain> {
+    inner: D,
+    stream: fn(&D::Req) -> u64,
+    // stream -> outstanding ids in push order
+    order: BTreeMap<u64, VecDeque<usize>>,
+    streams: BTreeMap<usize, u64>,
+}
+
+impl<D: Drain> SeqCheck<D>
+where
+    D::Req: Identified,
+    D::Resp: Identified,
+{
+    pub fn new(inner: D, stream: fn(&D::Req) -> u64) -> Self {
+        SeqCheck {
+            inner,
+            stream,
+            order: BTreeMap::new(),
+            streams: BTreeMap::new(),
+        }
+    }
+
+    fn forget(&mut self, id: usize) {
+        let Some(stream) = self.streams.remove(&id) else {
+            return;
+        };
+        let queue = self.order.get_mut(&stream).unwrap();
+        queue.retain(|other| *other != id);
+        if queue.is_empty() {
+            self.order.remove(&stream);
+        }
+    }
+}
+
+impl<D: Drain> Drain for SeqCheck<D>
+where
+    D::Req: Identified,
+    D::Resp: Identified,
+{
+    type Req = D::Req;
+    type Resp = D::Resp;
+
+    fn tick(&mut self) {
+        self.inner.tick();
+    }
+
+    fn tick_begin(&mut self) {
+        self.inner.tick_begin();
+    }
+
+    fn tick_end(&mut self) {
+        self.inner.tick_end();
+    }
+
+    fn push(&mut self, req: Self::Req) {
+        let stream = (self.stream)(&req);
+        self.order.entry(stream).or_default().push_back(req.id());
+        self.streams.insert(req.id(), stream);
+        self.inner.push(req);
+    }
+
+    fn pop(&mut self) -> Option<Self::Resp> {
+        let resp = self.inner.pop()?;
+        let id = resp.id();
+        if let Some(stream) = self.streams.get(&id) {
+            let first = self.order[stream][0];
+            if first != id {
+                panic!(
+                    "Response {} completed before {} in stream {}",
+                    id, first, stream
+                );
+            }
+        }
+        self.forget(id);
+        Some(resp)
+    }
+
+    fn cancel(&mut self, id: usize) -> bool {
+        let cancelled = self.inner.cancel(id);
+        if cancelled {
+            self.forget(id);
+        }
+        cancelled
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        self.inner.probe(addr)
+    }
+
+    fn kind_name(&self) -> &'static str {
+        self.inner.kind_name()
+    }
+
+    fn write_dot(&self, node: &str, out: &mut String) {
+        self.inner.write_dot(node, out);
+    }
+}
+
+#[cfg(test)]
+struct Corrupt<D> {
+    inner: D,
+    victim: usize,
+}
+
+#[cfg(test)]
+impl<D: Drain<Resp = crate::mem::MemResp<8>>> Drain for Corrupt<D> {
+    type Req = D::Req;
+    type Resp = D::Resp;
+
+    fn tick(&mut self) {
+        self.inner.tick();
+    }
+
+    fn push(&mut self, req: Self::Req) {
+        self.inner.push(req);
+    }
+
+    fn pop(&mut self) -> Option<Self::Resp> {
+        self.inner.pop().map(|mut resp| {
+            if resp.id == self.victim {
+                resp.rdata[0] ^= 1;
+            }
+            resp
+        })
+    }
+}
+
+#[cfg(test)]
+fn run_checked<D: Drain<Req = crate::mem::MemReq<8>, Resp = crate::mem::MemResp<8>>>(
+    drain: &mut D,
+) {
+    use crate::mem::MemReq;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(0);
+    for id in 0..32 {
+        let is_write = id < 16;
+        drain.push(MemReq::write(
+            id,
+            0x80000000 + 8 * (id as u64 % 16),
+            [is_write; 8],
+            rng.gen(),
+        ));
+        let mut done = false;
+        while !done {
+            drain.tick();
+            done = drain.pop().is_some();
+        }
+    }
+}
+
+#[test]
+fn test_checker_agrees() {
+    use crate::mem::*;
+    let dut: Mem<_, 8> = Mem::new(FixedDelay::new(5));
+    let reference: Mem<_, 8> = Mem::new(NoDelay::default());
+    let mut checker = Checker::new(dut, reference);
+    run_checked(&mut checker);
+}
+
+#[test]
+#[should_panic(expected = "Response mismatch for id 20")]
+fn test_checker_detects_corruption() {
+    use crate::mem::*;
+    let dut = Corrupt {
+        inner: Mem::<_, 8>::new(FixedDelay::new(5)),
+        victim: 20,
+    };
+    let reference: Mem<_, 8> = Mem::new(NoDelay::default());
+    let mut checker = Checker::new(dut, reference);
+    run_checked(&mut checker);
+}
+
+#[cfg(test)]
+fn full_guard() -> InflightGuard<crate::mem::Mem<crate::mem::FixedDelay, 8>> {
+    use crate::mem::*;
+    let mut guard = InflightGuard::new(Mem::new(FixedDelay::new(5)), 4);
+    for id in 0..4 {
+        guard.push(MemReq::read(id, 0x80000000 + 8 * id as u64));
+    }
+    guard
+}
+
+#[test]
+#[should_panic(expected = "5 outstanding requests exceed the maximum of 4")]
+fn test_inflight_guard() {
+    full_guard().push(MemReq::read(4, 0x80000020));
+}
+
+#[test]
+fn test_inflight_guard_completions() {
+    let mut guard = full_guard();
+    // Completions free up room again
+    while guard.pop().is_none() {
+        guard.tick();
+    }
+    guard.push(MemReq::read(4, 0x80000020));
+    assert_eq!(guard.outstanding(), 4);
+}
+
+#[cfg(test)]
+fn golden_write(drain: &mut impl Drain<Req = MemReq<8>, Resp = MemResp<8>>, wbe: [bool; 8]) {
+    drain.push(MemReq::write(
+        0,
+        0x80000000,
+        wbe,
+        [1, 2, 3, 4, 0xff, 0xff, 0xff, 0xff],
+    ));
+    while drain.pop().is_none() {
+        drain.tick();
+    }
+}
+
+#[test]
+fn test_write_assert_match() {
+    use crate::mem::*;
+    let golden = BTreeMap::from([(0x80000000, [1, 2, 3, 4, 5, 6, 7, 8])]);
+    let mut checked = WriteAssert::new(Mem::<_, 8>::new(NoDelay::default()), golden);
+    // Only the lower half is enabled, so the mismatching upper half doesn't count
+    golden_write(
+        &mut checked,
+        [true, true, true, true, false, false, false, false],
+    );
+}
+
+#[test]
+#[should_panic(expected = "Write 0 to 0x80000000 has byte 5 = 0xff, expected 0x06")]
+fn test_write_assert_mismatch() {
+    use crate::mem::*;
+    let golden = BTreeMap::from([(0x80000000, [1, 2, 3, 4, 0xff, 6, 7, 8])]);
+    let mut checked = WriteAssert::new(Mem::<_, 8>::new(NoDelay::default()), golden);
+    golden_write(&mut checked, [true; 8]);
+}
+
+#[cfg(test)]
+fn run_streams<D: Drain<Req = MemReq<8>, Resp = MemResp<8>>>(drain: &mut D) {
+    // Slow writes followed by fast reads, to two addresses
+    for id in 0..8 {
+        drain.push(MemReq::write(
+            id,
+            0x80000000 + 8 * (id as u64 % 2),
+            [id < 4; 8],
+            [0; 8],
+        ));
+    }
+    let mut popped = 0;
+    while popped < 8 {
+        drain.tick();
+        while drain.pop().is_some() {
+            popped += 1;
+        }
+    }
+}
+
+#[test]
+fn test_seq_check_per_address() {
+    use crate::mem::*;
+    let mem: Mem<_, 8> = Mem::new(SplitRw::new(FixedDelay::new(1), FixedDelay::new(5)));
+    let mut checked = SeqCheck::new(mem, |req| req.addr);
+    run_streams(&mut checked);
+}
+
+#[test]
+#[should_panic(expected = "Response 4 completed before 3 in stream 0")]
+fn test_seq_check_reordered() {
+    use crate::mem::*;
+    let mem: Mem<_, 8> = Mem::new(SplitRw::new(FixedDelay::new(1), FixedDelay::new(5)));
+    let mut checked = SeqCheck::new(mem, |_| 0);
+    run_streams(&mut checked);
+}
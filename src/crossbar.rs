@@ -1,31 +1,727 @@
-use std::{collections::BTreeMap, fmt::Display};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{
+    fmt::{Display, Write},
+    ops::{Range, RangeInclusive, Sub},
+};
 
-use crate::drain::Drain;
+use crate::drain::{Access, Drain, Identified, ProbeResult};
 
-pub trait Addr: Eq + Ord + Copy + Display {}
-impl Addr for u64 {}
+pub trait Addr: Eq + Ord + Copy + Display {
+    fn checked_next(self) -> Option<Self>;
+    fn is_aligned(self, alignment: usize) -> bool;
+
+    /// Conversions for `Drain::probe`, which always takes a `u64` address
+    fn from_u64(addr: u64) -> Option<Self>;
+    fn to_u64(self) -> u64;
+}
+
+impl Addr for u64 {
+    fn checked_next(self) -> Option<Self> {
+        self.checked_add(1)
+    }
+
+    fn is_aligned(self, alignment: usize) -> bool {
+        self.is_multiple_of(alignment as u64)
+    }
+
+    fn from_u64(addr: u64) -> Option<Self> {
+        Some(addr)
+    }
+
+    fn to_u64(self) -> u64 {
+        self
+    }
+}
 
 pub trait Routable<A: Addr> {
     fn addr(&self) -> A;
+
+    /// Address of the last byte covered by the request
+    fn last_addr(&self) -> A {
+        self.addr()
+    }
+}
+
+pub trait Relocatable<A: Addr>: Routable<A> {
+    fn set_addr(&mut self, addr: A);
+}
+
+/// Subtracts a fixed offset from each request's address before forwarding it
+pub struct Offset<A: Addr, T: Drain> {
+    inner: T,
+    offset: A,
+}
+
+impl<A: Addr, T: Drain> Offset<A, T> {
+    pub fn new(inner: T, offset: A) -> Offset<A, T> {
+        Offset { inner, offset }
+    }
+}
+
+impl<A: Addr + Sub<Output = A>, T: Drain> Drain for Offset<A, T>
+where
+    T::Req: Relocatable<A>,
+{
+    type Req = T::Req;
+    type Resp = T::Resp;
+
+    fn tick(&mut self) {
+        self.inner.tick();
+    }
+
+    fn tick_begin(&mut self) {
+        self.inner.tick_begin();
+    }
+
+    fn tick_end(&mut self) {
+        self.inner.tick_end();
+    }
+
+    fn tick_n(&mut self, n: usize) {
+        self.inner.tick_n(n);
+    }
+
+    fn push(&mut self, mut req: Self::Req) {
+        let addr = req.addr();
+        req.set_addr(addr - self.offset);
+        self.inner.push(req);
+    }
+
+    fn pop(&mut self) -> Option<Self::Resp> {
+        self.inner.pop()
+    }
+
+    fn cancel(&mut self, id: usize) -> bool {
+        self.inner.cancel(id)
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        match A::from_u64(addr) {
+            Some(addr) if addr >= self.offset => self.inner.probe((addr - self.offset).to_u64()),
+            _ => ProbeResult::NotPresent,
+        }
+    }
+
+    fn kind_name(&self) -> &'static str {
+        self.inner.kind_name()
+    }
+
+    fn write_dot(&self, node: &str, out: &mut String) {
+        self.inner.write_dot(node, out);
+    }
+}
+
+/// A single child mapped to `[start, end)`, rejecting addresses outside of it like a one-region
+/// `Crossbar` would
+pub struct FlatAddressSpace<A: Addr, D: Drain> {
+    inner: D,
+    start: A,
+    end: A,
+}
+
+impl<A: Addr, D: Drain> FlatAddressSpace<A, D> {
+    pub fn new(inner: D, start: A, end: A) -> FlatAddressSpace<A, D> {
+        assert!(start < end, "start must be below end");
+        FlatAddressSpace { inner, start, end }
+    }
+}
+
+impl<A: Addr, D: Drain> Drain for FlatAddressSpace<A, D>
+where
+    D::Req: Routable<A>,
+{
+    type Req = D::Req;
+    type Resp = D::Resp;
+
+    fn tick(&mut self) {
+        self.inner.tick();
+    }
+
+    fn tick_begin(&mut self) {
+        self.inner.tick_begin();
+    }
+
+    fn tick_end(&mut self) {
+        self.inner.tick_end();
+    }
+
+    fn tick_n(&mut self, n: usize) {
+        self.inner.tick_n(n);
+    }
+
+    fn push(&mut self, req: Self::Req) {
+        let addr = req.addr();
+        if addr < self.start || addr >= self.end {
+            panic!("Out-of-range request address: {}", addr);
+        }
+        if req.last_addr() >= self.end {
+            panic!("Request at {} straddles region boundary {}", addr, self.end);
+        }
+        self.inner.push(req);
+    }
+
+    fn pop(&mut self) -> Option<Self::Resp> {
+        self.inner.pop()
+    }
+
+    fn cancel(&mut self, id: usize) -> bool {
+        self.inner.cancel(id)
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        match A::from_u64(addr) {
+            Some(a) if a >= self.start && a < self.end => self.inner.probe(addr),
+            _ => ProbeResult::NotPresent,
+        }
+    }
+
+    fn kind_name(&self) -> &'static str {
+        self.inner.kind_name()
+    }
+
+    fn write_dot(&self, node: &str, out: &mut String) {
+        self.inner.write_dot(node, out);
+    }
+}
+
+type Child<Req, Resp> = Box<dyn Drain<Req = Req, Resp = Resp>>;
+
+/// Interleaves one region across several children, `stripe` bytes at a time, see
+/// `Crossbar::with_striped`
+struct Striped<A, Req, Resp> {
+    children: Vec<Child<Req, Resp>>,
+    stripe: u64,
+    _addr: core::marker::PhantomData<A>,
+}
+
+impl<A: Addr, Req: Routable<A>, Resp> Striped<A, Req, Resp> {
+    fn child_of(&self, addr: A) -> usize {
+        ((addr.to_u64() / self.stripe) % self.children.len() as u64) as usize
+    }
+}
+
+impl<A: Addr, Req: Routable<A>, Resp> Drain for Striped<A, Req, Resp> {
+    type Req = Req;
+    type Resp = Resp;
+
+    fn tick(&mut self) {
+        self.children.iter_mut().for_each(|child| child.tick());
+    }
+
+    fn tick_begin(&mut self) {
+        self.children
+            .iter_mut()
+            .for_each(|child| child.tick_begin());
+    }
+
+    fn tick_end(&mut self) {
+        self.children.iter_mut().for_each(|child| child.tick_end());
+    }
+
+    fn tick_n(&mut self, n: usize) {
+        self.children.iter_mut().for_each(|child| child.tick_n(n));
+    }
+
+    fn write_dot(&self, node: &str, out: &mut String) {
+        let _ = writeln!(out, "  subgraph cluster_{} {{", node);
+        let _ = writeln!(
+            out,
+            "  {} [label=\"{}\", shape=box];",
+            node,
+            self.kind_name()
+        );
+        for (idx, child) in self.children.iter().enumerate() {
+            child.write_dot(&format!("{}_{}", node, idx), out);
+            let _ = writeln!(
+                out,
+                "  {} -> {}_{} [label=\"stripe {}\"];",
+                node, node, idx, idx
+            );
+        }
+        out.push_str("  }\n");
+    }
+
+    fn push(&mut self, req: Req) {
+        let idx = self.child_of(req.addr());
+        if self.child_of(req.last_addr()) != idx {
+            panic!(
+                "Request at {} straddles a {}-byte stripe boundary",
+                req.addr(),
+                self.stripe
+            );
+        }
+        self.children[idx].push(req);
+    }
+
+    fn pop(&mut self) -> Option<Resp> {
+        self.children.iter_mut().find_map(|child| child.pop())
+    }
+
+    fn cancel(&mut self, id: usize) -> bool {
+        self.children.iter_mut().any(|child| child.cancel(id))
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        match A::from_u64(addr) {
+            Some(a) => self.children[self.child_of(a)].probe(addr),
+            None => ProbeResult::NotPresent,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct RegionId(usize);
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct RegionStats {
+    pub requests: u64,
+    pub responses: u64,
+}
+
+/// Accesses allowed to a region, see `Crossbar::with_permissions`
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Permissions {
+    ReadOnly,
+    WriteOnly,
+    #[default]
+    ReadWrite,
+}
+
+/// Which child `Crossbar::pop` takes a response from when several have one ready
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum PopPolicy {
+    /// The child added first
+    #[default]
+    FixedPriority,
+    /// The child whose oldest ready response has waited the longest. Responses are taken from
+    /// the children as soon as they are ready to keep track of their age.
+    OldestFirst,
+}
+
+struct Port<A, Req, Resp> {
+    name: String,
+    start: A,
+    end: A,
+    drain: Child<Req, Resp>,
+    stats: RegionStats,
+    alignment: Option<usize>,
+    permissions: Permissions,
+    // Responses taken from the child under `PopPolicy::OldestFirst` or a decode latency, with
+    // the tick they were seen ready
+    ready: VecDeque<(usize, Resp)>,
+    // Requests waiting out the decode latency, with the tick they reach the child
+    decoding: VecDeque<(usize, Req)>,
+}
+
+// Kept as function pointers so that the crossbar itself doesn't require `Identified`
+struct IdCheck<Req, Resp> {
+    req_id: fn(&Req) -> usize,
+    resp_id: fn(&Resp) -> usize,
+    is_last: fn(&Resp) -> bool,
+    inflight: BTreeSet<usize>,
 }
 
 pub struct Crossbar<A: Addr, Req: Routable<A>, Resp> {
-    children: BTreeMap<A, (A, Box<dyn Drain<Req = Req, Resp = Resp>>)>,
+    // start -> (end, index into children). Several address ranges may share one child
+    regions: BTreeMap<A, (A, usize)>,
+    children: Vec<Port<A, Req, Resp>>,
+    ids: Option<IdCheck<Req, Resp>>,
+    // Set once any region restricts its permissions
+    is_write: Option<fn(&Req) -> bool>,
+    policy: PopPolicy,
+    // Set along with `policy` or `decode_latency`, for cancelling requests and responses held
+    // by the crossbar itself
+    req_id: Option<fn(&Req) -> usize>,
+    resp_id: Option<fn(&Resp) -> usize>,
+    decode_latency: usize,
+    strict: bool,
+    tick: usize,
+}
+
+impl<A: Addr, Req: Routable<A>, Resp> Default for Crossbar<A, Req, Resp> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<A: Addr, Req: Routable<A>, Resp> Crossbar<A, Req, Resp> {
     pub fn new() -> Crossbar<A, Req, Resp> {
         Crossbar {
-            children: BTreeMap::new(),
+            regions: BTreeMap::new(),
+            children: Vec::new(),
+            ids: None,
+            is_write: None,
+            policy: PopPolicy::default(),
+            req_id: None,
+            resp_id: None,
+            decode_latency: 0,
+            strict: false,
+            tick: 0,
         }
     }
+
+    pub fn with_pop_policy(mut self, policy: PopPolicy) -> Crossbar<A, Req, Resp>
+    where
+        Resp: Identified,
+    {
+        self.policy = policy;
+        self.resp_id = Some(Resp::id);
+        self
+    }
+
+    /// Panics when ticked without any region mapped, which almost always means it was never
+    /// wired up
+    pub fn strict(mut self) -> Crossbar<A, Req, Resp> {
+        self.strict = true;
+        self
+    }
+
+    fn check_wired(&self) {
+        if self.strict && self.children.is_empty() {
+            panic!("Crossbar ticked with no regions mapped");
+        }
+    }
+
+    /// Holds each request for `latency` ticks before it reaches its child, and each response for
+    /// as long on its way back, modelling the decode and arbitration stages
+    pub fn with_decode_latency(mut self, latency: usize) -> Crossbar<A, Req, Resp>
+    where
+        Req: Identified,
+        Resp: Identified,
+    {
+        self.decode_latency = latency;
+        self.req_id = Some(Req::id);
+        self.resp_id = Some(Resp::id);
+        self
+    }
+
+    /// Panics when a request reuses the id of one still in flight in any region, as responses
+    /// from different children couldn't be told apart otherwise
+    pub fn with_unique_ids(mut self) -> Crossbar<A, Req, Resp>
+    where
+        Req: Identified,
+        Resp: Identified,
+    {
+        self.ids = Some(IdCheck {
+            req_id: Req::id,
+            resp_id: Resp::id,
+            is_last: Resp::is_last,
+            inflight: BTreeSet::new(),
+        });
+        self
+    }
+
+    /// Maps the half-open range `[start, end)` to `inner`
     pub fn with(
         mut self,
         start: A,
         end: A,
         inner: Box<dyn Drain<Req = Req, Resp = Resp>>,
     ) -> Crossbar<A, Req, Resp> {
-        self.children.insert(start, (end, inner));
+        self.add_region(start, end, inner);
+        self
+    }
+
+    /// Like `with`, but also returns the id of the new region
+    pub fn with_id(
+        mut self,
+        start: A,
+        end: A,
+        inner: Child<Req, Resp>,
+    ) -> (Crossbar<A, Req, Resp>, RegionId) {
+        let id = self.add_region(start, end, inner);
+        (self, id)
+    }
+
+    /// Like `with`, for building up a crossbar in place
+    pub fn add(&mut self, start: A, end: A, inner: Child<Req, Resp>) -> &mut Self {
+        self.add_region(start, end, inner);
+        self
+    }
+
+    /// Like `add`, but returns the id of the new region instead
+    pub fn add_region(&mut self, start: A, end: A, inner: Child<Req, Resp>) -> RegionId {
+        let idx = self.add_port(format!("{}..{}", start, end), start, end, inner);
+        self.regions.insert(start, (end, idx));
+        RegionId(idx)
+    }
+
+    /// Like `with`, but panics on requests to this region whose address isn't a multiple of
+    /// `alignment`
+    pub fn with_aligned(
+        mut self,
+        start: A,
+        end: A,
+        alignment: usize,
+        inner: Child<Req, Resp>,
+    ) -> Crossbar<A, Req, Resp> {
+        assert!(alignment > 0, "alignment must be positive");
+        self.add(start, end, inner);
+        self.children.last_mut().unwrap().alignment = Some(alignment);
+        self
+    }
+
+    /// Like `with`, but panics on writes to a read-only region or reads from a write-only one,
+    /// without forwarding them
+    pub fn with_permissions(
+        mut self,
+        start: A,
+        end: A,
+        permissions: Permissions,
+        inner: Child<Req, Resp>,
+    ) -> Crossbar<A, Req, Resp>
+    where
+        Req: Access,
+    {
+        self.add(start, end, inner);
+        self.children.last_mut().unwrap().permissions = permissions;
+        self.is_write = Some(Req::is_write);
+        self
+    }
+
+    pub fn with_range(self, range: Range<A>, inner: Child<Req, Resp>) -> Crossbar<A, Req, Resp> {
+        self.with(range.start, range.end, inner)
+    }
+
+    /// Like `with_range`, but `range.end()` itself is routed to `inner` as well
+    pub fn with_inclusive(
+        self,
+        range: RangeInclusive<A>,
+        inner: Child<Req, Resp>,
+    ) -> Crossbar<A, Req, Resp> {
+        let (start, last) = range.into_inner();
+        let end = last
+            .checked_next()
+            .unwrap_or_else(|| panic!("Region end {} is not representable as exclusive", last));
+        self.with(start, end, inner)
+    }
+
+    pub fn with_named(
+        mut self,
+        name: &str,
+        start: A,
+        end: A,
+        inner: Box<dyn Drain<Req = Req, Resp = Resp>>,
+    ) -> Crossbar<A, Req, Resp> {
+        let idx = self.add_port(name.to_string(), start, end, inner);
+        self.regions.insert(start, (end, idx));
+        self
+    }
+
+    fn add_port(&mut self, name: String, start: A, end: A, drain: Child<Req, Resp>) -> usize {
+        self.children.push(Port {
+            name,
+            start,
+            end,
+            drain,
+            stats: RegionStats::default(),
+            alignment: None,
+            permissions: Permissions::default(),
+            ready: VecDeque::new(),
+            decoding: VecDeque::new(),
+        });
+        self.children.len() - 1
+    }
+
+    /// Visits every child once, along with the address span it serves. A child mapped to
+    /// several ranges (see `with_carve_outs`) reports the span enclosing all of them.
+    pub fn for_each_child(
+        &mut self,
+        mut f: impl FnMut(A, A, &mut dyn Drain<Req = Req, Resp = Resp>),
+    ) {
+        self.visit_ports(|_, port| {
+            f(port.start, port.end, port.drain.as_mut());
+            None::<()>
+        });
+    }
+
+    // Every traversal of the children goes through here or `visit_ports_ref`, visiting them in
+    // the order they were added until `f` returns `Some`
+    fn visit_ports<T>(
+        &mut self,
+        mut f: impl FnMut(usize, &mut Port<A, Req, Resp>) -> Option<T>,
+    ) -> Option<T> {
+        self.children
+            .iter_mut()
+            .enumerate()
+            .find_map(|(idx, port)| f(idx, port))
+    }
+
+    fn visit_ports_ref<T>(
+        &self,
+        mut f: impl FnMut(usize, &Port<A, Req, Resp>) -> Option<T>,
+    ) -> Option<T> {
+        self.children
+            .iter()
+            .enumerate()
+            .find_map(|(idx, port)| f(idx, port))
+    }
+
+    pub fn region(&self, name: &str) -> Option<RegionId> {
+        self.visit_ports_ref(|idx, port| (port.name == name).then_some(RegionId(idx)))
+    }
+
+    pub fn region_of(&self, addr: A) -> Option<RegionId> {
+        self.regions
+            .range(..=addr)
+            .last()
+            .filter(|(_, (end, _))| addr < *end)
+            .map(|(_, (_, idx))| RegionId(*idx))
+    }
+
+    pub fn name(&self, id: RegionId) -> &str {
+        &self.children[id.0].name
+    }
+
+    pub fn stats(&self, id: RegionId) -> RegionStats {
+        self.children[id.0].stats
+    }
+
+    /// Like `pop`, but also reports which region the response came from
+    pub fn pop_with_region(&mut self) -> Option<(RegionId, Resp)> {
+        let (idx, resp) = if !self.buffered() {
+            self.visit_ports(|idx, port| port.drain.pop().map(|resp| (idx, resp)))?
+        } else {
+            self.gather();
+            let visible = self.tick.checked_sub(self.decode_latency)?;
+            let policy = self.policy;
+            let mut oldest: Option<(usize, usize)> = None;
+            let first = self.visit_ports_ref(|idx, port| {
+                let &(tick, _) = port.ready.front().filter(|(tick, _)| *tick <= visible)?;
+                match policy {
+                    PopPolicy::FixedPriority => Some(idx),
+                    PopPolicy::OldestFirst => {
+                        if oldest.is_none_or(|(t, _)| tick < t) {
+                            oldest = Some((tick, idx));
+                        }
+                        None
+                    }
+                }
+            });
+            let idx = first.or(oldest.map(|(_, idx)| idx))?;
+            (idx, self.children[idx].ready.pop_front().unwrap().1)
+        };
+        self.children[idx].stats.responses += 1;
+        if let Some(ids) = self.ids.as_mut() {
+            if (ids.is_last)(&resp) {
+                ids.inflight.remove(&(ids.resp_id)(&resp));
+            }
+        }
+        Some((RegionId(idx), resp))
+    }
+
+    // Whether responses are taken from the children as soon as they are ready
+    fn buffered(&self) -> bool {
+        self.policy == PopPolicy::OldestFirst || self.decode_latency > 0
+    }
+
+    // Forwards the requests that have waited out the decode latency
+    fn release(&mut self) {
+        let now = self.tick;
+        self.visit_ports(|_, port| {
+            while port.decoding.front().is_some_and(|(tick, _)| *tick <= now) {
+                port.drain.push(port.decoding.pop_front().unwrap().1);
+            }
+            None::<()>
+        });
+    }
+
+    fn gather(&mut self) {
+        let now = self.tick;
+        self.visit_ports(|_, port| {
+            while let Some(resp) = port.drain.pop() {
+                port.ready.push_back((now, resp));
+            }
+            None::<()>
+        });
+    }
+
+    /// Renders the crossbar as a Graphviz digraph, with each region an edge labelled with its
+    /// address range. Nested crossbars become subgraphs.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph {\n");
+        self.write_dot("n0", &mut out);
+        out.push_str("}\n");
+        out
+    }
+
+    /// Maps `region` to `children` interleaved by address: `addr` goes to child
+    /// `(addr / stripe_bytes) % children.len()`. Children see untranslated addresses, and
+    /// requests must not straddle a stripe boundary.
+    pub fn with_striped(
+        self,
+        region: Range<A>,
+        children: Vec<Child<Req, Resp>>,
+        stripe_bytes: usize,
+    ) -> Crossbar<A, Req, Resp>
+    where
+        A: 'static,
+        Req: 'static,
+        Resp: 'static,
+    {
+        assert!(
+            !children.is_empty() && stripe_bytes > 0,
+            "Striping needs at least one child and a positive stripe size"
+        );
+        self.with_range(
+            region,
+            Box::new(Striped {
+                children,
+                stripe: stripe_bytes as u64,
+                _addr: core::marker::PhantomData,
+            }),
+        )
+    }
+
+    /// Like `with`, but the child sees addresses relative to `offset`
+    pub fn with_offset(
+        self,
+        start: A,
+        end: A,
+        offset: A,
+        inner: Child<Req, Resp>,
+    ) -> Crossbar<A, Req, Resp>
+    where
+        A: Sub<Output = A> + 'static,
+        Req: Relocatable<A> + 'static,
+        Resp: 'static,
+    {
+        self.with(start, end, Box::new(Offset::new(inner, offset)))
+    }
+
+    /// Maps `[start, end)` to `base`, except for the carved-out sub-regions which are routed to
+    /// their own children. Carve-outs must lie within `[start, end)` and must not overlap.
+    pub fn with_carve_outs(
+        mut self,
+        start: A,
+        end: A,
+        base: Box<dyn Drain<Req = Req, Resp = Resp>>,
+        mut carve_outs: Vec<(A, A, Child<Req, Resp>)>,
+    ) -> Crossbar<A, Req, Resp> {
+        let base_idx = self.add_port(format!("{}..{}", start, end), start, end, base);
+
+        carve_outs.sort_by_key(|(s, _, _)| *s);
+        let mut cursor = start;
+        for (s, e, child) in carve_outs {
+            if s < cursor || e > end || s >= e {
+                panic!("Invalid carve-out region: {} - {}", s, e);
+            }
+            if cursor < s {
+                self.regions.insert(cursor, (s, base_idx));
+            }
+            let idx = self.add_port(format!("{}..{}", s, e), s, e, child);
+            self.regions.insert(s, (e, idx));
+            cursor = e;
+        }
+        if cursor < end {
+            self.regions.insert(cursor, (end, base_idx));
+        }
         self
     }
 }
@@ -35,29 +731,164 @@ impl<A: Addr, Req: Routable<A>, Resp> Drain for Crossbar<A, Req, Resp> {
     type Resp = Resp;
 
     fn tick(&mut self) {
-        for (_, (_, child)) in self.children.iter_mut() {
-            child.tick();
+        self.check_wired();
+        if self.decode_latency > 0 {
+            // Requests are released between the children's tick halves, as `Delay` does
+            self.tick_begin();
+            self.tick_end();
+            return;
         }
+        self.tick += 1;
+        self.for_each_child(|_, _, child| child.tick());
+        if self.buffered() {
+            self.gather();
+        }
+    }
+
+    fn tick_begin(&mut self) {
+        self.check_wired();
+        self.tick += 1;
+        self.for_each_child(|_, _, child| child.tick_begin());
+        self.release();
+    }
+
+    fn tick_end(&mut self) {
+        self.for_each_child(|_, _, child| child.tick_end());
+        if self.buffered() {
+            self.gather();
+        }
+    }
+
+    /// Children don't interact, so each can skip ahead on its own unless requests have to be
+    /// released along the way
+    fn tick_n(&mut self, n: usize) {
+        self.check_wired();
+        if self.decode_latency > 0 {
+            for _ in 0..n {
+                self.tick();
+            }
+            return;
+        }
+        self.tick += n;
+        self.for_each_child(|_, _, child| child.tick_n(n));
+        if self.buffered() {
+            self.gather();
+        }
+    }
+
+    fn write_dot(&self, node: &str, out: &mut String) {
+        let _ = writeln!(out, "  subgraph cluster_{} {{", node);
+        let _ = writeln!(
+            out,
+            "  {} [label=\"{}\", shape=box];",
+            node,
+            self.kind_name()
+        );
+        self.visit_ports_ref(|idx, port| {
+            port.drain.write_dot(&format!("{}_{}", node, idx), out);
+            None::<()>
+        });
+        for (start, (end, idx)) in self.regions.iter() {
+            let _ = writeln!(
+                out,
+                "  {} -> {}_{} [label=\"{}..{}\"];",
+                node, node, idx, start, end
+            );
+        }
+        out.push_str("  }\n");
     }
 
     fn push(&mut self, req: Self::Req) {
         let addr = req.addr();
-        let lb = self.children.range_mut(..addr).last();
+        let lb = self.regions.range(..=addr).last();
         if lb.is_none() || lb.as_ref().unwrap().1 .0 <= addr {
             panic!("Out-of-range request address: {}", addr);
         }
 
-        lb.unwrap().1 .1.push(req);
+        let (_, &(end, idx)) = lb.unwrap();
+        let port = &mut self.children[idx];
+        if end <= req.last_addr() {
+            panic!(
+                "Request at {} straddles region boundary {} of {}",
+                addr, end, port.name
+            );
+        }
+        if let Some(alignment) = port.alignment {
+            if !addr.is_aligned(alignment) {
+                panic!(
+                    "Request at {} to {} is not {}-byte aligned",
+                    addr, port.name, alignment
+                );
+            }
+        }
+        if let Some(is_write) = self.is_write {
+            match (port.permissions, is_write(&req)) {
+                (Permissions::ReadOnly, true) => {
+                    panic!("Write at {} to read-only region {}", addr, port.name)
+                }
+                (Permissions::WriteOnly, false) => {
+                    panic!("Read at {} from write-only region {}", addr, port.name)
+                }
+                _ => {}
+            }
+        }
+        if let Some(ids) = self.ids.as_mut() {
+            let id = (ids.req_id)(&req);
+            if !ids.inflight.insert(id) {
+                panic!("Duplicated inflight request id: {}", id);
+            }
+        }
+        port.stats.requests += 1;
+        if self.decode_latency > 0 {
+            port.decoding
+                .push_back((self.tick + self.decode_latency, req));
+        } else {
+            port.drain.push(req);
+        }
     }
 
     fn pop(&mut self) -> Option<Resp> {
-        for (_, (_, child)) in self.children.iter_mut() {
-            let try_pop = child.pop();
-            if try_pop.is_some() {
-                return try_pop;
+        self.pop_with_region().map(|(_, resp)| resp)
+    }
+
+    fn cancel(&mut self, id: usize) -> bool {
+        let (req_id, resp_id) = (self.req_id, self.resp_id);
+        let taken = resp_id.and_then(|resp_id| {
+            self.visit_ports(|_, port| {
+                let idx = port
+                    .ready
+                    .iter()
+                    .position(|(_, resp)| resp_id(resp) == id)?;
+                port.ready.remove(idx)
+            })
+        });
+        let decoding = req_id.and_then(|req_id| {
+            self.visit_ports(|_, port| {
+                let idx = port
+                    .decoding
+                    .iter()
+                    .position(|(_, req)| req_id(req) == id)?;
+                port.decoding.remove(idx)
+            })
+        });
+        let cancelled = taken.is_some()
+            || decoding.is_some()
+            || self
+                .visit_ports(|_, port| port.drain.cancel(id).then_some(()))
+                .is_some();
+        if cancelled {
+            if let Some(ids) = self.ids.as_mut() {
+                ids.inflight.remove(&id);
             }
         }
-        None
+        cancelled
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        match A::from_u64(addr).and_then(|addr| self.region_of(addr)) {
+            Some(id) => self.children[id.0].drain.probe(addr),
+            None => ProbeResult::NotPresent,
+        }
     }
 }
 
@@ -69,12 +900,7 @@ fn test_multiple_memory() {
     let mut mem_b: Mem<_, 8> = Mem::new(NoDelay::default());
 
     mem_a.tick();
-    mem_a.push(MemReq {
-        id: 0,
-        addr: 0x80000040,
-        wbe: [true; 8],
-        wdata: [1; 8],
-    });
+    mem_a.push(MemReq::write(0, 0x80000040, [true; 8], [1; 8]));
     loop {
         if mem_a.pop().is_some() {
             break;
@@ -83,12 +909,7 @@ fn test_multiple_memory() {
     }
 
     mem_b.tick();
-    mem_b.push(MemReq {
-        id: 0,
-        addr: 0x80002040,
-        wbe: [true; 8],
-        wdata: [2; 8],
-    });
+    mem_b.push(MemReq::write(0, 0x80002040, [true; 8], [2; 8]));
     loop {
         if mem_b.pop().is_some() {
             break;
@@ -101,19 +922,9 @@ fn test_multiple_memory() {
         .with(0x80002000, 0x80004000, Box::new(Delay::new(mem_b, 4, 2)));
 
     crossbar.tick();
-    crossbar.push(MemReq {
-        id: 1,
-        addr: 0x80000040,
-        wbe: [false; 8],
-        wdata: [0; 8],
-    });
+    crossbar.push(MemReq::read(1, 0x80000040));
 
-    crossbar.push(MemReq {
-        id: 2,
-        addr: 0x80002040,
-        wbe: [false; 8],
-        wdata: [0; 8],
-    });
+    crossbar.push(MemReq::read(2, 0x80002040));
 
     let mut popped = 0;
     'outer: loop {
@@ -138,14 +949,633 @@ fn test_multiple_memory_oob() {
     let mut crossbar = Crossbar::new()
         .with(0x80000000, 0x80002000, Box::new(Delay::new(mem_a, 3, 5)))
         .with(0x80004000, 0x80008000, Box::new(Delay::new(mem_b, 4, 2)));
-    crossbar.push(MemReq {
-        id: 0,
-        addr: 0x80002000,
-        wbe: [false; 8],
-        wdata: [0; 8],
+    crossbar.push(MemReq::read(0, 0x80002000));
+
+    for _ in 0..10 {
+        crossbar.tick();
+    }
+}
+
+#[test]
+#[should_panic(expected = "straddles region boundary")]
+fn test_straddling_request() {
+    use crate::drain::*;
+    use crate::mem::*;
+    let mem_a: Mem<_, 8> = Mem::new(NoDelay::default());
+    let mem_b: Mem<_, 8> = Mem::new(NoDelay::default());
+    let mut crossbar = Crossbar::new()
+        .with(0x80000000, 0x80002004, Box::new(mem_a))
+        .with(0x80002004, 0x80004000, Box::new(mem_b));
+
+    crossbar.push(MemReq::read(1, 0x80002000));
+}
+
+#[cfg(test)]
+struct Echo {
+    tag: u8,
+    queue: std::collections::VecDeque<usize>,
+}
+
+#[cfg(test)]
+impl Echo {
+    fn new(tag: u8) -> Box<Echo> {
+        Box::new(Echo {
+            tag,
+            queue: Default::default(),
+        })
+    }
+}
+
+#[cfg(test)]
+impl Drain for Echo {
+    type Req = crate::mem::MemReq<8>;
+    type Resp = crate::mem::MemResp<8>;
+
+    fn tick(&mut self) {}
+
+    fn push(&mut self, req: Self::Req) {
+        self.queue.push_back(req.id);
+    }
+
+    fn pop(&mut self) -> Option<Self::Resp> {
+        self.queue.pop_front().map(|id| crate::mem::MemResp {
+            id,
+            rdata: [self.tag; 8],
+            beat: 0,
+            last: true,
+            src: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+fn route_tag<D: Drain<Req = crate::mem::MemReq<8>, Resp = crate::mem::MemResp<8>>>(
+    drain: &mut D,
+    addr: u64,
+) -> u8 {
+    drain.push(crate::mem::MemReq::read(0, addr));
+    drain.tick();
+    drain.pop().unwrap().rdata[0]
+}
+
+#[test]
+fn test_pop_with_region() {
+    let mut crossbar = Crossbar::new()
+        .with_named("ram", 0x80000000, 0x80001000, Echo::new(1))
+        .with_named("mmio", 0x90000000, 0x90001000, Echo::new(2));
+    for (id, addr) in [(0, 0x90000000), (1, 0x80000000)] {
+        crossbar.push(crate::mem::MemReq::read(id, addr));
+    }
+    crossbar.tick();
+
+    let mut seen = Vec::new();
+    while let Some((region, resp)) = crossbar.pop_with_region() {
+        seen.push((crossbar.name(region).to_string(), resp.id));
+    }
+    seen.sort();
+    assert_eq!(seen, [("mmio".to_string(), 0), ("ram".to_string(), 1)]);
+}
+
+#[test]
+fn test_striped() {
+    let mut crossbar = Crossbar::new()
+        .with_striped(
+            0x80000000..0x80001000,
+            vec![Echo::new(1), Echo::new(2)],
+            0x40,
+        )
+        .with(0x90000000, 0x90001000, Echo::new(3));
+    let tags: Vec<_> = (0..6)
+        .map(|i| route_tag(&mut crossbar, 0x80000000 + 0x40 * i))
+        .collect();
+    assert_eq!(tags, [1, 2, 1, 2, 1, 2]);
+    assert_eq!(route_tag(&mut crossbar, 0x80000078), 2);
+    assert_eq!(route_tag(&mut crossbar, 0x90000040), 3);
+}
+
+#[test]
+fn test_oldest_first() {
+    use crate::mem::*;
+    let order = |policy| {
+        let mem = |latency| Box::new(Mem::<_, 8>::new(FixedDelay::new(latency)));
+        let mut crossbar = Crossbar::new()
+            .with(0x80000000, 0x80001000, mem(3))
+            .with(0x90000000, 0x90001000, mem(1))
+            .with_pop_policy(policy);
+        for (id, addr) in [(0, 0x80000000), (1, 0x90000000)] {
+            crossbar.push(crate::mem::MemReq::read(id, addr));
+        }
+        // Request 1 is ready at tick 1 and request 0 at tick 3
+        for _ in 0..4 {
+            crossbar.tick();
+        }
+        let mut ids = Vec::new();
+        while let Some(resp) = crossbar.pop() {
+            ids.push(resp.id);
+        }
+        ids
+    };
+    assert_eq!(order(PopPolicy::FixedPriority), [0, 1]);
+    assert_eq!(order(PopPolicy::OldestFirst), [1, 0]);
+}
+
+#[test]
+#[should_panic(expected = "Crossbar ticked with no regions mapped")]
+fn test_strict_empty() {
+    use crate::mem::{MemReq, MemResp};
+    let mut crossbar: Crossbar<u64, MemReq<8>, MemResp<8>> = Crossbar::new().strict();
+    crossbar.tick();
+}
+
+#[test]
+fn test_strict_wired() {
+    use crate::mem::{MemReq, MemResp};
+    let mut wired = Crossbar::new()
+        .with(0x80000000, 0x80001000, Echo::new(1))
+        .strict();
+    assert_eq!(route_tag(&mut wired, 0x80000000), 1);
+
+    // Not strict, so an unwired crossbar ticks quietly
+    let mut crossbar: Crossbar<u64, MemReq<8>, MemResp<8>> = Crossbar::new();
+    crossbar.tick();
+    assert!(crossbar.pop().is_none());
+}
+
+#[test]
+fn test_decode_latency() {
+    use crate::mem::*;
+    let latency = |decode_latency| {
+        let mut crossbar = Crossbar::new()
+            .with(
+                0x80000000,
+                0x80001000,
+                Box::new(Mem::<_, 8>::new(NoDelay::default())),
+            )
+            .with_decode_latency(decode_latency);
+        crossbar.push(MemReq::read(0, 0x80000000));
+        let mut ticks = 0;
+        while crossbar.pop().is_none() {
+            crossbar.tick();
+            ticks += 1;
+        }
+        ticks
+    };
+    assert_eq!(latency(0), 0);
+    // One tick on the way down and one on the way back
+    assert_eq!(latency(1), 2);
+    assert_eq!(latency(3), 6);
+}
+
+#[test]
+fn test_add_in_loop() {
+    let regions = vec![
+        (0x80000000, 0x80001000, 1),
+        (0x80001000, 0x80003000, 2),
+        (0x90000000, 0x90001000, 3),
+    ];
+    let mut crossbar = Crossbar::new();
+    for (start, end, tag) in regions {
+        crossbar.add(start, end, Echo::new(tag));
+    }
+    crossbar
+        .add(0xa0000000, 0xa0001000, Echo::new(4))
+        .add(0xb0000000, 0xb0001000, Echo::new(5));
+
+    assert_eq!(route_tag(&mut crossbar, 0x80000ff8), 1);
+    assert_eq!(route_tag(&mut crossbar, 0x80001000), 2);
+    assert_eq!(route_tag(&mut crossbar, 0x80002ff8), 2);
+    assert_eq!(route_tag(&mut crossbar, 0x90000000), 3);
+    assert_eq!(route_tag(&mut crossbar, 0xa0000000), 4);
+    assert_eq!(route_tag(&mut crossbar, 0xb0000ff8), 5);
+}
+
+#[test]
+#[should_panic(expected = "Request at 2147483652 to 2147483648..2147487744 is not 8-byte aligned")]
+fn test_region_alignment() {
+    let mut crossbar = Crossbar::new().with_aligned(0x80000000, 0x80001000, 8, Echo::new(1));
+    route_tag(&mut crossbar, 0x80000004);
+}
+
+#[test]
+fn test_region_aligned_request() {
+    let mut crossbar = Crossbar::new().with_aligned(0x80000000, 0x80001000, 8, Echo::new(1));
+    assert_eq!(route_tag(&mut crossbar, 0x80000008), 1);
+}
+
+#[test]
+fn test_range_constructors() {
+    let mut crossbar = Crossbar::new()
+        .with_range(0x80000000..0x80001000, Echo::new(1))
+        .with_inclusive(0x80001000..=0x80001fff, Echo::new(2));
+
+    assert_eq!(route_tag(&mut crossbar, 0x80000000), 1);
+    assert_eq!(route_tag(&mut crossbar, 0x80000ff8), 1);
+    assert_eq!(route_tag(&mut crossbar, 0x80001000), 2);
+    assert_eq!(route_tag(&mut crossbar, 0x80001ff8), 2);
+    assert_eq!(
+        crossbar.region_of(0x80001fff),
+        crossbar.region("2147487744..2147491840")
+    );
+    assert_eq!(crossbar.region_of(0x80002000), None);
+}
+
+#[test]
+#[should_panic(expected = "Out-of-range request address: 2147491840")]
+fn test_inclusive_end() {
+    let mut crossbar = Crossbar::new().with_inclusive(0x80001000..=0x80001fff, Echo::new(2));
+    route_tag(&mut crossbar, 0x80002000);
+}
+
+#[test]
+fn test_carve_out() {
+    let mut crossbar = Crossbar::new().with_carve_outs(
+        0x80000000,
+        0x80100000,
+        Echo::new(1),
+        vec![(0x80040000, 0x80041000, Echo::new(2) as Box<_>)],
+    );
+
+    assert_eq!(route_tag(&mut crossbar, 0x80000000), 1);
+    assert_eq!(route_tag(&mut crossbar, 0x8003fff8), 1);
+    assert_eq!(route_tag(&mut crossbar, 0x80040000), 2);
+    assert_eq!(route_tag(&mut crossbar, 0x80040ff8), 2);
+    assert_eq!(route_tag(&mut crossbar, 0x80041000), 1);
+    assert_eq!(route_tag(&mut crossbar, 0x800ffff8), 1);
+}
+
+#[cfg(test)]
+struct Spy<D: Drain> {
+    inner: D,
+    seen: std::rc::Rc<std::cell::RefCell<Vec<u64>>>,
+}
+
+#[cfg(test)]
+impl<D: Drain<Req = crate::mem::MemReq<8>>> Drain for Spy<D> {
+    type Req = D::Req;
+    type Resp = D::Resp;
+
+    fn tick(&mut self) {
+        self.inner.tick();
+    }
+
+    fn push(&mut self, req: Self::Req) {
+        self.seen.borrow_mut().push(req.addr);
+        self.inner.push(req);
+    }
+
+    fn pop(&mut self) -> Option<Self::Resp> {
+        self.inner.pop()
+    }
+}
+
+#[test]
+fn test_offset_translation() {
+    use crate::mem::*;
+    let seen = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+    let mem: Mem<_, 8> = Mem::new(NoDelay::default());
+    let spy = Spy {
+        inner: mem,
+        seen: seen.clone(),
+    };
+    let mut crossbar =
+        Crossbar::new().with_offset(0x80000000, 0x80002000, 0x80000000, Box::new(spy));
+
+    crossbar.push(MemReq::write(0, 0x80000040, [true; 8], [5; 8]));
+    crossbar.tick();
+    assert!(crossbar.pop().is_some());
+    crossbar.push(MemReq::read(1, 0x80000040));
+    crossbar.tick();
+    let resp = crossbar.pop().unwrap();
+    assert_eq!(resp.id, 1);
+    assert_eq!(resp.rdata, [5; 8]);
+    assert_eq!(*seen.borrow(), vec![0x40, 0x40]);
+}
+
+#[test]
+fn test_named_regions() {
+    let mut crossbar = Crossbar::new()
+        .with_named("dram", 0x80000000, 0x80100000, Echo::new(1))
+        .with_named("mmio", 0x10000000, 0x10001000, Echo::new(2));
+
+    assert_eq!(route_tag(&mut crossbar, 0x80000000), 1);
+    assert_eq!(route_tag(&mut crossbar, 0x80000040), 1);
+    assert_eq!(route_tag(&mut crossbar, 0x10000000), 2);
+
+    let dram = crossbar.region("dram").unwrap();
+    let mmio = crossbar.region("mmio").unwrap();
+    assert_eq!(crossbar.region_of(0x80000080), Some(dram));
+    assert_eq!(crossbar.region_of(0x20000000), None);
+    assert_eq!(crossbar.name(mmio), "mmio");
+    assert_eq!(
+        crossbar.stats(dram),
+        RegionStats {
+            requests: 2,
+            responses: 2
+        }
+    );
+    assert_eq!(crossbar.stats(mmio).requests, 1);
+}
+
+#[test]
+fn test_region_id() {
+    let (crossbar, dram) = Crossbar::new()
+        .with(0x10000000, 0x10001000, Echo::new(1))
+        .with_id(0x80000000, 0x80100000, Echo::new(2));
+    let mut crossbar = crossbar.with(0x90000000, 0x90001000, Echo::new(3));
+
+    assert_eq!(route_tag(&mut crossbar, 0x80000040), 2);
+    assert_eq!(crossbar.region_of(0x80000040), Some(dram));
+    assert_eq!(crossbar.name(dram), "2147483648..2148532224");
+    assert_eq!(crossbar.stats(dram).requests, 1);
+}
+
+#[test]
+#[should_panic(expected = "straddles region boundary 268439552 of mmio")]
+fn test_named_region_diagnostics() {
+    let mut crossbar = Crossbar::new().with_named("mmio", 0x10000000, 0x10001000, Echo::new(2));
+    route_tag(&mut crossbar, 0x10000ffc);
+}
+
+#[test]
+fn test_for_each_child() {
+    let mut crossbar = Crossbar::new()
+        .with(0x80000000, 0x80100000, Echo::new(1))
+        .with_carve_outs(
+            0x90000000,
+            0x90010000,
+            Echo::new(2),
+            vec![(0x90004000, 0x90005000, Echo::new(3) as Box<_>)],
+        );
+
+    let mut count = 0;
+    let mut size = 0;
+    crossbar.for_each_child(|start, end, _| {
+        count += 1;
+        size += end - start;
     });
+    assert_eq!(count, 3);
+    assert_eq!(size, 0x100000 + 0x10000 + 0x1000);
+}
+
+#[test]
+fn test_cancel_routed() {
+    use crate::mem::*;
+    let mem_a: Mem<_, 8> = Mem::new(FixedDelay::new(2));
+    let mem_b: Mem<_, 8> = Mem::new(FixedDelay::new(4));
+    let mut crossbar = Crossbar::new()
+        .with(0x80000000, 0x80002000, Box::new(mem_a))
+        .with(0x80002000, 0x80004000, Box::new(mem_b));
+
+    for id in 0..4 {
+        crossbar.push(MemReq::read(id, 0x80000000 + 0x1000 * id as u64));
+    }
+    assert!(crossbar.cancel(3));
+    assert!(!crossbar.cancel(7));
 
+    let mut seen = Vec::new();
     for _ in 0..10 {
         crossbar.tick();
+        while let Some(resp) = crossbar.pop() {
+            seen.push(resp.id);
+        }
+    }
+    seen.sort();
+    assert_eq!(seen, vec![0, 1, 2]);
+}
+
+#[test]
+fn test_probe() {
+    use crate::drain::Delay;
+    use crate::mem::*;
+    let mem_a: Mem<_, 8> = Mem::new(FixedDelay::new(2));
+    let mem_b: Mem<_, 8> = Mem::new(FixedDelay::new(4));
+    let mut crossbar = Crossbar::new()
+        .with(0x80000000, 0x80002000, Box::new(mem_a))
+        .with_offset(
+            0x90000000,
+            0x90002000,
+            0x90000000,
+            Box::new(Delay::new(mem_b, 1, 1)),
+        );
+    let run = |crossbar: &mut Crossbar<_, _, _>| loop {
+        crossbar.tick();
+        if crossbar.pop().is_some() {
+            break;
+        }
+    };
+
+    crossbar.push(MemReq::write(
+        0,
+        0x90000010,
+        [true; 8],
+        [1, 2, 3, 4, 5, 6, 7, 8],
+    ));
+    run(&mut crossbar);
+    let stored = ProbeResult::Present {
+        data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+        inflight: 0,
+    };
+    assert_eq!(crossbar.probe(0x90000010), stored);
+
+    crossbar.push(MemReq::read(1, 0x90000010));
+    crossbar.tick();
+    assert_eq!(
+        crossbar.probe(0x90000010),
+        ProbeResult::Present {
+            data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+            inflight: 1,
+        }
+    );
+    run(&mut crossbar);
+    assert_eq!(crossbar.probe(0x90000010), stored);
+
+    assert_eq!(
+        crossbar.probe(0x80000000),
+        ProbeResult::Present {
+            data: vec![0; 8],
+            inflight: 0,
+        }
+    );
+    assert_eq!(crossbar.probe(0xa0000000), ProbeResult::NotPresent);
+}
+
+#[cfg(test)]
+fn unique_id_map() -> Crossbar<u64, crate::mem::MemReq<8>, crate::mem::MemResp<8>> {
+    use crate::mem::*;
+    let mem = || Box::new(Mem::<_, 8>::new(FixedDelay::new(2)));
+    Crossbar::new()
+        .with(0x80000000, 0x80002000, mem())
+        .with(0x80002000, 0x80004000, mem())
+        .with_unique_ids()
+}
+
+#[test]
+#[should_panic(expected = "Duplicated inflight request id: 5")]
+fn test_unique_ids() {
+    use crate::mem::*;
+    let mut crossbar = unique_id_map();
+    crossbar.push(MemReq::read(5, 0x80000000));
+    crossbar.push(MemReq::read(5, 0x80002000));
+}
+
+#[test]
+fn test_unique_ids_reuse() {
+    use crate::mem::*;
+    let mut crossbar = unique_id_map();
+    // Ids can be reused once the earlier response is popped
+    crossbar.push(MemReq::read(5, 0x80000000));
+    while crossbar.pop().is_none() {
+        crossbar.tick();
+    }
+    crossbar.push(MemReq::read(5, 0x80002000));
+    while crossbar.pop().is_none() {
+        crossbar.tick();
+    }
+}
+
+#[cfg(test)]
+fn permission_map() -> Crossbar<u64, crate::mem::MemReq<8>, crate::mem::MemResp<8>> {
+    use crate::mem::*;
+    let mem = || Box::new(Mem::<_, 8>::new(NoDelay::default()));
+    Crossbar::new()
+        .with_permissions(0x0, 0x1000, Permissions::ReadOnly, mem())
+        .with_permissions(0x1000, 0x2000, Permissions::WriteOnly, mem())
+        .with_permissions(0x2000, 0x3000, Permissions::ReadWrite, mem())
+}
+
+#[cfg(test)]
+fn access(
+    crossbar: &mut Crossbar<u64, crate::mem::MemReq<8>, crate::mem::MemResp<8>>,
+    addr: u64,
+    is_write: bool,
+) {
+    crossbar.push(crate::mem::MemReq::write(0, addr, [is_write; 8], [0; 8]));
+    crossbar.tick();
+    assert!(crossbar.pop().is_some());
+}
+
+#[test]
+fn test_permissions_legal() {
+    let mut crossbar = permission_map();
+    access(&mut crossbar, 0x0, false);
+    access(&mut crossbar, 0x1000, true);
+    access(&mut crossbar, 0x2000, false);
+    access(&mut crossbar, 0x2000, true);
+}
+
+#[test]
+#[should_panic(expected = "Write at 8 to read-only region 0..4096")]
+fn test_permissions_read_only() {
+    access(&mut permission_map(), 0x8, true);
+}
+
+#[test]
+#[should_panic(expected = "Read at 4104 from write-only region 4096..8192")]
+fn test_permissions_write_only() {
+    access(&mut permission_map(), 0x1008, false);
+}
+
+#[test]
+fn test_flat_address_space() {
+    use crate::mem::*;
+    let mem: Mem<_, 8> = Mem::new(NoDelay::default());
+    let mut flat = FlatAddressSpace::new(mem, 0x80000000, 0x80001000);
+    flat.push(MemReq::read(0, 0x80000ff8));
+    flat.tick();
+    assert_eq!(flat.pop().map(|resp| resp.id), Some(0));
+    assert_eq!(flat.probe(0x80001000), ProbeResult::NotPresent);
+}
+
+#[test]
+#[should_panic(expected = "Out-of-range request address: 2147487744")]
+fn test_flat_address_space_out_of_range() {
+    use crate::mem::*;
+    let mem: Mem<_, 8> = Mem::new(NoDelay::default());
+    let mut flat = FlatAddressSpace::new(mem, 0x80000000, 0x80001000);
+    flat.push(MemReq::read(0, 0x80001000));
+}
+
+#[test]
+fn test_tick_n() {
+    use crate::drain::Delay;
+    use crate::mem::*;
+    let build = || {
+        Crossbar::new()
+            .with(
+                0x80000000,
+                0x80001000,
+                Box::new(Delay::new(Mem::<_, 8>::new(FixedDelay::new(30)), 2, 3)),
+            )
+            .with(
+                0x90000000,
+                0x90001000,
+                Box::new(Mem::<_, 8>::new(QueuedDelay::new(4, 70))),
+            )
+    };
+    let push = |crossbar: &mut Crossbar<_, _, _>, id| {
+        crossbar.push(MemReq::read(
+            id,
+            [0x80000000, 0x90000000][id % 2] + 8 * id as u64,
+        ))
+    };
+
+    let mut stepped = build();
+    let mut skipped = build();
+    for crossbar in [&mut stepped, &mut skipped] {
+        // Idle first, so the memories can skip ahead
+        crossbar.tick_n(1000);
+        for id in 0..6 {
+            push(crossbar, id);
+        }
+    }
+    for _ in 0..100 {
+        stepped.tick();
+    }
+    skipped.tick_n(100);
+
+    let drain = |crossbar: &mut Crossbar<u64, MemReq<8>, MemResp<8>>| {
+        let mut ids = Vec::new();
+        while let Some(resp) = crossbar.pop() {
+            ids.push(resp.id);
+        }
+        ids
+    };
+    let ids = drain(&mut stepped);
+    assert_eq!(ids.len(), 6);
+    assert_eq!(drain(&mut skipped), ids);
+}
+
+#[test]
+fn test_to_dot() {
+    use crate::drain::Delay;
+    use crate::mem::*;
+    let mem = || Box::new(Mem::<_, 8>::new(NoDelay::default()));
+    let inner = Crossbar::new().with(0x100, 0x200, mem()).with(
+        0x200,
+        0x300,
+        Box::new(Delay::new(Mem::<_, 8>::new(NoDelay::default()), 1, 1)),
+    );
+    // Wrappers are transparent, so the nested crossbar still becomes a subgraph
+    let crossbar = Crossbar::new().with(0x0, 0x100, mem()).with(
+        0x100,
+        0x300,
+        Box::new(Delay::new(inner, 1, 1)),
+    );
+
+    let dot = crossbar.to_dot();
+    assert!(dot.starts_with("digraph {\n"));
+    for line in [
+        "subgraph cluster_n0 {",
+        "n0 [label=\"Crossbar\", shape=box];",
+        "n0_0 [label=\"Mem\"];",
+        "n0 -> n0_0 [label=\"0..256\"];",
+        "n0 -> n0_1 [label=\"256..768\"];",
+        "subgraph cluster_n0_1 {",
+        "n0_1 [label=\"Crossbar\", shape=box];",
+        "n0_1_0 [label=\"Mem\"];",
+        "n0_1_1 [label=\"Mem\"];",
+        "n0_1 -> n0_1_1 [label=\"512..768\"];",
+    ] {
+        assert!(dot.contains(line), "{} missing from:\n{}", line, dot);
     }
 }
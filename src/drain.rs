@@ -1,22 +1,250 @@
-use std::collections::VecDeque;
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    rc::Rc,
+    string::String,
+    vec::Vec,
+};
+use core::{cell::Cell, fmt::Write};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::cache::{FillPolicy, WriteThroughCache};
+use crate::crossbar::Routable;
+use crate::mem::{MemReq, MemResp};
 
 pub trait Drain {
     type Req;
     type Resp;
     fn tick(&mut self);
+
+    /// Two-phase ticking for co-simulation: `tick_begin` advances time and issues accepted
+    /// requests, `tick_end` collects completions. Responses collected by `tick_end` are fixed at
+    /// that point, so pushes and pops interleaved before the next `tick_begin` can be done in
+    /// any order. Calling both in sequence is equivalent to `tick`.
+    fn tick_begin(&mut self) {
+        self.tick();
+    }
+    fn tick_end(&mut self) {}
+
+    /// Equivalent to calling `tick` `n` times. Drains that can skip over idle stretches override
+    /// this, but anything observable per tick, like timeouts or per-tick sampling, still happens
+    /// at the tick it would have.
+    fn tick_n(&mut self, n: usize) {
+        for _ in 0..n {
+            self.tick();
+        }
+    }
+
     fn push(&mut self, req: Self::Req);
     fn pop(&mut self) -> Option<Self::Resp>;
+
+    /// Cancels an outstanding request, so that its response is never delivered. Returns false
+    /// if this drain doesn't know about the request.
+    fn cancel(&mut self, _id: usize) -> bool {
+        false
+    }
+
+    /// Looks up the stored value at `addr` and how many requests to it are in flight, for
+    /// debugging. Doesn't affect timing.
+    fn probe(&self, _addr: u64) -> ProbeResult {
+        ProbeResult::NotPresent
+    }
+
+    /// Type name without module path or generics, e.g. `Mem`
+    fn kind_name(&self) -> &'static str {
+        let name = core::any::type_name::<Self>();
+        let name = &name[..name.find('<').unwrap_or(name.len())];
+        name.rsplit("::").next().unwrap()
+    }
+
+    /// Appends a Graphviz node called `node` describing this drain, see `Crossbar::to_dot`.
+    /// Drains containing others may write a subgraph around it instead, and wrappers around a
+    /// single drain forward both this and `kind_name` to it.
+    fn write_dot(&self, node: &str, out: &mut String) {
+        let _ = writeln!(out, "  {} [label=\"{}\"];", node, self.kind_name());
+    }
+}
+
+impl<T: Drain + ?Sized> Drain for Box<T> {
+    type Req = T::Req;
+    type Resp = T::Resp;
+
+    fn tick(&mut self) {
+        (**self).tick();
+    }
+
+    fn tick_begin(&mut self) {
+        (**self).tick_begin();
+    }
+
+    fn tick_end(&mut self) {
+        (**self).tick_end();
+    }
+
+    fn tick_n(&mut self, n: usize) {
+        (**self).tick_n(n);
+    }
+
+    fn push(&mut self, req: Self::Req) {
+        (**self).push(req);
+    }
+
+    fn pop(&mut self) -> Option<Self::Resp> {
+        (**self).pop()
+    }
+
+    fn cancel(&mut self, id: usize) -> bool {
+        (**self).cancel(id)
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        (**self).probe(addr)
+    }
+
+    fn kind_name(&self) -> &'static str {
+        (**self).kind_name()
+    }
+
+    fn write_dot(&self, node: &str, out: &mut String) {
+        (**self).write_dot(node, out);
+    }
+}
+
+pub trait DrainExt: Drain + Sized {
+    fn delayed(self, up_delay: usize, down_delay: usize) -> Delay<Self> {
+        Delay::new(self, up_delay, down_delay)
+    }
+
+    fn cached<const WIDTH: usize>(
+        self,
+        capacity: usize,
+        policy: FillPolicy,
+    ) -> WriteThroughCache<Self, WIDTH>
+    where
+        Self: Drain<Req = MemReq<WIDTH>, Resp = MemResp<WIDTH>>,
+    {
+        WriteThroughCache::new(self, capacity, policy)
+    }
+
+    fn tagged<Ctx>(self) -> Tagged<Self, Ctx> {
+        Tagged::new(self)
+    }
+}
+
+impl<T: Drain> DrainExt for T {}
+
+pub trait Access {
+    fn is_write(&self) -> bool;
+}
+
+pub trait Identified {
+    fn id(&self) -> usize;
+
+    /// Whether this is the last response to its request. Only the earlier beats of
+    /// `Mem::with_beat_responses` aren't.
+    fn is_last(&self) -> bool {
+        true
+    }
+}
+
+impl<T: Identified, Ctx> Identified for (T, Ctx) {
+    fn id(&self) -> usize {
+        self.0.id()
+    }
+
+    fn is_last(&self) -> bool {
+        self.0.is_last()
+    }
+}
+
+/// What `Drain::probe` finds at an address
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub enum ProbeResult {
+    /// Nothing along the way backs the address
+    #[default]
+    NotPresent,
+    Present {
+        data: Vec<u8>,
+        inflight: usize,
+    },
 }
 
+/// What a bounded response buffer does with a completion arriving while it's full
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OverflowPolicy {
+    Panic,
+    /// Discards the oldest buffered response to make room
+    DropOldest,
+    /// Leaves further completions in the inner drain or simulator until there's room
+    Block,
+}
+
+pub(crate) fn overflow_blocks(cap: Option<(usize, OverflowPolicy)>, len: usize) -> bool {
+    matches!(cap, Some((cap, OverflowPolicy::Block)) if len >= cap)
+}
+
+/// Makes room for one more response in `queue`, according to the overflow policy
+pub(crate) fn make_room<E>(queue: &mut VecDeque<E>, cap: Option<(usize, OverflowPolicy)>) {
+    match cap {
+        Some((cap, OverflowPolicy::Panic)) if queue.len() >= cap => {
+            panic!("Response buffer overflow: {} responses not popped", cap)
+        }
+        Some((cap, OverflowPolicy::DropOldest)) if queue.len() >= cap => {
+            queue.pop_front();
+        }
+        _ => {}
+    }
+}
+
+/// Simulation-wide time, shared by drains so that they agree on the current tick. Its owner
+/// advances it once per cycle, before ticking the drains sharing it.
+#[derive(Clone, Default, Debug)]
+pub struct Clock(Rc<Cell<usize>>);
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock::default()
+    }
+
+    pub fn now(&self) -> usize {
+        self.0.get()
+    }
+
+    pub fn advance(&self) {
+        self.0.set(self.0.get() + 1);
+    }
+
+    pub fn advance_by(&self, n: usize) {
+        self.0.set(self.0.get() + n);
+    }
+}
+
+/// A request pushed at tick `T` reaches the inner drain at `T + down_delay`, and a response
+/// completed by the inner drain at tick `C` can be popped from `C + up_delay` on. With both
+/// delays at zero this is a transparent passthrough.
 pub struct Delay<T: Drain> {
     inner: T,
 
     up_delay: usize,
     down_delay: usize,
 
-    tick: usize,
+    up_jitter: usize,
+    down_jitter: usize,
+    rng: Option<StdRng>,
+
+    tick: Clock,
+    shared_clock: bool,
+    response_cap: Option<(usize, OverflowPolicy)>,
     downlink: VecDeque<(usize, T::Req)>,
     uplink: VecDeque<(usize, T::Resp)>,
+    // Requests forwarded to the inner drain and not answered yet, and those among them that were
+    // cancelled while the inner drain didn't support it
+    forwarded: BTreeSet<usize>,
+    cancelled: BTreeSet<usize>,
+    // Which requests are delayed at all, and the forwarded ones that weren't
+    selective: Option<fn(&T::Req) -> bool>,
+    undelayed: BTreeSet<usize>,
 }
 
 impl<T: Drain> Delay<T> {
@@ -25,40 +253,1452 @@ impl<T: Drain> Delay<T> {
             inner,
             up_delay,
             down_delay,
-            tick: 0,
+            up_jitter: 0,
+            down_jitter: 0,
+            rng: None,
+            tick: Clock::new(),
+            shared_clock: false,
+            response_cap: None,
             downlink: VecDeque::new(),
             uplink: VecDeque::new(),
+            forwarded: BTreeSet::new(),
+            cancelled: BTreeSet::new(),
+            selective: None,
+            undelayed: BTreeSet::new(),
+        }
+    }
+
+    /// Adds a uniformly random extra delay in `[0, jitter]` to each request, so responses may be
+    /// reordered relative to their pushes
+    pub fn with_jitter(mut self, up_jitter: usize, down_jitter: usize, seed: u64) -> Delay<T> {
+        self.up_jitter = up_jitter;
+        self.down_jitter = down_jitter;
+        self.rng = Some(StdRng::seed_from_u64(seed));
+        self
+    }
+
+    /// Drives this delay from an external clock instead of its own counter. `tick` then no
+    /// longer advances time, but each drain still needs its own `tick` call to make progress.
+    pub fn with_clock(mut self, clock: Clock) -> Delay<T> {
+        self.tick = clock;
+        self.shared_clock = true;
+        self
+    }
+
+    /// Bounds the number of responses waiting to be popped, including those still in flight
+    /// upwards
+    pub fn with_response_cap(mut self, cap: usize, policy: OverflowPolicy) -> Delay<T> {
+        self.response_cap = Some((cap, policy));
+        self
+    }
+
+    pub fn current_tick(&self) -> usize {
+        self.tick.now()
+    }
+
+    fn jitter(&mut self, max: usize) -> usize {
+        match self.rng.as_mut() {
+            Some(rng) => rng.gen_range(0..=max),
+            None => 0,
+        }
+    }
+}
+
+impl<T: Drain> Delay<T>
+where
+    T::Req: Identified,
+    T::Resp: Identified,
+{
+    fn forward(&mut self, req: T::Req) {
+        if self.selective.is_some_and(|delayed| !delayed(&req)) {
+            self.undelayed.insert(req.id());
+        }
+        self.forwarded.insert(req.id());
+        self.inner.push(req);
+    }
+
+    fn collect(&mut self) {
+        while !overflow_blocks(self.response_cap, self.uplink.len()) {
+            let Some(resp) = self.inner.pop() else {
+                break;
+            };
+            // Earlier beats leave the request in flight
+            let (undelayed, cancelled) = if resp.is_last() {
+                self.forwarded.remove(&resp.id());
+                (
+                    self.undelayed.remove(&resp.id()),
+                    self.cancelled.remove(&resp.id()),
+                )
+            } else {
+                (
+                    self.undelayed.contains(&resp.id()),
+                    self.cancelled.contains(&resp.id()),
+                )
+            };
+            if cancelled {
+                continue;
+            }
+            make_room(&mut self.uplink, self.response_cap);
+            let at = if undelayed {
+                self.current_tick()
+            } else {
+                self.current_tick() + self.up_delay + self.jitter(self.up_jitter)
+            };
+            schedule(&mut self.uplink, at, resp);
         }
     }
 }
 
-impl<T: Drain> Drain for Delay<T> {
+impl<T: Drain> Delay<T>
+where
+    T::Req: Access,
+{
+    /// Delays only reads, passing writes and their responses straight through, like a
+    /// write-posting interconnect
+    pub fn read_only(inner: T, up_delay: usize, down_delay: usize) -> Delay<T> {
+        let mut delay = Delay::new(inner, up_delay, down_delay);
+        delay.selective = Some(|req| !req.is_write());
+        delay
+    }
+
+    /// Delays only writes, passing reads and their responses straight through
+    pub fn write_only(inner: T, up_delay: usize, down_delay: usize) -> Delay<T> {
+        let mut delay = Delay::new(inner, up_delay, down_delay);
+        delay.selective = Some(|req| req.is_write());
+        delay
+    }
+}
+
+fn schedule<E>(queue: &mut VecDeque<(usize, E)>, at: usize, e: E) {
+    let idx = queue.partition_point(|(t, _)| *t <= at);
+    queue.insert(idx, (at, e));
+}
+
+impl<T: Drain> Drain for Delay<T>
+where
+    T::Req: Identified,
+    T::Resp: Identified,
+{
     type Req = T::Req;
     type Resp = T::Resp;
 
     fn tick(&mut self) {
-        self.inner.tick();
-        self.tick += 1;
-        while self.downlink.front().is_some_and(|(t, _)| *t >= self.tick) {
+        self.tick_begin();
+        self.tick_end();
+    }
+
+    fn tick_begin(&mut self) {
+        self.inner.tick_begin();
+        if !self.shared_clock {
+            self.tick.advance();
+        }
+        let now = self.current_tick();
+        while self.downlink.front().is_some_and(|(t, _)| *t <= now) {
             let (_, req) = self.downlink.pop_front().unwrap();
-            self.inner.push(req);
+            self.forward(req);
         }
+    }
 
-        while let Some(resp) = self.inner.pop() {
-            self.uplink.push_back((self.tick + self.up_delay, resp));
-        }
+    fn tick_end(&mut self) {
+        self.inner.tick_end();
+        self.collect();
     }
 
     fn push(&mut self, req: Self::Req) {
-        self.downlink.push_back((self.tick + self.down_delay, req));
+        let at = if self.selective.is_some_and(|delayed| !delayed(&req)) {
+            self.current_tick()
+        } else {
+            self.current_tick() + self.down_delay + self.jitter(self.down_jitter)
+        };
+        if at <= self.current_tick() {
+            // Zero down delay: pass straight through, picking up immediate completions
+            self.forward(req);
+            self.collect();
+        } else {
+            schedule(&mut self.downlink, at, req);
+        }
     }
 
     fn pop(&mut self) -> Option<Self::Resp> {
-        if self.uplink.front().is_some_and(|(t, _)| *t >= self.tick) {
+        if self
+            .uplink
+            .front()
+            .is_some_and(|(t, _)| *t <= self.current_tick())
+        {
             let (_, resp) = self.uplink.pop_front().unwrap();
             Some(resp)
         } else {
             None
         }
     }
+
+    /// Cancellation takes effect immediately wherever the request is: still on its way down, in
+    /// the inner drain, or as a response waiting out `up_delay`. If the inner drain can't cancel
+    /// it, the response is dropped once it comes back up.
+    fn cancel(&mut self, id: usize) -> bool {
+        if let Some(idx) = self.downlink.iter().position(|(_, req)| req.id() == id) {
+            self.downlink.remove(idx);
+            return true;
+        }
+        if let Some(idx) = self.uplink.iter().position(|(_, resp)| resp.id() == id) {
+            self.uplink.remove(idx);
+            return true;
+        }
+        if !self.forwarded.contains(&id) {
+            return false;
+        }
+        if !self.inner.cancel(id) {
+            self.cancelled.insert(id);
+        } else {
+            self.forwarded.remove(&id);
+            self.undelayed.remove(&id);
+        }
+        true
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        self.inner.probe(addr)
+    }
+
+    fn kind_name(&self) -> &'static str {
+        self.inner.kind_name()
+    }
+
+    fn write_dot(&self, node: &str, out: &mut String) {
+        self.inner.write_dot(node, out);
+    }
+}
+
+pub struct Tagged<T: Drain, Ctx> {
+    inner: T,
+    ctxs: BTreeMap<usize, Ctx>,
+}
+
+impl<T: Drain, Ctx> Tagged<T, Ctx> {
+    pub fn new(inner: T) -> Tagged<T, Ctx> {
+        Tagged {
+            inner,
+            ctxs: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T: Drain, Ctx> Drain for Tagged<T, Ctx>
+where
+    T::Req: Identified,
+    T::Resp: Identified,
+{
+    type Req = (T::Req, Ctx);
+    type Resp = (T::Resp, Ctx);
+
+    fn tick(&mut self) {
+        self.inner.tick();
+    }
+
+    fn tick_begin(&mut self) {
+        self.inner.tick_begin();
+    }
+
+    fn tick_end(&mut self) {
+        self.inner.tick_end();
+    }
+
+    fn push(&mut self, (req, ctx): Self::Req) {
+        if self.ctxs.insert(req.id(), ctx).is_some() {
+            panic!("Duplicated inflight request id: {}", req.id());
+        }
+        self.inner.push(req);
+    }
+
+    fn pop(&mut self) -> Option<Self::Resp> {
+        self.inner.pop().map(|resp| {
+            let ctx = self
+                .ctxs
+                .remove(&resp.id())
+                .expect("Response for unknown request id");
+            (resp, ctx)
+        })
+    }
+
+    fn cancel(&mut self, id: usize) -> bool {
+        let cancelled = self.inner.cancel(id);
+        if cancelled {
+            self.ctxs.remove(&id);
+        }
+        cancelled
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        self.inner.probe(addr)
+    }
+
+    fn kind_name(&self) -> &'static str {
+        self.inner.kind_name()
+    }
+
+    fn write_dot(&self, node: &str, out: &mut String) {
+        self.inner.write_dot(node, out);
+    }
+}
+
+/// Models a core's issue window: pushed requests are held back so that at most `window` are
+/// outstanding in the inner drain, and at most `rate` are issued to it per tick
+pub struct IssueWindow<T: Drain> {
+    inner: T,
+    window: usize,
+    rate: usize,
+
+    outstanding: usize,
+    issued: usize,
+    pending: VecDeque<T::Req>,
+}
+
+impl<T: Drain> IssueWindow<T> {
+    pub fn new(inner: T, window: usize, rate: usize) -> IssueWindow<T> {
+        assert!(window > 0 && rate > 0, "window and rate must be positive");
+        IssueWindow {
+            inner,
+            window,
+            rate,
+            outstanding: 0,
+            issued: 0,
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn outstanding(&self) -> usize {
+        self.outstanding
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn issue(&mut self) {
+        while self.outstanding < self.window && self.issued < self.rate {
+            let Some(req) = self.pending.pop_front() else {
+                break;
+            };
+            self.inner.push(req);
+            self.outstanding += 1;
+            self.issued += 1;
+        }
+    }
+}
+
+impl<T: Drain> Drain for IssueWindow<T>
+where
+    T::Req: Identified,
+{
+    type Req = T::Req;
+    type Resp = T::Resp;
+
+    fn tick(&mut self) {
+        self.tick_begin();
+        self.tick_end();
+    }
+
+    fn tick_begin(&mut self) {
+        self.issued = 0;
+        self.issue();
+        self.inner.tick_begin();
+    }
+
+    fn tick_end(&mut self) {
+        self.inner.tick_end();
+    }
+
+    fn push(&mut self, req: Self::Req) {
+        self.pending.push_back(req);
+        self.issue();
+    }
+
+    fn pop(&mut self) -> Option<Self::Resp> {
+        let resp = self.inner.pop()?;
+        self.outstanding -= 1;
+        self.issue();
+        Some(resp)
+    }
+
+    fn cancel(&mut self, id: usize) -> bool {
+        if let Some(idx) = self.pending.iter().position(|req| req.id() == id) {
+            self.pending.remove(idx);
+            return true;
+        }
+        let cancelled = self.inner.cancel(id);
+        if cancelled {
+            self.outstanding -= 1;
+        }
+        cancelled
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        self.inner.probe(addr)
+    }
+
+    fn kind_name(&self) -> &'static str {
+        self.inner.kind_name()
+    }
+
+    fn write_dot(&self, node: &str, out: &mut String) {
+        self.inner.write_dot(node, out);
+    }
+}
+
+/// A request-response handshake: only one request is outstanding at a time, and the next is
+/// issued once the response to the previous one has been popped. Requests pushed meanwhile are
+/// queued in order.
+pub struct Handshake<T: Drain> {
+    inner: T,
+    outstanding: Option<usize>,
+    pending: VecDeque<T::Req>,
+}
+
+impl<T: Drain> Handshake<T>
+where
+    T::Req: Identified,
+{
+    pub fn new(inner: T) -> Handshake<T> {
+        Handshake {
+            inner,
+            outstanding: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.outstanding.is_some()
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Like `push`, but hands the request back instead of queueing it if another one is
+    /// outstanding or queued
+    pub fn try_push(&mut self, req: T::Req) -> Result<(), T::Req> {
+        if self.is_busy() || !self.pending.is_empty() {
+            return Err(req);
+        }
+        self.issue(req);
+        Ok(())
+    }
+
+    fn issue(&mut self, req: T::Req) {
+        self.outstanding = Some(req.id());
+        self.inner.push(req);
+    }
+}
+
+impl<T: Drain> Drain for Handshake<T>
+where
+    T::Req: Identified,
+{
+    type Req = T::Req;
+    type Resp = T::Resp;
+
+    fn tick(&mut self) {
+        self.inner.tick();
+    }
+
+    fn tick_begin(&mut self) {
+        self.inner.tick_begin();
+    }
+
+    fn tick_end(&mut self) {
+        self.inner.tick_end();
+    }
+
+    fn push(&mut self, req: Self::Req) {
+        if self.is_busy() {
+            self.pending.push_back(req);
+        } else {
+            self.issue(req);
+        }
+    }
+
+    fn pop(&mut self) -> Option<Self::Resp> {
+        let resp = self.inner.pop()?;
+        self.outstanding = None;
+        if let Some(req) = self.pending.pop_front() {
+            self.issue(req);
+        }
+        Some(resp)
+    }
+
+    fn cancel(&mut self, id: usize) -> bool {
+        if let Some(idx) = self.pending.iter().position(|req| req.id() == id) {
+            self.pending.remove(idx);
+            return true;
+        }
+        if self.outstanding != Some(id) || !self.inner.cancel(id) {
+            return false;
+        }
+        self.outstanding = None;
+        if let Some(req) = self.pending.pop_front() {
+            self.issue(req);
+        }
+        true
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        self.inner.probe(addr)
+    }
+
+    fn kind_name(&self) -> &'static str {
+        self.inner.kind_name()
+    }
+
+    fn write_dot(&self, node: &str, out: &mut String) {
+        self.inner.write_dot(node, out);
+    }
+}
+
+/// Response-side credit flow control: each popped response takes up a credit granted by the
+/// consumer, and responses stay buffered in the inner drain while none are left. Starts out
+/// without credits.
+pub struct Credited<T: Drain> {
+    inner: T,
+    credits: usize,
+}
+
+impl<T: Drain> Credited<T> {
+    pub fn new(inner: T) -> Credited<T> {
+        Credited { inner, credits: 0 }
+    }
+
+    pub fn grant_credits(&mut self, n: usize) {
+        self.credits += n;
+    }
+
+    pub fn credits(&self) -> usize {
+        self.credits
+    }
+}
+
+impl<T: Drain> Drain for Credited<T> {
+    type Req = T::Req;
+    type Resp = T::Resp;
+
+    fn tick(&mut self) {
+        self.inner.tick();
+    }
+
+    fn tick_begin(&mut self) {
+        self.inner.tick_begin();
+    }
+
+    fn tick_end(&mut self) {
+        self.inner.tick_end();
+    }
+
+    fn push(&mut self, req: Self::Req) {
+        self.inner.push(req);
+    }
+
+    fn pop(&mut self) -> Option<Self::Resp> {
+        if self.credits == 0 {
+            return None;
+        }
+        let resp = self.inner.pop()?;
+        self.credits -= 1;
+        Some(resp)
+    }
+
+    fn cancel(&mut self, id: usize) -> bool {
+        self.inner.cancel(id)
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        self.inner.probe(addr)
+    }
+
+    fn kind_name(&self) -> &'static str {
+        self.inner.kind_name()
+    }
+
+    fn write_dot(&self, node: &str, out: &mut String) {
+        self.inner.write_dot(node, out);
+    }
+}
+
+/// Freezes the inner drain for the last `stall_len` ticks of every `period` ticks. Requests
+/// pushed during a stall are held back, and nothing completes until it's over.
+pub struct PeriodicStall<T: Drain> {
+    inner: T,
+    period: usize,
+    stall_len: usize,
+
+    tick: usize,
+    held: VecDeque<T::Req>,
+}
+
+impl<T: Drain> PeriodicStall<T> {
+    pub fn new(inner: T, period: usize, stall_len: usize) -> PeriodicStall<T> {
+        assert!(stall_len < period, "stall_len must be shorter than period");
+        PeriodicStall {
+            inner,
+            period,
+            stall_len,
+            tick: 0,
+            held: VecDeque::new(),
+        }
+    }
+
+    pub fn is_stalling(&self) -> bool {
+        self.tick % self.period >= self.period - self.stall_len
+    }
+}
+
+impl<T: Drain> Drain for PeriodicStall<T>
+where
+    T::Req: Identified,
+{
+    type Req = T::Req;
+    type Resp = T::Resp;
+
+    fn tick(&mut self) {
+        self.tick_begin();
+        self.tick_end();
+    }
+
+    fn tick_begin(&mut self) {
+        self.tick += 1;
+        if !self.is_stalling() {
+            for req in self.held.drain(..) {
+                self.inner.push(req);
+            }
+            self.inner.tick_begin();
+        }
+    }
+
+    fn tick_end(&mut self) {
+        if !self.is_stalling() {
+            self.inner.tick_end();
+        }
+    }
+
+    fn push(&mut self, req: Self::Req) {
+        if self.is_stalling() {
+            self.held.push_back(req);
+        } else {
+            self.inner.push(req);
+        }
+    }
+
+    fn pop(&mut self) -> Option<Self::Resp> {
+        if self.is_stalling() {
+            None
+        } else {
+            self.inner.pop()
+        }
+    }
+
+    fn cancel(&mut self, id: usize) -> bool {
+        if let Some(idx) = self.held.iter().position(|req| req.id() == id) {
+            self.held.remove(idx);
+            return true;
+        }
+        self.inner.cancel(id)
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        self.inner.probe(addr)
+    }
+
+    fn kind_name(&self) -> &'static str {
+        self.inner.kind_name()
+    }
+
+    fn write_dot(&self, node: &str, out: &mut String) {
+        self.inner.write_dot(node, out);
+    }
+}
+
+/// Freezes the inner drain between `pause` and `resume`, e.g. while a debugger single-steps the
+/// rest of the system. Ticks are ignored and nothing completes; requests pushed meanwhile are
+/// held back and forwarded on `resume`.
+pub struct Pausable<T: Drain> {
+    inner: T,
+    paused: bool,
+    held: VecDeque<T::Req>,
+}
+
+impl<T: Drain> Pausable<T> {
+    pub fn new(inner: T) -> Pausable<T> {
+        Pausable {
+            inner,
+            paused: false,
+            held: VecDeque::new(),
+        }
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+        for req in self.held.drain(..) {
+            self.inner.push(req);
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+}
+
+impl<T: Drain> Drain for Pausable<T>
+where
+    T::Req: Identified,
+{
+    type Req = T::Req;
+    type Resp = T::Resp;
+
+    fn tick(&mut self) {
+        if !self.paused {
+            self.inner.tick();
+        }
+    }
+
+    fn tick_begin(&mut self) {
+        if !self.paused {
+            self.inner.tick_begin();
+        }
+    }
+
+    fn tick_end(&mut self) {
+        if !self.paused {
+            self.inner.tick_end();
+        }
+    }
+
+    fn tick_n(&mut self, n: usize) {
+        if !self.paused {
+            self.inner.tick_n(n);
+        }
+    }
+
+    fn push(&mut self, req: Self::Req) {
+        if self.paused {
+            self.held.push_back(req);
+        } else {
+            self.inner.push(req);
+        }
+    }
+
+    fn pop(&mut self) -> Option<Self::Resp> {
+        if self.paused {
+            None
+        } else {
+            self.inner.pop()
+        }
+    }
+
+    fn cancel(&mut self, id: usize) -> bool {
+        if let Some(idx) = self.held.iter().position(|req| req.id() == id) {
+            self.held.remove(idx);
+            return true;
+        }
+        self.inner.cancel(id)
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        self.inner.probe(addr)
+    }
+
+    fn kind_name(&self) -> &'static str {
+        self.inner.kind_name()
+    }
+
+    fn write_dot(&self, node: &str, out: &mut String) {
+        self.inner.write_dot(node, out);
+    }
+}
+
+/// What `DedupGuard` does with a duplicated request
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DuplicatePolicy {
+    Panic,
+    /// Discards the duplicate, so only the first one gets a response
+    Drop,
+}
+
+/// Catches a request pushed again with the same id and address less than `window` ticks after
+/// the last time, which usually means the upstream issued it twice
+pub struct DedupGuard<T: Drain> {
+    inner: T,
+    window: usize,
+    policy: DuplicatePolicy,
+
+    tick: usize,
+    // (tick pushed, id, addr) of the requests pushed within the window, oldest first
+    seen: VecDeque<(usize, usize, u64)>,
+    duplicates: u64,
+}
+
+impl<T: Drain> DedupGuard<T> {
+    pub fn new(inner: T, window: usize, policy: DuplicatePolicy) -> DedupGuard<T> {
+        DedupGuard {
+            inner,
+            window,
+            policy,
+            tick: 0,
+            seen: VecDeque::new(),
+            duplicates: 0,
+        }
+    }
+
+    /// Duplicates dropped so far
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+}
+
+impl<T: Drain> Drain for DedupGuard<T>
+where
+    T::Req: Identified + Routable<u64>,
+{
+    type Req = T::Req;
+    type Resp = T::Resp;
+
+    fn tick(&mut self) {
+        self.tick_begin();
+        self.tick_end();
+    }
+
+    fn tick_begin(&mut self) {
+        self.tick += 1;
+        self.inner.tick_begin();
+    }
+
+    fn tick_end(&mut self) {
+        self.inner.tick_end();
+    }
+
+    fn tick_n(&mut self, n: usize) {
+        self.tick += n;
+        self.inner.tick_n(n);
+    }
+
+    fn push(&mut self, req: Self::Req) {
+        while self
+            .seen
+            .front()
+            .is_some_and(|(t, _, _)| self.tick - t >= self.window)
+        {
+            self.seen.pop_front();
+        }
+        let (id, addr) = (req.id(), req.addr());
+        if self.seen.iter().any(|(_, i, a)| (*i, *a) == (id, addr)) {
+            match self.policy {
+                DuplicatePolicy::Panic => {
+                    panic!(
+                        "Duplicated request {} to {:#x} within {} ticks",
+                        id, addr, self.window
+                    )
+                }
+                DuplicatePolicy::Drop => {
+                    self.duplicates += 1;
+                    return;
+                }
+            }
+        }
+        self.seen.push_back((self.tick, id, addr));
+        self.inner.push(req);
+    }
+
+    fn pop(&mut self) -> Option<Self::Resp> {
+        self.inner.pop()
+    }
+
+    fn cancel(&mut self, id: usize) -> bool {
+        self.inner.cancel(id)
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        self.inner.probe(addr)
+    }
+
+    fn kind_name(&self) -> &'static str {
+        self.inner.kind_name()
+    }
+
+    fn write_dot(&self, node: &str, out: &mut String) {
+        self.inner.write_dot(node, out);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TimedOut {
+    pub id: usize,
+}
+
+/// Answers requests that got no response from the inner drain within `timeout` ticks with
+/// `Err(TimedOut)`, cancelling them in the inner drain. Responses arriving after that are dropped.
+pub struct WithTimeout<T: Drain> {
+    inner: T,
+    timeout: usize,
+
+    tick: usize,
+    // id -> tick pushed
+    pushed: BTreeMap<usize, usize>,
+    ready: VecDeque<Result<T::Resp, TimedOut>>,
+}
+
+impl<T: Drain> WithTimeout<T>
+where
+    T::Resp: Identified,
+{
+    pub fn new(inner: T, timeout: usize) -> WithTimeout<T> {
+        WithTimeout {
+            inner,
+            timeout,
+            tick: 0,
+            pushed: BTreeMap::new(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    fn collect(&mut self) {
+        while let Some(resp) = self.inner.pop() {
+            let pending = if resp.is_last() {
+                self.pushed.remove(&resp.id()).is_some()
+            } else {
+                self.pushed.contains_key(&resp.id())
+            };
+            if pending {
+                self.ready.push_back(Ok(resp));
+            }
+        }
+    }
+}
+
+impl<T: Drain> Drain for WithTimeout<T>
+where
+    T::Req: Identified,
+    T::Resp: Identified,
+{
+    type Req = T::Req;
+    type Resp = Result<T::Resp, TimedOut>;
+
+    fn tick(&mut self) {
+        self.tick_begin();
+        self.tick_end();
+    }
+
+    fn tick_begin(&mut self) {
+        self.tick += 1;
+        self.inner.tick_begin();
+    }
+
+    fn tick_end(&mut self) {
+        self.inner.tick_end();
+        self.collect();
+        let now = self.tick;
+        let timeout = self.timeout;
+        let expired: Vec<usize> = self
+            .pushed
+            .iter()
+            .filter(|(_, t)| now - **t >= timeout)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            self.pushed.remove(&id);
+            self.inner.cancel(id);
+            self.ready.push_back(Err(TimedOut { id }));
+        }
+    }
+
+    fn push(&mut self, req: Self::Req) {
+        if self.pushed.insert(req.id(), self.tick).is_some() {
+            panic!("Duplicated inflight request id: {}", req.id());
+        }
+        self.inner.push(req);
+    }
+
+    fn pop(&mut self) -> Option<Self::Resp> {
+        self.collect();
+        self.ready.pop_front()
+    }
+
+    fn cancel(&mut self, id: usize) -> bool {
+        if let Some(idx) = self.ready.iter().position(|resp| match resp {
+            Ok(resp) => resp.id() == id,
+            Err(err) => err.id == id,
+        }) {
+            self.ready.remove(idx);
+            return true;
+        }
+        let cancelled = self.inner.cancel(id);
+        if cancelled {
+            self.pushed.remove(&id);
+        }
+        cancelled
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        self.inner.probe(addr)
+    }
+
+    fn kind_name(&self) -> &'static str {
+        self.inner.kind_name()
+    }
+
+    fn write_dot(&self, node: &str, out: &mut String) {
+        self.inner.write_dot(node, out);
+    }
+}
+
+#[test]
+fn test_tagged_context() {
+    use crate::crossbar::Crossbar;
+    use crate::mem::*;
+    let mem_a: Mem<_, 8> = Mem::new(NoDelay::default());
+    let mem_b: Mem<_, 8> = Mem::new(NoDelay::default());
+    let crossbar = Crossbar::new()
+        .with(0x80000000, 0x80002000, Box::new(Delay::new(mem_a, 6, 5)))
+        .with(0x80002000, 0x80004000, Box::new(Delay::new(mem_b, 1, 1)));
+    let mut tagged = Tagged::new(crossbar);
+
+    for id in 0..4 {
+        let ctx = format!("thread-{}", id);
+        tagged.push((MemReq::read(id, 0x80000000 + 0x1000 * id as u64), ctx));
+    }
+
+    let mut popped = 0;
+    while popped < 4 {
+        tagged.tick();
+        while let Some((resp, ctx)) = tagged.pop() {
+            assert_eq!(ctx, format!("thread-{}", resp.id));
+            popped += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+fn completion_ticks<T: Drain<Req = crate::mem::MemReq<8>, Resp = crate::mem::MemResp<8>>>(
+    drain: &mut T,
+    n: usize,
+) -> Vec<usize> {
+    use crate::mem::MemReq;
+    for id in 0..n {
+        drain.push(MemReq::read(id, 0x80000000 + 8 * id as u64));
+    }
+
+    let mut done = vec![0; n];
+    let mut popped = 0;
+    let mut t = 0;
+    while popped < n {
+        drain.tick();
+        t += 1;
+        while let Some(resp) = drain.pop() {
+            done[resp.id] = t;
+            popped += 1;
+        }
+    }
+    done
+}
+
+#[test]
+fn test_issue_window() {
+    use crate::mem::*;
+    let mem: Mem<_, 8> = Mem::new(FixedDelay::new(10));
+    let mut window = IssueWindow::new(mem, 4, 1);
+    for id in 0..10 {
+        window.push(MemReq::read(id, 0x80000000 + 8 * id as u64));
+    }
+    assert_eq!(window.pending(), 9);
+
+    let mut popped = 0;
+    let mut max_outstanding = 0;
+    let mut ticks = 0;
+    while popped < 10 {
+        let before = window.pending();
+        window.tick();
+        ticks += 1;
+        while window.pop().is_some() {
+            popped += 1;
+        }
+        assert!(before - window.pending() <= 1);
+        assert!(window.outstanding() <= 4);
+        max_outstanding = max_outstanding.max(window.outstanding());
+    }
+    assert_eq!(max_outstanding, 4);
+    // Each batch of four waits for the previous one to complete, with issues one tick apart
+    assert_eq!(ticks, 30);
+}
+
+#[test]
+fn test_handshake() {
+    use crate::mem::*;
+    let read = |id| MemReq::read(id, 0x80000000 + 8 * id as u64);
+    let mem: Mem<_, 8> = Mem::new(FixedDelay::new(2));
+    let mut handshake = Handshake::new(mem);
+
+    handshake.push(read(0));
+    assert!(handshake.is_busy());
+    assert_eq!(handshake.try_push(read(1)).unwrap_err().id, 1);
+    handshake.push(read(1));
+    assert_eq!(handshake.pending(), 1);
+
+    // The second request isn't issued until the first response is consumed, however long that
+    // takes
+    for _ in 0..5 {
+        handshake.tick();
+    }
+    assert_eq!(handshake.pending(), 1);
+    assert_eq!(handshake.pop().unwrap().id, 0);
+    assert_eq!(handshake.pending(), 0);
+    assert!(handshake.pop().is_none());
+    for _ in 0..2 {
+        handshake.tick();
+    }
+    assert_eq!(handshake.pop().unwrap().id, 1);
+    assert!(!handshake.is_busy());
+    assert!(handshake.try_push(read(2)).is_ok());
+}
+
+#[test]
+fn test_response_credits() {
+    use crate::mem::*;
+    let mem: Mem<_, 8> = Mem::new(FixedDelay::new(1));
+    let mut credited = Credited::new(mem);
+    for id in 0..4 {
+        credited.push(MemReq::read(id, 0x80000000 + 8 * id as u64));
+    }
+    credited.grant_credits(2);
+    let mut popped = Vec::new();
+    for _ in 0..5 {
+        credited.tick();
+        while let Some(resp) = credited.pop() {
+            popped.push(resp.id);
+        }
+    }
+    assert_eq!(popped, [0, 1]);
+    assert_eq!(credited.credits(), 0);
+
+    credited.grant_credits(3);
+    while let Some(resp) = credited.pop() {
+        popped.push(resp.id);
+    }
+    assert_eq!(popped, [0, 1, 2, 3]);
+    assert_eq!(credited.credits(), 1);
+}
+
+#[test]
+fn test_dedup_window() {
+    use crate::mem::*;
+    let req = |id| MemReq::read(id, 0x80000000);
+    let mem: Mem<_, 8> = Mem::new(FixedDelay::new(1));
+    let mut guard = DedupGuard::new(mem, 4, DuplicatePolicy::Drop);
+    let mut done = Vec::new();
+    for t in 0..8 {
+        match t {
+            // Same id, different address
+            0 => guard.push(req(0)),
+            1 => guard.push(MemReq {
+                addr: 0x80000008,
+                ..req(0)
+            }),
+            3 => guard.push(req(0)),
+            4 => guard.push(req(0)),
+            _ => {}
+        }
+        guard.tick();
+        while let Some(resp) = guard.pop() {
+            done.push(resp.id);
+        }
+    }
+    assert_eq!(done, [0, 0, 0]);
+    assert_eq!(guard.duplicates(), 1);
+}
+
+#[test]
+#[should_panic(expected = "Duplicated request 3 to 0x80000000 within 4 ticks")]
+fn test_dedup_panic() {
+    use crate::mem::*;
+    let mem: Mem<_, 8> = Mem::new(FixedDelay::new(1));
+    let mut guard = DedupGuard::new(mem, 4, DuplicatePolicy::Panic);
+    for _ in 0..2 {
+        guard.push(MemReq::read(3, 0x80000000));
+        guard.tick();
+    }
+}
+
+#[test]
+fn test_pause_resume() {
+    use crate::mem::*;
+    let mem: Mem<_, 8> = Mem::new(FixedDelay::new(3));
+    let mut drain = Pausable::new(mem);
+    let req = |id| MemReq::read(id, 0x80000000 + 8 * id as u64);
+    drain.push(req(0));
+    drain.tick();
+    drain.pause();
+    drain.push(req(1));
+    for _ in 0..10 {
+        drain.tick();
+        assert!(drain.pop().is_none());
+    }
+    assert!(drain.is_paused());
+
+    // Request 0 still has 2 ticks to go, and request 1 starts on resume
+    drain.resume();
+    let mut done = Vec::new();
+    for t in 1..=4 {
+        drain.tick();
+        while let Some(resp) = drain.pop() {
+            done.push((t, resp.id));
+        }
+    }
+    assert_eq!(done, [(2, 0), (3, 1)]);
+}
+
+#[test]
+fn test_periodic_stall() {
+    use crate::mem::*;
+    let mem: Mem<_, 8> = Mem::new(FixedDelay::new(1));
+    let mut stall = PeriodicStall::new(mem, 10, 4);
+
+    let mut done = Vec::new();
+    for t in 1..=30 {
+        stall.push(MemReq::read(t, 0x80000000 + 8 * t as u64));
+        stall.tick();
+        let stalling = stall.is_stalling();
+        assert_eq!(stalling, (6..10).contains(&(t % 10)));
+        while let Some(resp) = stall.pop() {
+            assert!(!stalling);
+            done.push((t, resp.id));
+        }
+    }
+
+    // Everything held up by the stall completes right after it ends
+    let after_stall: Vec<_> = done
+        .iter()
+        .filter(|(t, _)| *t == 10)
+        .map(|(_, id)| *id)
+        .collect();
+    assert_eq!(after_stall, [6, 7, 8, 9, 10]);
+    assert!(done.iter().all(|(t, _)| !(6..10).contains(&(t % 10))));
+}
+
+#[test]
+fn test_response_cap() {
+    use crate::mem::*;
+    let delay = |policy| {
+        let mem: Mem<_, 8> = Mem::new(NoDelay::default());
+        let mut delay = Delay::new(mem, 0, 1).with_response_cap(2, policy);
+        for id in 0..4 {
+            delay.push(MemReq::read(id, 0x80000000 + 8 * id as u64));
+        }
+        for _ in 0..3 {
+            delay.tick();
+        }
+        delay
+    };
+    let pop_all = |delay: &mut Delay<Mem<NoDelay, 8>>| {
+        let mut ids = Vec::new();
+        while let Some(resp) = delay.pop() {
+            ids.push(resp.id);
+        }
+        ids
+    };
+
+    let mut dropping = delay(OverflowPolicy::DropOldest);
+    assert_eq!(pop_all(&mut dropping), [2, 3]);
+
+    // Responses beyond the cap wait in the inner drain until there's room again
+    let mut blocking = delay(OverflowPolicy::Block);
+    assert_eq!(pop_all(&mut blocking), [0, 1]);
+    blocking.tick();
+    assert_eq!(pop_all(&mut blocking), [2, 3]);
+}
+
+#[cfg(test)]
+struct Stuck;
+
+#[cfg(test)]
+impl Drain for Stuck {
+    type Req = MemReq<8>;
+    type Resp = MemResp<8>;
+
+    fn tick(&mut self) {}
+
+    fn push(&mut self, _req: MemReq<8>) {}
+
+    fn pop(&mut self) -> Option<MemResp<8>> {
+        None
+    }
+}
+
+#[test]
+fn test_with_timeout() {
+    use crate::crossbar::Crossbar;
+    use crate::mem::*;
+    let crossbar = Crossbar::new()
+        .with(
+            0x80000000,
+            0x80001000,
+            Box::new(Mem::<_, 8>::new(FixedDelay::new(2))),
+        )
+        .with(0x90000000, 0x90001000, Box::new(Stuck));
+    let mut drain = WithTimeout::new(crossbar, 5);
+    for (id, addr) in [(3, 0x90000000), (4, 0x80000000)] {
+        drain.push(MemReq::read(id, addr));
+    }
+
+    let mut done = Vec::new();
+    for t in 1..=10 {
+        drain.tick();
+        while let Some(resp) = drain.pop() {
+            done.push((t, resp.map(|resp| resp.id)));
+        }
+    }
+    assert_eq!(done, [(2, Ok(4)), (5, Err(TimedOut { id: 3 }))]);
+}
+
+#[test]
+fn test_delay_cancel() {
+    use crate::mem::*;
+    let req = |id| MemReq::read(id, 0x80000000 + 8 * id as u64);
+    let mem: Mem<_, 8> = Mem::new(FixedDelay::new(1));
+    let mut delay = Delay::new(mem, 5, 2);
+    delay.push(req(0));
+    delay.push(req(1));
+    delay.push(req(2));
+    // Request 0 still on the downlink
+    assert!(delay.cancel(0));
+    for _ in 0..4 {
+        delay.tick();
+    }
+    // Request 1 completed by the memory and waiting in the uplink
+    assert!(delay.cancel(1));
+    assert!(!delay.cancel(7));
+
+    let mut done = Vec::new();
+    for _ in 0..10 {
+        delay.tick();
+        while let Some(resp) = delay.pop() {
+            done.push(resp.id);
+        }
+    }
+    assert_eq!(done, [2]);
+}
+
+#[test]
+fn test_jitter_reproducible() {
+    use crate::mem::*;
+    let run = |seed| {
+        let mem: Mem<_, 8> = Mem::new(NoDelay::default());
+        let mut delay = Delay::new(mem, 2, 3).with_jitter(4, 4, seed);
+        completion_ticks(&mut delay, 32)
+    };
+    assert_eq!(run(42), run(42));
+    assert_ne!(run(42), run(43));
+}
+
+#[test]
+fn test_jitter_latency() {
+    use crate::mem::*;
+    let average = |jitter| {
+        let mem: Mem<_, 8> = Mem::new(NoDelay::default());
+        let mut delay = Delay::new(mem, 2, 3).with_jitter(jitter, jitter, 7);
+        let ticks = completion_ticks(&mut delay, 64);
+        ticks.iter().sum::<usize>() as f64 / ticks.len() as f64
+    };
+    let baseline = average(0);
+    let jittered = average(16);
+    assert!(jittered > baseline);
+}
+
+#[test]
+fn test_delay_latency() {
+    use crate::mem::*;
+    for (up, down) in [(0, 0), (2, 0), (0, 2), (3, 1)] {
+        let mem: Mem<_, 8> = Mem::new(NoDelay::default());
+        let mut delay = Delay::new(mem, up, down);
+        for _ in 0..3 {
+            delay.tick();
+        }
+        delay.push(MemReq::read(0, 0x80000000));
+
+        let mut latency = 0;
+        while delay.pop().is_none() {
+            delay.tick();
+            latency += 1;
+        }
+        assert_eq!(latency, up + down);
+    }
+}
+
+#[test]
+fn test_read_only_delay() {
+    use crate::mem::*;
+    let mem: Mem<_, 8> = Mem::new(NoDelay::default());
+    let mut delay = Delay::read_only(mem, 3, 2);
+    let mut latency = |id, wbe| {
+        delay.push(MemReq::write(id, 0x80000000, [wbe; 8], [0; 8]));
+        let mut latency = 0;
+        while delay.pop().is_none() {
+            delay.tick();
+            latency += 1;
+        }
+        latency
+    };
+    assert_eq!(latency(0, true), 0);
+    assert_eq!(latency(1, false), 5);
+    assert_eq!(latency(2, true), 0);
+}
+
+#[test]
+fn test_pipeline_builder() {
+    use crate::mem::*;
+    let mut built = Mem::<_, 8>::new(FixedDelay::new(2))
+        .cached(8, FillPolicy::AllocateOnReadMiss)
+        .delayed(1, 2);
+    let mut nested = Delay::new(
+        WriteThroughCache::new(
+            Mem::<_, 8>::new(FixedDelay::new(2)),
+            8,
+            FillPolicy::AllocateOnReadMiss,
+        ),
+        1,
+        2,
+    );
+
+    for _ in 0..2 {
+        assert_eq!(
+            completion_ticks(&mut built, 8),
+            completion_ticks(&mut nested, 8)
+        );
+    }
+    assert_eq!(built.inner.hits(), 8);
+}
+
+#[test]
+fn test_current_tick() {
+    use crate::mem::*;
+    let mem: Mem<_, 8> = Mem::new(NoDelay::default());
+    let mut delay = Delay::new(mem, 1, 1);
+    assert_eq!(delay.current_tick(), 0);
+    for t in 1..=5 {
+        delay.tick();
+        assert_eq!(delay.current_tick(), t);
+    }
+
+    let clock = Clock::new();
+    let mem_a: Mem<_, 8> = Mem::new(NoDelay::default());
+    let mem_b: Mem<_, 8> = Mem::new(NoDelay::default()).with_clock(clock.clone());
+    let mut a = Delay::new(mem_a, 1, 1).with_clock(clock.clone());
+    let mut b = Delay::new(mem_b, 2, 2).with_clock(clock.clone());
+    for _ in 0..3 {
+        clock.advance();
+        a.tick();
+        b.tick();
+    }
+    assert_eq!(clock.now(), 3);
+    assert_eq!(a.current_tick(), 3);
+    assert_eq!(b.current_tick(), 3);
+    assert_eq!(b.inner.current_tick(), 3);
 }
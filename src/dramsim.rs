@@ -0,0 +1,525 @@
+use std::{
+    cell::RefCell,
+    collections::{btree_map::Entry, BTreeMap, HashMap, VecDeque},
+    ffi::CString,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    rc::Rc,
+};
+
+use crate::mem::DelaySimulator;
+#[cfg(test)]
+use crate::{
+    drain::Drain,
+    mem::{test_simple, Chain, FixedDelay, Mem, MemReq, SplitRw},
+};
+
+struct AddrProgress {
+    sent: u64,
+    recv: u64,
+    is_write: bool,
+    completions: Vec<usize>,
+}
+
+impl AddrProgress {
+    fn next_send(&self, base: u64, transfer: u64) -> u64 {
+        base + transfer * self.sent
+    }
+}
+
+struct Progress<const WIDTH: usize> {
+    transfer_width: u64,
+    tick: usize,
+
+    // Ordered, so that pending sub-transfers are offered in the same order on every run
+    progress: BTreeMap<u64, AddrProgress>,
+    done: VecDeque<u64>,
+    // Only filled once `record_transfer_ticks` is called
+    breakdowns: Option<HashMap<u64, Vec<usize>>>,
+}
+
+impl<const WIDTH: usize> Default for Progress<WIDTH> {
+    fn default() -> Self {
+        Progress {
+            transfer_width: WIDTH as u64,
+            tick: 0,
+            progress: BTreeMap::new(),
+            done: VecDeque::new(),
+            breakdowns: None,
+        }
+    }
+}
+
+impl<const WIDTH: usize> Progress<WIDTH> {
+    const MASK: u64 = {
+        assert!(WIDTH.is_power_of_two(), "WIDTH must be a power of two");
+        WIDTH as u64 - 1
+    };
+
+    fn align(addr: u64) -> u64 {
+        addr & !Self::MASK
+    }
+
+    fn add(&mut self, addr: u64, is_write: bool) {
+        assert_eq!(addr & Self::MASK, 0);
+        assert!(self
+            .progress
+            .insert(
+                addr,
+                AddrProgress {
+                    sent: 0,
+                    recv: 0,
+                    is_write,
+                    completions: Vec::new(),
+                }
+            )
+            .is_none());
+    }
+
+    fn step(&mut self, addr: u64) {
+        let aligned = Self::align(addr);
+        let multiplicity = self.multiplicity();
+        match self.progress.entry(aligned) {
+            Entry::Occupied(mut o) => {
+                let prog = o.get_mut();
+                assert_eq!(aligned + prog.recv * self.transfer_width, addr); // Sequential response
+                prog.completions.push(self.tick);
+                if prog.recv == multiplicity - 1 {
+                    let prog = o.remove();
+                    if let Some(breakdowns) = self.breakdowns.as_mut() {
+                        breakdowns.insert(aligned, prog.completions);
+                    }
+                    self.done.push_back(aligned);
+                } else {
+                    prog.recv += 1;
+                }
+            }
+            Entry::Vacant(_) => panic!("Unexpected memory response"),
+        }
+    }
+
+    fn pop(&mut self) -> Option<u64> {
+        self.done.pop_front()
+    }
+
+    fn multiplicity(&self) -> u64 {
+        WIDTH as u64 / self.transfer_width
+    }
+}
+
+/// Each memory tick, pending sub-transfers are offered to dramsim3 in ascending address order,
+/// so identical runs issue them identically.
+///
+/// `WIDTH` must be a power of two:
+///
+/// ```compile_fail
+/// use uncore_sim::mem::DRAMSim;
+/// let _: DRAMSim<24> = DRAMSim::new("config.ini", "out");
+/// ```
+pub struct DRAMSim<const WIDTH: usize> {
+    sys: dramsim3::MemorySystem,
+    prog: Rc<RefCell<Progress<WIDTH>>>,
+    ratio: usize,
+    error: Rc<RefCell<Option<String>>>,
+}
+
+impl<const WIDTH: usize> DRAMSim<WIDTH> {
+    pub fn new<Config: AsRef<Path>, Dir: AsRef<Path>>(config: Config, dir: Dir) -> Self {
+        Self::new_with_ratio(config, dir, 1)
+    }
+
+    /// Ticks dramsim3 `mem_ticks_per_sim_tick` times per outer tick, for memory controllers
+    /// clocked faster than the rest of the simulation
+    pub fn new_with_ratio<Config: AsRef<Path>, Dir: AsRef<Path>>(
+        config: Config,
+        dir: Dir,
+        mem_ticks_per_sim_tick: usize,
+    ) -> Self {
+        assert!(
+            mem_ticks_per_sim_tick > 0,
+            "Memory ticks per simulation tick must be positive"
+        );
+        let _ = Progress::<WIDTH>::MASK;
+        let prog: Rc<RefCell<Progress<WIDTH>>> = Default::default();
+        let prog_cb = prog.clone();
+        let error: Rc<RefCell<Option<String>>> = Default::default();
+        let error_cb = error.clone();
+
+        let config_cstr = CString::new(config.as_ref().as_os_str().as_encoded_bytes()).unwrap();
+        let dir_cstr = CString::new(dir.as_ref().as_os_str().as_encoded_bytes()).unwrap();
+        let sys = dramsim3::MemorySystem::new(&config_cstr, &dir_cstr, move |addr, _is_write| {
+            // Unwinding through dramsim3's C++ frames is UB, so record the panic and report it
+            // once control is back on the Rust side
+            let result = panic::catch_unwind(AssertUnwindSafe(|| prog_cb.borrow_mut().step(addr)));
+            if let Err(payload) = result {
+                let msg = payload
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "Unknown panic in dramsim3 callback".to_string());
+                error_cb.borrow_mut().get_or_insert(msg);
+            }
+        });
+
+        let transfer_width = sys.bus_bits() * sys.burst_length() / 8;
+        prog.borrow_mut().transfer_width = transfer_width as u64;
+
+        DRAMSim {
+            sys,
+            prog,
+            ratio: mem_ticks_per_sim_tick,
+            error,
+        }
+    }
+
+    /// Like `DelaySimulator::tick`, but returns errors raised inside the dramsim3 completion
+    /// callback instead of panicking
+    pub fn try_tick(&mut self) -> Result<(), String> {
+        self.prog.borrow_mut().tick += 1;
+        for _ in 0..self.ratio {
+            self.mem_tick();
+            if let Some(err) = self.error.borrow_mut().take() {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sub-transfers handed to dramsim3 that haven't completed yet
+    pub fn inflight_transfers(&self) -> usize {
+        self.prog
+            .borrow()
+            .progress
+            .values()
+            .map(|prog| (prog.sent - prog.recv) as usize)
+            .sum()
+    }
+
+    fn mem_tick(&mut self) {
+        self.sys.tick();
+        let mut prog = self.prog.borrow_mut();
+        let multiplicity = prog.multiplicity();
+        let transfer_width = prog.transfer_width;
+        for (aligned, addr_prog) in prog.progress.iter_mut() {
+            if addr_prog.sent != multiplicity {
+                let next_addr = addr_prog.next_send(*aligned, transfer_width);
+                if self.sys.can_add(next_addr, addr_prog.is_write) {
+                    self.sys.add(next_addr, addr_prog.is_write);
+                    addr_prog.sent += 1;
+                }
+            }
+        }
+    }
+}
+
+impl<const WIDTH: usize> DelaySimulator for DRAMSim<WIDTH> {
+    fn tick(&mut self) {
+        if let Err(err) = self.try_tick() {
+            panic!("{}", err);
+        }
+    }
+
+    fn push(&mut self, addr: u64, is_write: bool) {
+        self.prog.borrow_mut().add(addr, is_write);
+    }
+
+    fn pop(&mut self) -> Option<u64> {
+        self.prog.borrow_mut().pop()
+    }
+
+    fn transfer_ticks(&mut self, addr: u64) -> Option<Vec<usize>> {
+        self.prog.borrow_mut().breakdowns.as_mut()?.remove(&addr)
+    }
+
+    fn record_transfer_ticks(&mut self) {
+        self.prog
+            .borrow_mut()
+            .breakdowns
+            .get_or_insert_with(HashMap::new);
+    }
+}
+
+#[test]
+#[should_panic(expected = "Memory ticks per simulation tick must be positive")]
+fn test_zero_ratio() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let _: DRAMSim<256> = DRAMSim::new_with_ratio("config.ini", &tmpdir, 0);
+}
+
+#[test]
+fn test_simple_dram() {
+    let mut cfg = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    cfg.push("resources/test/DDR4_8Gb_x16_3200.ini");
+
+    let tmpdir = tempfile::tempdir().unwrap();
+
+    let dramsim: DRAMSim<256> = DRAMSim::new(cfg, &tmpdir);
+    let mem: Mem<_, 256> = Mem::new(dramsim);
+    test_simple(mem, 20);
+}
+
+#[test]
+fn test_transfer_breakdown() {
+    let mut cfg = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    cfg.push("resources/test/DDR4_8Gb_x16_3200.ini");
+    let tmpdir = tempfile::tempdir().unwrap();
+
+    // 64-byte transfers, so each 256-byte request takes four
+    let dramsim: DRAMSim<256> = DRAMSim::new(cfg, &tmpdir);
+    let mut mem: Mem<_, 256> = Mem::new(dramsim).with_transfer_breakdown();
+    mem.push(MemReq::read(3, 0x80004000));
+    let mut ticks = 0;
+    while mem.pop().is_none() {
+        mem.tick();
+        ticks += 1;
+    }
+
+    let breakdown = mem.transfer_breakdown(3).unwrap();
+    assert_eq!(breakdown.len(), 4);
+    assert!(breakdown.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(breakdown[3], ticks);
+    // Taken out, so long runs don't accumulate them
+    assert!(mem.transfer_breakdown(3).is_none());
+    assert!(mem
+        .sim()
+        .prog
+        .borrow()
+        .breakdowns
+        .as_ref()
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_chained_transfer_breakdown() {
+    let mut cfg = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    cfg.push("resources/test/DDR4_8Gb_x16_3200.ini");
+    let tmpdir = tempfile::tempdir().unwrap();
+
+    let dramsim: DRAMSim<256> = DRAMSim::new(cfg, &tmpdir);
+    let sim = Chain::new(dramsim, FixedDelay::new(3));
+    let mut mem: Mem<_, 256> = Mem::new(sim).with_transfer_breakdown();
+    mem.push(MemReq::read(0, 0x80004000));
+    while mem.pop().is_none() {
+        mem.tick();
+    }
+    assert_eq!(mem.transfer_breakdown(0).unwrap().len(), 4);
+}
+
+#[test]
+fn test_beat_responses_through_wrappers() {
+    use crate::arbiter::Arbiter;
+    use crate::check::InflightGuard;
+    use crate::scrub::Scrubber;
+    use crate::width::{SubOrder, WidthAdapter};
+
+    let mut cfg = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    cfg.push("resources/test/DDR4_8Gb_x16_3200.ini");
+    let mem = || {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let dramsim: DRAMSim<256> = DRAMSim::new(&cfg, &tmpdir);
+        Mem::<_, 256>::new(dramsim).with_beat_responses()
+    };
+
+    // Beats pass through, and requests retire on the last one
+    let guarded = InflightGuard::new(mem(), 16);
+    let scrubbed = Scrubber::new(guarded, 0x90000000, 0x90001000, 50);
+    let mut arbiter = Arbiter::new(scrubbed, 2);
+    arbiter.push(0, MemReq::read(0, 0x80004000));
+    arbiter.push(1, MemReq::read(1, 0x80008000));
+    let mut beats = [Vec::new(), Vec::new()];
+    for _ in 0..500 {
+        arbiter.tick();
+        for (port, beats) in beats.iter_mut().enumerate() {
+            while let Some(resp) = arbiter.pop(port) {
+                assert_eq!(resp.id, port);
+                beats.push((resp.beat, resp.last));
+            }
+        }
+    }
+    for beats in beats {
+        assert_eq!(beats, [(0, false), (1, false), (2, false), (3, true)]);
+    }
+
+    // Collapsed into a single wide response
+    let mut wide: WidthAdapter<_, 512, 256> = WidthAdapter::new(mem(), SubOrder::Ascending);
+    wide.push(MemReq::read(5, 0x80004000));
+    let mut resps = Vec::new();
+    for _ in 0..500 {
+        wide.tick();
+        while let Some(resp) = wide.pop() {
+            resps.push((resp.id, resp.last));
+        }
+    }
+    assert_eq!(resps, [(5, true)]);
+}
+
+#[test]
+fn test_split_rw_transfer_breakdown() {
+    let mut cfg = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    cfg.push("resources/test/DDR4_8Gb_x16_3200.ini");
+    let (read_dir, write_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+
+    let reads: DRAMSim<256> = DRAMSim::new(&cfg, &read_dir);
+    let writes: DRAMSim<256> = DRAMSim::new(&cfg, &write_dir);
+    let sim = SplitRw::new(reads, writes);
+    let mut mem: Mem<_, 256> = Mem::new(sim).with_transfer_breakdown();
+    mem.push(MemReq::write(0, 0x80004000, [true; 256], [1; 256]));
+    mem.push(MemReq::read(1, 0x80008000));
+    let mut done = 0;
+    while done < 2 {
+        mem.tick();
+        while let Some(resp) = mem.pop() {
+            assert_eq!(mem.transfer_breakdown(resp.id).unwrap().len(), 4);
+            done += 1;
+        }
+    }
+}
+
+#[test]
+fn test_beat_responses() {
+    let mut cfg = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    cfg.push("resources/test/DDR4_8Gb_x16_3200.ini");
+    let tmpdir = tempfile::tempdir().unwrap();
+
+    let dramsim: DRAMSim<256> = DRAMSim::new(cfg, &tmpdir);
+    let mut mem: Mem<_, 256> = Mem::new(dramsim).with_beat_responses();
+    let mut wdata = [0; 256];
+    wdata[0x80] = 5;
+    test_write(&mut mem, 0x80004000, wdata);
+    mem.push(MemReq::read(3, 0x80004000));
+    let mut beats = Vec::new();
+    while beats.len() < 4 {
+        mem.tick();
+        while let Some(resp) = mem.pop() {
+            assert_eq!(resp.id, 3);
+            assert_eq!(resp.rdata, wdata);
+            beats.push((resp.beat, resp.last));
+        }
+    }
+    assert_eq!(beats, [(0, false), (1, false), (2, false), (3, true)]);
+    for _ in 0..100 {
+        mem.tick();
+        assert!(mem.pop().is_none());
+    }
+}
+
+#[cfg(test)]
+fn test_write<D: DelaySimulator>(mem: &mut Mem<D, 256>, addr: u64, wdata: [u8; 256]) {
+    mem.push(MemReq::write(0, addr, [true; 256], wdata));
+    let mut beats = 0;
+    while beats < 4 {
+        mem.tick();
+        while mem.pop().is_some() {
+            beats += 1;
+        }
+    }
+}
+
+#[test]
+fn test_inflight_transfers() {
+    let mut cfg = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    cfg.push("resources/test/DDR4_8Gb_x16_3200.ini");
+    let tmpdir = tempfile::tempdir().unwrap();
+
+    let dramsim: DRAMSim<256> = DRAMSim::new(cfg, &tmpdir);
+    let mut mem: Mem<_, 256> = Mem::new(dramsim);
+    mem.push(MemReq::read(0, 0x80004000));
+    assert_eq!(mem.sim().inflight_transfers(), 0);
+    let mut counts = Vec::new();
+    while mem.pop().is_none() {
+        mem.tick();
+        counts.push(mem.sim().inflight_transfers());
+    }
+    assert_eq!(counts.last(), Some(&0));
+    // All four transfers get issued before the first one returns
+    assert_eq!(counts.iter().max(), Some(&4));
+    assert!(counts.iter().any(|n| (1..4).contains(n)));
+}
+
+#[test]
+fn test_dram_deterministic_issue() {
+    let mut cfg = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    cfg.push("resources/test/DDR4_8Gb_x16_3200.ini");
+
+    // Enough requests at once that dramsim3 has to turn some sub-transfers away
+    let run = || {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let dramsim: DRAMSim<256> = DRAMSim::new(&cfg, &tmpdir);
+        let mut mem: Mem<_, 256> = Mem::new(dramsim).with_transfer_breakdown();
+        for id in 0..64 {
+            mem.push(MemReq::write(
+                id,
+                0x80000000 + 0x2300 * id as u64,
+                [id % 3 == 0; 256],
+                [0; 256],
+            ));
+        }
+        let mut order = Vec::new();
+        while order.len() < 64 {
+            mem.tick();
+            while let Some(resp) = mem.pop() {
+                order.push((resp.id, mem.transfer_breakdown(resp.id).unwrap()));
+            }
+        }
+        order
+    };
+    assert_eq!(run(), run());
+}
+
+#[test]
+fn test_alignment_mask() {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    let mut rng = StdRng::seed_from_u64(0);
+    for _ in 0..100000 {
+        let addr: u64 = rng.gen();
+        assert_eq!(Progress::<64>::align(addr), addr - addr % 64);
+        assert_eq!(Progress::<256>::align(addr), addr - addr % 256);
+    }
+}
+
+#[test]
+fn test_dram_clock_ratio() {
+    let mut cfg = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    cfg.push("resources/test/DDR4_8Gb_x16_3200.ini");
+
+    let latency = |ratio| {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let dramsim: DRAMSim<256> = DRAMSim::new_with_ratio(&cfg, &tmpdir, ratio);
+        let mut mem: Mem<_, 256> = Mem::new(dramsim);
+        mem.push(MemReq::read(0, 0x80004000));
+        let mut ticks: usize = 0;
+        while mem.pop().is_none() {
+            mem.tick();
+            ticks += 1;
+        }
+        ticks
+    };
+
+    let single = latency(1);
+    let double = latency(2);
+    assert!(double < single);
+    assert!(double <= single.div_ceil(2) + 1);
+}
+
+#[test]
+fn test_dram_callback_error() {
+    let mut cfg = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    cfg.push("resources/test/DDR4_8Gb_x16_3200.ini");
+    let tmpdir = tempfile::tempdir().unwrap();
+
+    let mut dramsim: DRAMSim<256> = DRAMSim::new(cfg, &tmpdir);
+    dramsim.push(0x80004000, false);
+    dramsim.try_tick().unwrap();
+    // Forget about the request, so its sub-transfer responses are unexpected
+    dramsim.prog.borrow_mut().progress.clear();
+
+    let mut result = Ok(());
+    for _ in 0..100 {
+        result = dramsim.try_tick();
+        if result.is_err() {
+            break;
+        }
+    }
+    assert_eq!(result, Err("Unexpected memory response".to_string()));
+}
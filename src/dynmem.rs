@@ -0,0 +1,182 @@
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+
+use crate::drain::{Access, Drain, Identified, ProbeResult};
+use crate::mem::DelaySimulator;
+
+/// Like `MemReq`, but with a width only known at runtime
+#[derive(Clone, Debug)]
+pub struct DynMemReq {
+    pub id: usize,
+    pub addr: u64,
+    pub wbe: Vec<bool>,
+    pub wdata: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DynMemResp {
+    pub id: usize,
+    pub rdata: Vec<u8>,
+}
+
+impl crate::crossbar::Routable<u64> for DynMemReq {
+    fn addr(&self) -> u64 {
+        self.addr
+    }
+}
+
+impl crate::crossbar::Relocatable<u64> for DynMemReq {
+    fn set_addr(&mut self, addr: u64) {
+        self.addr = addr;
+    }
+}
+
+impl Identified for DynMemReq {
+    fn id(&self) -> usize {
+        self.id
+    }
+}
+
+impl Identified for DynMemResp {
+    fn id(&self) -> usize {
+        self.id
+    }
+}
+
+impl Access for DynMemReq {
+    fn is_write(&self) -> bool {
+        self.wbe.contains(&true)
+    }
+}
+
+/// Backing memory for `DynMemReq`s, panicking on requests whose `wbe` or `wdata` length doesn't
+/// match `width`
+pub struct DynMem<D: DelaySimulator> {
+    sim: D,
+    width: usize,
+    content: BTreeMap<u64, Vec<u8>>,
+    inflights: BTreeMap<u64, usize>,
+}
+
+impl<D: DelaySimulator> DynMem<D> {
+    pub fn new(sim: D, width: usize) -> Self {
+        assert!(width > 0, "width must be positive");
+        DynMem {
+            sim,
+            width,
+            content: BTreeMap::new(),
+            inflights: BTreeMap::new(),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+}
+
+impl<D: DelaySimulator> Drain for DynMem<D> {
+    type Req = DynMemReq;
+    type Resp = DynMemResp;
+
+    fn tick(&mut self) {
+        self.sim.tick();
+    }
+
+    fn push(&mut self, req: DynMemReq) {
+        if req.wbe.len() != self.width || req.wdata.len() != self.width {
+            panic!(
+                "Request {} has {} byte-enables and {} data bytes, expected width {}",
+                req.id,
+                req.wbe.len(),
+                req.wdata.len(),
+                self.width
+            );
+        }
+        if self.inflights.insert(req.addr, req.id).is_some() {
+            panic!("Duplicated inflight memory requests");
+        }
+        self.sim.push(req.addr, req.is_write());
+        let chunk = self
+            .content
+            .entry(req.addr)
+            .or_insert_with(|| vec![0; self.width]);
+        for (c, (w, be)) in chunk.iter_mut().zip(req.wdata.iter().zip(req.wbe.iter())) {
+            if *be {
+                *c = *w;
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<DynMemResp> {
+        let addr = self.sim.pop()?;
+        let id = self
+            .inflights
+            .remove(&addr)
+            .expect("Unexpected memory response");
+        Some(DynMemResp {
+            id,
+            rdata: self.content[&addr].clone(),
+        })
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        ProbeResult::Present {
+            data: self
+                .content
+                .get(&addr)
+                .cloned()
+                .unwrap_or_else(|| vec![0; self.width]),
+            inflight: self.inflights.contains_key(&addr) as usize,
+        }
+    }
+}
+
+#[test]
+fn test_dyn_mem_roundtrip() {
+    use crate::mem::FixedDelay;
+
+    let width = "16".parse().unwrap();
+    let mut mem = DynMem::new(FixedDelay::new(2), width);
+    let mut run = |req: DynMemReq| {
+        mem.push(req);
+        loop {
+            mem.tick();
+            if let Some(resp) = mem.pop() {
+                break resp;
+            }
+        }
+    };
+
+    let wdata: Vec<u8> = (0..16).collect();
+    let mut wbe = vec![true; 16];
+    wbe[3] = false;
+    run(DynMemReq {
+        id: 0,
+        addr: 0x80000010,
+        wbe,
+        wdata: wdata.clone(),
+    });
+    let resp = run(DynMemReq {
+        id: 1,
+        addr: 0x80000010,
+        wbe: vec![false; 16],
+        wdata: vec![0; 16],
+    });
+    assert_eq!(resp.id, 1);
+    let mut expected = wdata;
+    expected[3] = 0;
+    assert_eq!(resp.rdata, expected);
+}
+
+#[test]
+#[should_panic(expected = "Request 0 has 8 byte-enables and 8 data bytes, expected width 16")]
+fn test_dyn_mem_width_mismatch() {
+    use crate::mem::NoDelay;
+
+    let mut mem = DynMem::new(NoDelay::default(), 16);
+    mem.push(DynMemReq {
+        id: 0,
+        addr: 0x80000000,
+        wbe: vec![false; 8],
+        wdata: vec![0; 8],
+    });
+}
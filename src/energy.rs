@@ -0,0 +1,112 @@
+use alloc::string::String;
+
+use crate::drain::{Access, Drain, ProbeResult};
+
+pub struct EnergyModel<D: Drain> {
+    inner: D,
+
+    read_energy: f64,
+    write_energy: f64,
+    idle_energy: f64,
+
+    energy: f64,
+}
+
+impl<D: Drain> EnergyModel<D> {
+    /// All costs are in joules: per read, per write, and leakage per tick
+    pub fn new(inner: D, read_energy: f64, write_energy: f64, idle_energy: f64) -> Self {
+        EnergyModel {
+            inner,
+            read_energy,
+            write_energy,
+            idle_energy,
+            energy: 0.0,
+        }
+    }
+
+    pub fn energy_joules(&self) -> f64 {
+        self.energy
+    }
+}
+
+impl<D: Drain> Drain for EnergyModel<D>
+where
+    D::Req: Access,
+{
+    type Req = D::Req;
+    type Resp = D::Resp;
+
+    fn tick(&mut self) {
+        self.energy += self.idle_energy;
+        self.inner.tick();
+    }
+
+    fn tick_begin(&mut self) {
+        self.energy += self.idle_energy;
+        self.inner.tick_begin();
+    }
+
+    fn tick_end(&mut self) {
+        self.inner.tick_end();
+    }
+
+    fn push(&mut self, req: Self::Req) {
+        self.energy += if req.is_write() {
+            self.write_energy
+        } else {
+            self.read_energy
+        };
+        self.inner.push(req);
+    }
+
+    fn pop(&mut self) -> Option<Self::Resp> {
+        self.inner.pop()
+    }
+
+    fn cancel(&mut self, id: usize) -> bool {
+        self.inner.cancel(id)
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        self.inner.probe(addr)
+    }
+
+    fn kind_name(&self) -> &'static str {
+        self.inner.kind_name()
+    }
+
+    fn write_dot(&self, node: &str, out: &mut String) {
+        self.inner.write_dot(node, out);
+    }
+}
+
+#[test]
+fn test_energy_accounting() {
+    use crate::mem::*;
+    let mem: Mem<_, 8> = Mem::new(NoDelay::default());
+    let mut model = EnergyModel::new(mem, 2e-9, 5e-9, 1e-12);
+
+    for i in 0..3 {
+        model.push(MemReq::write(
+            i,
+            0x80000000 + 8 * i as u64,
+            [true; 8],
+            [i as u8; 8],
+        ));
+    }
+    for i in 0..2 {
+        model.push(MemReq::read(3 + i, 0x80001000 + 8 * i as u64));
+    }
+
+    let mut popped = 0;
+    for _ in 0..10 {
+        model.tick();
+        while model.pop().is_some() {
+            popped += 1;
+        }
+    }
+    assert_eq!(popped, 5);
+
+    let expected = 3.0 * 5e-9 + 2.0 * 2e-9 + 10.0 * 1e-12;
+    assert!((model.energy_joules() - expected).abs() < 1e-18);
+}
@@ -1,3 +1,23 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+pub mod arbiter;
+pub mod cache;
+pub mod check;
 pub mod crossbar;
 pub mod drain;
+#[cfg(feature = "std")]
+pub mod dramsim;
+pub mod dynmem;
+pub mod energy;
 pub mod mem;
+#[cfg(all(feature = "mmap", unix))]
+pub mod mmap;
+pub mod presets;
+pub mod reservation;
+pub mod scrub;
+pub mod testutil;
+#[cfg(feature = "std")]
+pub mod trace;
+pub mod width;
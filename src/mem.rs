@@ -1,35 +1,146 @@
-use std::{
-    cell::RefCell,
-    collections::{HashMap, VecDeque},
-    ffi::CString,
-    path::Path,
-    rc::Rc,
+use alloc::{
+    boxed::Box,
+    collections::{btree_map::Entry, BTreeMap, BTreeSet, VecDeque},
+    vec::Vec,
 };
 
-use crate::drain::Drain;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 
+use crate::drain::{
+    make_room, overflow_blocks, Access, Clock, Drain, Identified, OverflowPolicy, ProbeResult,
+};
+#[cfg(feature = "std")]
+pub use crate::dramsim::DRAMSim;
+
+#[derive(Clone, Debug)]
 pub struct MemReq<const WIDTH: usize> {
     pub id: usize,
     pub addr: u64,
     pub wbe: [bool; WIDTH],
     pub wdata: [u8; WIDTH],
+    /// Read byte-enables. Only enabled bytes of `rdata` are guaranteed to be valid, the rest may
+    /// be zero. `None` reads the whole chunk.
+    pub rbe: Option<[bool; WIDTH]>,
+    /// Bytes transferred, from the start of the chunk. `rdata` is zero past it, and simulators
+    /// may use it for timing. `WIDTH` for a full access.
+    pub size: usize,
+    /// Requester issuing the access, carried over to its response. 0 unless set.
+    pub src: u16,
 }
 
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct MemResp<const WIDTH: usize> {
     pub id: usize,
     pub rdata: [u8; WIDTH],
+    /// Sub-transfer this response reports, see `Mem::with_beat_responses`. 0 otherwise.
+    pub beat: usize,
+    /// False for all but the final beat with `Mem::with_beat_responses`, so wrappers tracking
+    /// requests know when one is done. True otherwise.
+    pub last: bool,
+    /// `src` of the request this responds to
+    pub src: u16,
+}
+
+impl<const WIDTH: usize> MemReq<WIDTH> {
+    /// Full-chunk read from requester 0
+    pub fn read(id: usize, addr: u64) -> Self {
+        MemReq {
+            id,
+            addr,
+            wbe: [false; WIDTH],
+            wdata: [0; WIDTH],
+            rbe: None,
+            size: WIDTH,
+            src: 0,
+        }
+    }
+
+    /// Writes the bytes of `wdata` enabled in `wbe`, from requester 0
+    pub fn write(id: usize, addr: u64, wbe: [bool; WIDTH], wdata: [u8; WIDTH]) -> Self {
+        MemReq {
+            wbe,
+            wdata,
+            ..MemReq::read(id, addr)
+        }
+    }
 }
 
 impl<const WIDTH: usize> crate::crossbar::Routable<u64> for MemReq<WIDTH> {
     fn addr(&self) -> u64 {
         self.addr
     }
+
+    fn last_addr(&self) -> u64 {
+        self.addr + WIDTH as u64 - 1
+    }
+}
+
+impl<const WIDTH: usize> crate::crossbar::Relocatable<u64> for MemReq<WIDTH> {
+    fn set_addr(&mut self, addr: u64) {
+        self.addr = addr;
+    }
+}
+
+impl<const WIDTH: usize> Identified for MemReq<WIDTH> {
+    fn id(&self) -> usize {
+        self.id
+    }
+}
+
+impl<const WIDTH: usize> Identified for MemResp<WIDTH> {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn is_last(&self) -> bool {
+        self.last
+    }
+}
+
+impl<const WIDTH: usize> Access for MemReq<WIDTH> {
+    fn is_write(&self) -> bool {
+        self.wbe.contains(&true)
+    }
 }
 
 pub trait DelaySimulator {
     fn tick(&mut self);
+
+    /// Equivalent to calling `tick` `n` times
+    fn tick_n(&mut self, n: usize) {
+        for _ in 0..n {
+            self.tick();
+        }
+    }
     fn push(&mut self, addr: u64, is_write: bool);
     fn pop(&mut self) -> Option<u64>;
+
+    /// Like `push`, for simulators whose timing depends on the number of bytes transferred
+    fn push_sized(&mut self, addr: u64, is_write: bool, _size: usize) {
+        self.push(addr, is_write);
+    }
+
+    /// Like `push_sized`, for simulators whose timing depends on the accessed data. `data` is the
+    /// chunk as it is once the request has taken effect.
+    fn push_with_data(&mut self, addr: u64, is_write: bool, size: usize, _data: &[u8]) {
+        self.push_sized(addr, is_write, size);
+    }
+
+    /// Like `push`, but returns false instead of accepting the request if the simulator is full
+    fn try_push(&mut self, addr: u64, is_write: bool) -> bool {
+        self.push(addr, is_write);
+        true
+    }
+
+    /// Ticks at which each sub-transfer of the request just popped at `addr` completed, for
+    /// simulators that split requests into multiple transfers
+    fn transfer_ticks(&mut self, _addr: u64) -> Option<Vec<usize>> {
+        None
+    }
+
+    /// Asks the simulator to keep what `transfer_ticks` reports, which it may otherwise skip
+    /// recording. Each entry is then kept until `transfer_ticks` takes it.
+    fn record_transfer_ticks(&mut self) {}
 }
 
 #[derive(Default)]
@@ -39,6 +150,7 @@ pub struct NoDelay {
 
 impl DelaySimulator for NoDelay {
     fn tick(&mut self) {}
+    fn tick_n(&mut self, _n: usize) {}
     fn push(&mut self, addr: u64, _is_write: bool) {
         self.queue.push_back(addr);
     }
@@ -48,244 +160,2763 @@ impl DelaySimulator for NoDelay {
     }
 }
 
-struct AddrProgress {
-    sent: u64,
-    recv: u64,
-    is_write: bool,
+pub struct FixedDelay {
+    latency: usize,
+    bytes_per_tick: Option<usize>,
+    tick: usize,
+    queue: VecDeque<(usize, u64)>,
 }
 
-impl AddrProgress {
-    fn next_send(&self, base: u64, transfer: u64) -> u64 {
-        base + transfer * self.sent
+impl FixedDelay {
+    pub fn new(latency: usize) -> Self {
+        FixedDelay {
+            latency,
+            bytes_per_tick: None,
+            tick: 0,
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// Sized accesses take an extra tick for every `bytes` bytes beyond the first `bytes`
+    pub fn with_bytes_per_tick(mut self, bytes: usize) -> Self {
+        assert!(bytes > 0, "bytes must be positive");
+        self.bytes_per_tick = Some(bytes);
+        self
     }
 }
 
-struct Progress<const WIDTH: usize> {
-    transfer_width: u64,
+impl DelaySimulator for FixedDelay {
+    fn tick(&mut self) {
+        self.tick += 1;
+    }
+
+    fn tick_n(&mut self, n: usize) {
+        self.tick += n;
+    }
+
+    fn push(&mut self, addr: u64, _is_write: bool) {
+        self.queue.push_back((self.tick + self.latency, addr));
+    }
+
+    fn push_sized(&mut self, addr: u64, is_write: bool, size: usize) {
+        let Some(bytes) = self.bytes_per_tick else {
+            return self.push(addr, is_write);
+        };
+        // Shorter accesses may overtake longer ones, but not ones to the same address
+        let earliest = self
+            .queue
+            .iter()
+            .filter(|(_, a)| *a == addr)
+            .map(|(t, _)| *t)
+            .max()
+            .unwrap_or(0);
+        let extra = size.div_ceil(bytes).saturating_sub(1);
+        let at = (self.tick + self.latency + extra).max(earliest);
+        let pos = self.queue.partition_point(|(t, _)| *t <= at);
+        self.queue.insert(pos, (at, addr));
+    }
+
+    fn pop(&mut self) -> Option<u64> {
+        if self.queue.front().is_some_and(|(t, _)| *t <= self.tick) {
+            self.queue.pop_front().map(|(_, addr)| addr)
+        } else {
+            None
+        }
+    }
+}
 
-    progress: HashMap<u64, AddrProgress>,
-    done: VecDeque<u64>,
+pub struct QueuedDelay {
+    depth: usize,
+    inner: FixedDelay,
+    occupied: usize,
 }
 
-impl<const WIDTH: usize> Default for Progress<WIDTH> {
-    fn default() -> Self {
-        Progress {
-            transfer_width: WIDTH as u64,
-            progress: HashMap::new(),
-            done: VecDeque::new(),
+impl QueuedDelay {
+    pub fn new(depth: usize, latency: usize) -> Self {
+        QueuedDelay {
+            depth,
+            inner: FixedDelay::new(latency),
+            occupied: 0,
         }
     }
 }
 
-impl<const WIDTH: usize> Progress<WIDTH> {
-    fn add(&mut self, addr: u64, is_write: bool) {
-        assert_eq!(addr % (WIDTH as u64), 0);
-        assert!(self
-            .progress
-            .insert(
-                addr,
-                AddrProgress {
-                    sent: 0,
-                    recv: 0,
-                    is_write,
-                }
-            )
-            .is_none());
-    }
-
-    fn step(&mut self, addr: u64) {
-        let aligned = addr - addr % WIDTH as u64;
-        let multiplicity = self.multiplicity();
-        match self.progress.entry(aligned) {
-            std::collections::hash_map::Entry::Occupied(mut o) => {
-                let prog = o.get_mut();
-                assert_eq!(aligned + prog.recv * self.transfer_width, addr); // Sequential response
-                if prog.recv == multiplicity - 1 {
-                    o.remove();
-                    self.done.push_back(aligned);
-                } else {
-                    prog.recv += 1;
-                }
-            }
-            std::collections::hash_map::Entry::Vacant(_) => panic!("Unexpected memory response"),
+impl DelaySimulator for QueuedDelay {
+    fn tick(&mut self) {
+        self.inner.tick();
+    }
+
+    fn tick_n(&mut self, n: usize) {
+        self.inner.tick_n(n);
+    }
+
+    fn push(&mut self, addr: u64, is_write: bool) {
+        if !self.try_push(addr, is_write) {
+            panic!("Command queue full");
+        }
+    }
+
+    fn try_push(&mut self, addr: u64, is_write: bool) -> bool {
+        if self.occupied == self.depth {
+            return false;
+        }
+        self.occupied += 1;
+        self.inner.push(addr, is_write);
+        true
+    }
+
+    fn push_sized(&mut self, addr: u64, is_write: bool, size: usize) {
+        if self.occupied == self.depth {
+            panic!("Command queue full");
         }
+        self.occupied += 1;
+        self.inner.push_sized(addr, is_write, size);
     }
 
     fn pop(&mut self) -> Option<u64> {
-        self.done.pop_front()
+        let addr = self.inner.pop()?;
+        self.occupied -= 1;
+        Some(addr)
+    }
+}
+
+/// Models congestion: a request's latency is `base + k * n`, where `n` is the number of requests
+/// still queued when it arrives
+pub struct AdaptiveDelay {
+    base: usize,
+    k: usize,
+    tick: usize,
+    queue: VecDeque<(usize, u64)>,
+}
+
+impl AdaptiveDelay {
+    pub fn new(base: usize, k: usize) -> Self {
+        AdaptiveDelay {
+            base,
+            k,
+            tick: 0,
+            queue: VecDeque::new(),
+        }
+    }
+}
+
+impl DelaySimulator for AdaptiveDelay {
+    fn tick(&mut self) {
+        self.tick += 1;
+    }
+
+    fn push(&mut self, addr: u64, _is_write: bool) {
+        let at = self.tick + self.base + self.k * self.queue.len();
+        let pos = self.queue.partition_point(|(t, _)| *t <= at);
+        self.queue.insert(pos, (at, addr));
     }
 
-    fn multiplicity(&self) -> u64 {
-        WIDTH as u64 / self.transfer_width
+    fn pop(&mut self) -> Option<u64> {
+        if self.queue.front().is_some_and(|(t, _)| *t <= self.tick) {
+            self.queue.pop_front().map(|(_, addr)| addr)
+        } else {
+            None
+        }
     }
 }
 
-pub struct DRAMSim<const WIDTH: usize> {
-    sys: dramsim3::MemorySystem,
-    prog: Rc<RefCell<Progress<WIDTH>>>,
+/// Draws each request's latency uniformly from `[min, max]`. Requests to the same address still
+/// complete in issue order.
+pub struct RandomDelay {
+    min: usize,
+    max: usize,
+    max_reorder: Option<usize>,
+    rng: StdRng,
+    tick: usize,
+    next_seq: usize,
+    // (completion tick, push sequence number, addr), ordered by completion tick
+    queue: VecDeque<(usize, usize, u64)>,
 }
 
-impl<const WIDTH: usize> DRAMSim<WIDTH> {
-    pub fn new<Config: AsRef<Path>, Dir: AsRef<Path>>(config: Config, dir: Dir) -> Self {
-        let prog: Rc<RefCell<Progress<WIDTH>>> = Default::default();
-        let prog_cb = prog.clone();
+impl RandomDelay {
+    pub fn new(min: usize, max: usize, seed: u64) -> Self {
+        assert!(min <= max, "min must not exceed max");
+        RandomDelay {
+            min,
+            max,
+            max_reorder: None,
+            rng: StdRng::seed_from_u64(seed),
+            tick: 0,
+            next_seq: 0,
+            queue: VecDeque::new(),
+        }
+    }
 
-        let config_cstr = CString::new(config.as_ref().as_os_str().as_encoded_bytes()).unwrap();
-        let dir_cstr = CString::new(dir.as_ref().as_os_str().as_encoded_bytes()).unwrap();
-        let sys = dramsim3::MemorySystem::new(&config_cstr, &dir_cstr, move |addr, _is_write| {
-            prog_cb.borrow_mut().step(addr)
-        });
+    /// Holds back a response while a request pushed more than `distance` requests before it is
+    /// still incomplete. With a distance of 0, responses come back in push order.
+    pub fn with_max_reorder_distance(mut self, distance: usize) -> Self {
+        self.max_reorder = Some(distance);
+        self
+    }
+}
+
+impl DelaySimulator for RandomDelay {
+    fn tick(&mut self) {
+        self.tick += 1;
+    }
 
-        let transfer_width = sys.bus_bits() * sys.burst_length() / 8;
-        prog.borrow_mut().transfer_width = transfer_width as u64;
+    fn push(&mut self, addr: u64, _is_write: bool) {
+        let earliest = self
+            .queue
+            .iter()
+            .filter(|(_, _, a)| *a == addr)
+            .map(|(t, _, _)| *t)
+            .max()
+            .unwrap_or(0);
+        let at = (self.tick + self.rng.gen_range(self.min..=self.max)).max(earliest);
+        let pos = self.queue.partition_point(|(t, _, _)| *t <= at);
+        self.queue.insert(pos, (at, self.next_seq, addr));
+        self.next_seq += 1;
+    }
+
+    fn pop(&mut self) -> Option<u64> {
+        let limit = match self.max_reorder {
+            Some(distance) => self.queue.iter().map(|(_, seq, _)| *seq).min()? + distance,
+            None => usize::MAX,
+        };
+        let pos = self
+            .queue
+            .iter()
+            .take_while(|(t, _, _)| *t <= self.tick)
+            .position(|(_, seq, _)| *seq <= limit)?;
+        self.queue.remove(pos).map(|(_, _, addr)| addr)
+    }
+}
+
+/// Computes each access's latency from the contents of the accessed chunk, like a compressed
+/// memory. Needs the data from `push_with_data`, so it can't be used where only addresses are
+/// passed on, e.g. as the second stage of a `Chain`.
+pub struct DataDependentDelay<const WIDTH: usize> {
+    latency: fn(&[u8; WIDTH]) -> usize,
+    tick: usize,
+    queue: VecDeque<(usize, u64)>,
+}
 
-        DRAMSim { sys, prog }
+impl<const WIDTH: usize> DataDependentDelay<WIDTH> {
+    pub fn new(latency: fn(&[u8; WIDTH]) -> usize) -> Self {
+        DataDependentDelay {
+            latency,
+            tick: 0,
+            queue: VecDeque::new(),
+        }
     }
 }
 
-impl<const WIDTH: usize> DelaySimulator for DRAMSim<WIDTH> {
+impl<const WIDTH: usize> DelaySimulator for DataDependentDelay<WIDTH> {
     fn tick(&mut self) {
-        self.sys.tick();
-        let mut prog = self.prog.borrow_mut();
-        let multiplicity = prog.multiplicity();
-        let transfer_width = prog.transfer_width;
-        for (aligned, addr_prog) in prog.progress.iter_mut() {
-            if addr_prog.sent != multiplicity {
-                let next_addr = addr_prog.next_send(*aligned, transfer_width);
-                if self.sys.can_add(next_addr, addr_prog.is_write) {
-                    self.sys.add(next_addr, addr_prog.is_write);
-                    addr_prog.sent += 1;
-                }
-            }
+        self.tick += 1;
+    }
+
+    fn push(&mut self, addr: u64, _is_write: bool) {
+        panic!("Access to {:#x} pushed without its data", addr);
+    }
+
+    fn push_with_data(&mut self, addr: u64, _is_write: bool, _size: usize, data: &[u8]) {
+        let data: &[u8; WIDTH] = data.try_into().unwrap_or_else(|_| {
+            panic!(
+                "Access to {:#x} has {} data bytes, expected {}",
+                addr,
+                data.len(),
+                WIDTH
+            )
+        });
+        // Accesses to the same address still complete in issue order
+        let earliest = self
+            .queue
+            .iter()
+            .filter(|(_, a)| *a == addr)
+            .map(|(t, _)| *t)
+            .max()
+            .unwrap_or(0);
+        let at = (self.tick + (self.latency)(data)).max(earliest);
+        let pos = self.queue.partition_point(|(t, _)| *t <= at);
+        self.queue.insert(pos, (at, addr));
+    }
+
+    fn pop(&mut self) -> Option<u64> {
+        if self.queue.front().is_some_and(|(t, _)| *t <= self.tick) {
+            self.queue.pop_front().map(|(_, addr)| addr)
+        } else {
+            None
         }
     }
+}
 
-    fn push(&mut self, addr: u64, is_write: bool) {
-        self.prog.borrow_mut().add(addr, is_write);
+/// Approximates DRAM row-buffer behavior: each of `banks` banks keeps its last accessed row open,
+/// and an access to the open row takes `hit_latency` instead of `miss_latency`. Rows are
+/// `row_bytes` large and interleaved across banks.
+pub struct SimpleDramModel {
+    row_bytes: u64,
+    hit_latency: usize,
+    miss_latency: usize,
+    open_rows: Vec<Option<u64>>,
+
+    tick: usize,
+    queue: VecDeque<(usize, u64)>,
+    hits: u64,
+    misses: u64,
+}
+
+impl SimpleDramModel {
+    pub fn new(row_bytes: u64, banks: usize, hit_latency: usize, miss_latency: usize) -> Self {
+        assert!(
+            row_bytes > 0 && banks > 0,
+            "row_bytes and banks must be positive"
+        );
+        SimpleDramModel {
+            row_bytes,
+            hit_latency,
+            miss_latency,
+            open_rows: alloc::vec![None; banks],
+            tick: 0,
+            queue: VecDeque::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn row_buffer_hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn row_buffer_misses(&self) -> u64 {
+        self.misses
+    }
+}
+
+impl DelaySimulator for SimpleDramModel {
+    fn tick(&mut self) {
+        self.tick += 1;
+    }
+
+    fn push(&mut self, addr: u64, _is_write: bool) {
+        let row = addr / self.row_bytes;
+        let bank = (row % self.open_rows.len() as u64) as usize;
+        let latency = if self.open_rows[bank] == Some(row) {
+            self.hits += 1;
+            self.hit_latency
+        } else {
+            self.misses += 1;
+            self.open_rows[bank] = Some(row);
+            self.miss_latency
+        };
+        let at = self.tick + latency;
+        let pos = self.queue.partition_point(|(t, _)| *t <= at);
+        self.queue.insert(pos, (at, addr));
     }
 
     fn pop(&mut self) -> Option<u64> {
-        self.prog.borrow_mut().pop()
+        if self.queue.front().is_some_and(|(t, _)| *t <= self.tick) {
+            self.queue.pop_front().map(|(_, addr)| addr)
+        } else {
+            None
+        }
     }
 }
 
-pub struct Mem<D: DelaySimulator, const WIDTH: usize> {
-    sim: D,
-    content: HashMap<u64, [u8; WIDTH]>,
-    inflights: HashMap<u64, usize>,
+/// Models column-to-column spacing across DDR4/DDR5 bank groups: accesses are issued in order,
+/// at least `t_ccd_l` ticks after the previous one if both hit the same bank group, and
+/// `t_ccd_s` ticks otherwise. Each takes `latency` ticks from issue. Consecutive
+/// `block_bytes`-sized blocks are interleaved across the `groups` bank groups.
+pub struct BankGroupModel {
+    block_bytes: u64,
+    groups: u64,
+    latency: usize,
+    t_ccd_s: usize,
+    t_ccd_l: usize,
+
+    tick: usize,
+    // Issue tick and bank group of the previous access
+    last: Option<(usize, u64)>,
+    queue: VecDeque<(usize, u64)>,
 }
 
-impl<D: DelaySimulator, const WIDTH: usize> Mem<D, WIDTH> {
-    pub fn new(sim: D) -> Self {
-        Mem {
-            sim,
-            content: HashMap::new(),
-            inflights: HashMap::new(),
+impl BankGroupModel {
+    pub fn new(
+        block_bytes: u64,
+        groups: u64,
+        latency: usize,
+        t_ccd_s: usize,
+        t_ccd_l: usize,
+    ) -> Self {
+        assert!(
+            block_bytes > 0 && groups > 0,
+            "block_bytes and groups must be positive"
+        );
+        BankGroupModel {
+            block_bytes,
+            groups,
+            latency,
+            t_ccd_s,
+            t_ccd_l,
+            tick: 0,
+            last: None,
+            queue: VecDeque::new(),
         }
     }
+
+    pub fn bank_group(&self, addr: u64) -> u64 {
+        addr / self.block_bytes % self.groups
+    }
 }
 
-impl<D: DelaySimulator, const WIDTH: usize> Drain for Mem<D, WIDTH> {
-    type Req = MemReq<WIDTH>;
-    type Resp = MemResp<WIDTH>;
+impl DelaySimulator for BankGroupModel {
     fn tick(&mut self) {
-        self.sim.tick();
+        self.tick += 1;
     }
 
-    fn push(&mut self, req: MemReq<WIDTH>) {
-        if self.inflights.insert(req.addr, req.id).is_some() {
-            panic!("Duplicated inflight memory requests");
+    fn push(&mut self, addr: u64, _is_write: bool) {
+        let group = self.bank_group(addr);
+        let issue = match self.last {
+            Some((last, last_group)) => {
+                let spacing = if last_group == group {
+                    self.t_ccd_l
+                } else {
+                    self.t_ccd_s
+                };
+                self.tick.max(last + spacing)
+            }
+            None => self.tick,
+        };
+        self.last = Some((issue, group));
+        // Issue ticks never decrease, so neither do completions
+        self.queue.push_back((issue + self.latency, addr));
+    }
+
+    fn pop(&mut self) -> Option<u64> {
+        if self.queue.front().is_some_and(|(t, _)| *t <= self.tick) {
+            self.queue.pop_front().map(|(_, addr)| addr)
+        } else {
+            None
         }
-        self.sim.push(req.addr, req.wbe.contains(&true));
-        match self.content.entry(req.addr) {
-            std::collections::hash_map::Entry::Occupied(mut o) => {
-                for (c, (w, be)) in o
-                    .get_mut()
-                    .iter_mut()
-                    .zip(req.wdata.iter().zip(req.wbe.iter()))
-                {
-                    if *be {
-                        *c = *w;
-                    }
+    }
+}
+
+struct FrFcfsBank {
+    open_row: Option<u64>,
+    // Tick the access being served completes
+    busy_until: usize,
+    // Waiting accesses in arrival order
+    pending: VecDeque<u64>,
+}
+
+/// Like `SimpleDramModel`, but each bank serves one access at a time and picks the next one
+/// FR-FCFS style: the oldest waiting access to the open row if any, the oldest one otherwise.
+/// Accesses to the same address always share a row, so they still complete in order.
+pub struct FrFcfsModel {
+    row_bytes: u64,
+    hit_latency: usize,
+    miss_latency: usize,
+    banks: Vec<FrFcfsBank>,
+
+    tick: usize,
+    queue: VecDeque<(usize, u64)>,
+    hits: u64,
+    misses: u64,
+}
+
+impl FrFcfsModel {
+    pub fn new(row_bytes: u64, banks: usize, hit_latency: usize, miss_latency: usize) -> Self {
+        assert!(
+            row_bytes > 0 && banks > 0,
+            "row_bytes and banks must be positive"
+        );
+        FrFcfsModel {
+            row_bytes,
+            hit_latency,
+            miss_latency,
+            banks: (0..banks)
+                .map(|_| FrFcfsBank {
+                    open_row: None,
+                    busy_until: 0,
+                    pending: VecDeque::new(),
+                })
+                .collect(),
+            tick: 0,
+            queue: VecDeque::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn row_buffer_hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn row_buffer_misses(&self) -> u64 {
+        self.misses
+    }
+
+    fn bank_of(&self, addr: u64) -> usize {
+        (addr / self.row_bytes % self.banks.len() as u64) as usize
+    }
+
+    fn schedule(&mut self, bank: usize) {
+        let row_bytes = self.row_bytes;
+        let b = &mut self.banks[bank];
+        while b.busy_until <= self.tick && !b.pending.is_empty() {
+            let pos = b
+                .pending
+                .iter()
+                .position(|addr| Some(addr / row_bytes) == b.open_row);
+            let latency = match pos {
+                Some(_) => {
+                    self.hits += 1;
+                    self.hit_latency
                 }
-            }
-            std::collections::hash_map::Entry::Vacant(v) => {
-                let mut buf = [0; WIDTH];
-                for (c, (w, be)) in buf.iter_mut().zip(req.wdata.iter().zip(req.wbe.iter())) {
-                    if *be {
-                        *c = *w;
-                    }
+                None => {
+                    self.misses += 1;
+                    self.miss_latency
                 }
-                v.insert(buf);
-            }
+            };
+            let addr = b.pending.remove(pos.unwrap_or(0)).unwrap();
+            b.open_row = Some(addr / row_bytes);
+            b.busy_until = self.tick + latency;
+            let at = b.busy_until;
+            let idx = self.queue.partition_point(|(t, _)| *t <= at);
+            self.queue.insert(idx, (at, addr));
         }
     }
+}
 
-    fn pop(&mut self) -> Option<MemResp<WIDTH>> {
-        self.sim.pop().map(|addr| {
-            let rdata = self.content.get(&addr).cloned().unwrap_or([0; WIDTH]);
-            let id = self
-                .inflights
-                .remove(&addr)
-                .expect("Unexpected memory response");
-            MemResp { id, rdata }
-        })
+impl DelaySimulator for FrFcfsModel {
+    fn tick(&mut self) {
+        self.tick += 1;
+        for bank in 0..self.banks.len() {
+            self.schedule(bank);
+        }
     }
-}
 
-#[test]
-fn test_simple_sram() {
-    let mem: Mem<_, 256> = Mem::new(NoDelay::default());
-    test_simple(mem, 1);
+    fn push(&mut self, addr: u64, _is_write: bool) {
+        let bank = self.bank_of(addr);
+        self.banks[bank].pending.push_back(addr);
+        self.schedule(bank);
+    }
+
+    fn pop(&mut self) -> Option<u64> {
+        if self.queue.front().is_some_and(|(t, _)| *t <= self.tick) {
+            self.queue.pop_front().map(|(_, addr)| addr)
+        } else {
+            None
+        }
+    }
 }
 
-#[test]
-fn test_simple_dram() {
-    let mut cfg = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    cfg.push("resources/test/DDR4_8Gb_x16_3200.ini");
+/// Feeds completions of `A` into `B`, so that latencies add up
+pub struct Chain<A: DelaySimulator, B: DelaySimulator> {
+    first: A,
+    second: B,
+    is_write: BTreeMap<u64, VecDeque<bool>>,
+}
 
-    let tmpdir = tempfile::tempdir().unwrap();
+impl<A: DelaySimulator, B: DelaySimulator> Chain<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        Chain {
+            first,
+            second,
+            is_write: BTreeMap::new(),
+        }
+    }
 
-    let dramsim: DRAMSim<256> = DRAMSim::new(cfg, &tmpdir);
-    let mem: Mem<_, 256> = Mem::new(dramsim);
-    test_simple(mem, 20);
+    fn forward(&mut self) {
+        while let Some(addr) = self.first.pop() {
+            let pending = self.is_write.get_mut(&addr).unwrap();
+            let is_write = pending.pop_front().unwrap();
+            if pending.is_empty() {
+                self.is_write.remove(&addr);
+            }
+            self.second.push(addr, is_write);
+        }
+    }
 }
 
-#[cfg(test)]
-fn test_simple<D: DelaySimulator>(mut mem: Mem<D, 256>, wait: usize) {
-    use rand::Rng;
+impl<A: DelaySimulator, B: DelaySimulator> DelaySimulator for Chain<A, B> {
+    fn tick(&mut self) {
+        // Tick the second stage first, so a request entering it this tick still waits its full
+        // latency there
+        self.second.tick();
+        self.first.tick();
+        self.forward();
+    }
 
-    let mut rng = rand::thread_rng();
-    let mut buf = [0; 256];
-    rng.fill(&mut buf);
+    fn push(&mut self, addr: u64, is_write: bool) {
+        self.is_write.entry(addr).or_default().push_back(is_write);
+        self.first.push(addr, is_write);
+    }
 
-    mem.tick();
-    mem.push(MemReq {
-        id: 1,
-        addr: 0x80004000,
-        wbe: [true; 256],
-        wdata: buf.clone(),
-    });
+    /// Only the first stage sees the size
+    fn push_sized(&mut self, addr: u64, is_write: bool, size: usize) {
+        self.is_write.entry(addr).or_default().push_back(is_write);
+        self.first.push_sized(addr, is_write, size);
+    }
 
-    let mut done = false;
-    for t in 0..wait {
-        if let Some(r) = mem.pop() {
-            assert_eq!(r.id, 1);
-            done = true;
-            println!("Done at tick {}", t);
-            break;
+    /// Only the first stage sees the data
+    fn push_with_data(&mut self, addr: u64, is_write: bool, size: usize, data: &[u8]) {
+        self.is_write.entry(addr).or_default().push_back(is_write);
+        self.first.push_with_data(addr, is_write, size, data);
+    }
+
+    fn pop(&mut self) -> Option<u64> {
+        self.forward();
+        self.second.pop()
+    }
+
+    /// Taken from whichever stage splits requests, the second one if both do
+    fn transfer_ticks(&mut self, addr: u64) -> Option<Vec<usize>> {
+        self.second
+            .transfer_ticks(addr)
+            .or_else(|| self.first.transfer_ticks(addr))
+    }
+
+    fn record_transfer_ticks(&mut self) {
+        self.first.record_transfer_ticks();
+        self.second.record_transfer_ticks();
+    }
+}
+
+/// Sends reads through `R` and writes through `W`. A completion that overtakes an earlier access
+/// to the same address on the other path is held back until that access completes, since `Mem`
+/// expects each address to complete in issue order.
+pub struct SplitRw<R: DelaySimulator, W: DelaySimulator> {
+    reads: R,
+    writes: W,
+    // addr -> kinds of the outstanding accesses in issue order
+    issued: BTreeMap<u64, VecDeque<bool>>,
+    // addr -> (reads, writes) completed but held back
+    held: BTreeMap<u64, (usize, usize)>,
+    // Completions released in order, and whether each is a write
+    ready: VecDeque<(u64, bool)>,
+    // Kind of the access popped last, for `transfer_ticks`
+    popped: Option<bool>,
+}
+
+impl<R: DelaySimulator, W: DelaySimulator> SplitRw<R, W> {
+    pub fn new(reads: R, writes: W) -> Self {
+        SplitRw {
+            reads,
+            writes,
+            issued: BTreeMap::new(),
+            held: BTreeMap::new(),
+            ready: VecDeque::new(),
+            popped: None,
         }
-        mem.tick();
     }
-    assert!(done);
 
-    mem.tick();
-    mem.push(MemReq {
-        id: 2,
-        addr: 0x80004000,
-        wbe: [false; 256],
-        wdata: [0; 256],
-    });
+    fn complete(&mut self, addr: u64, is_write: bool) {
+        let held = self.held.entry(addr).or_default();
+        if is_write {
+            held.1 += 1;
+        } else {
+            held.0 += 1;
+        }
+        let pending = self.issued.get_mut(&addr).unwrap();
+        while let Some(&front) = pending.front() {
+            let count = if front { &mut held.1 } else { &mut held.0 };
+            if *count == 0 {
+                break;
+            }
+            *count -= 1;
+            pending.pop_front();
+            self.ready.push_back((addr, front));
+        }
+        if pending.is_empty() {
+            self.issued.remove(&addr);
+            self.held.remove(&addr);
+        }
+    }
+}
+
+impl<R: DelaySimulator, W: DelaySimulator> DelaySimulator for SplitRw<R, W> {
+    fn tick(&mut self) {
+        self.reads.tick();
+        self.writes.tick();
+    }
+
+    fn push(&mut self, addr: u64, is_write: bool) {
+        self.issued.entry(addr).or_default().push_back(is_write);
+        if is_write {
+            self.writes.push(addr, true);
+        } else {
+            self.reads.push(addr, false);
+        }
+    }
+
+    fn push_sized(&mut self, addr: u64, is_write: bool, size: usize) {
+        self.issued.entry(addr).or_default().push_back(is_write);
+        if is_write {
+            self.writes.push_sized(addr, true, size);
+        } else {
+            self.reads.push_sized(addr, false, size);
+        }
+    }
+
+    fn push_with_data(&mut self, addr: u64, is_write: bool, size: usize, data: &[u8]) {
+        self.issued.entry(addr).or_default().push_back(is_write);
+        if is_write {
+            self.writes.push_with_data(addr, true, size, data);
+        } else {
+            self.reads.push_with_data(addr, false, size, data);
+        }
+    }
+
+    fn try_push(&mut self, addr: u64, is_write: bool) -> bool {
+        let accepted = if is_write {
+            self.writes.try_push(addr, true)
+        } else {
+            self.reads.try_push(addr, false)
+        };
+        if accepted {
+            self.issued.entry(addr).or_default().push_back(is_write);
+        }
+        accepted
+    }
+
+    fn pop(&mut self) -> Option<u64> {
+        while let Some(addr) = self.reads.pop() {
+            self.complete(addr, false);
+        }
+        while let Some(addr) = self.writes.pop() {
+            self.complete(addr, true);
+        }
+        let (addr, is_write) = self.ready.pop_front()?;
+        self.popped = Some(is_write);
+        Some(addr)
+    }
+
+    fn transfer_ticks(&mut self, addr: u64) -> Option<Vec<usize>> {
+        match self.popped? {
+            true => self.writes.transfer_ticks(addr),
+            false => self.reads.transfer_ticks(addr),
+        }
+    }
+
+    fn record_transfer_ticks(&mut self) {
+        self.reads.record_transfer_ticks();
+        self.writes.record_transfer_ticks();
+    }
+}
+
+struct Queued {
+    addr: u64,
+    is_write: bool,
+    // Which `push` variant it came through
+    size: Option<usize>,
+    data: Option<Vec<u8>>,
+}
+
+/// Lets each bank, as given by `bank_of`, have one access in `inner` at a time, like a
+/// single-ported bank. Accesses to a busy bank wait in FIFO order and are pushed as soon as the
+/// previous one completes, while different banks proceed in parallel.
+pub struct Serialize<D: DelaySimulator> {
+    inner: D,
+    bank_of: fn(u64) -> usize,
+    // Banks with an access in `inner`, and the accesses waiting for each
+    busy: BTreeSet<usize>,
+    waiting: BTreeMap<usize, VecDeque<Queued>>,
+    ready: VecDeque<u64>,
+}
+
+impl<D: DelaySimulator> Serialize<D> {
+    pub fn new(inner: D, bank_of: fn(u64) -> usize) -> Self {
+        Serialize {
+            inner,
+            bank_of,
+            busy: BTreeSet::new(),
+            waiting: BTreeMap::new(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    fn enqueue(&mut self, access: Queued) {
+        let bank = (self.bank_of)(access.addr);
+        if self.busy.insert(bank) {
+            self.issue(access);
+        } else {
+            self.waiting.entry(bank).or_default().push_back(access);
+        }
+    }
+
+    fn issue(&mut self, access: Queued) {
+        match (access.size, access.data) {
+            (Some(size), Some(data)) => {
+                self.inner
+                    .push_with_data(access.addr, access.is_write, size, &data)
+            }
+            (Some(size), None) => self.inner.push_sized(access.addr, access.is_write, size),
+            (None, _) => self.inner.push(access.addr, access.is_write),
+        }
+    }
+
+    fn collect(&mut self) {
+        while let Some(addr) = self.inner.pop() {
+            self.ready.push_back(addr);
+            let bank = (self.bank_of)(addr);
+            let next = self.waiting.get_mut(&bank).and_then(VecDeque::pop_front);
+            match next {
+                Some(access) => self.issue(access),
+                None => {
+                    self.busy.remove(&bank);
+                    self.waiting.remove(&bank);
+                }
+            }
+        }
+    }
+}
+
+impl<D: DelaySimulator> DelaySimulator for Serialize<D> {
+    fn tick(&mut self) {
+        self.inner.tick();
+        self.collect();
+    }
+
+    fn push(&mut self, addr: u64, is_write: bool) {
+        self.enqueue(Queued {
+            addr,
+            is_write,
+            size: None,
+            data: None,
+        });
+    }
+
+    fn push_sized(&mut self, addr: u64, is_write: bool, size: usize) {
+        self.enqueue(Queued {
+            addr,
+            is_write,
+            size: Some(size),
+            data: None,
+        });
+    }
+
+    fn push_with_data(&mut self, addr: u64, is_write: bool, size: usize, data: &[u8]) {
+        self.enqueue(Queued {
+            addr,
+            is_write,
+            size: Some(size),
+            data: Some(data.to_vec()),
+        });
+    }
+
+    fn pop(&mut self) -> Option<u64> {
+        self.collect();
+        self.ready.pop_front()
+    }
+
+    fn transfer_ticks(&mut self, addr: u64) -> Option<Vec<usize>> {
+        self.inner.transfer_ticks(addr)
+    }
+
+    fn record_transfer_ticks(&mut self) {
+        self.inner.record_transfer_ticks();
+    }
+}
+
+/// Effective order of a read and a write to the same chunk that complete in the same tick
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum SameTickOrder {
+    /// The read observes the write's data
+    #[default]
+    WriteFirst,
+    /// The read observes the data from before the write
+    ReadFirst,
+}
+
+/// When a read takes its data, which matters for writes to the same chunk pushed while it's
+/// outstanding
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum ReadCapture {
+    /// The read returns the contents at completion, including later writes
+    #[default]
+    CaptureAtCompletion,
+    /// The read returns the contents at the time it was pushed
+    CaptureAtIssue,
+}
+
+/// What `Mem::merge_from` does with a chunk stored in both memories
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum MergePolicy {
+    /// Take the other memory's chunk
+    #[default]
+    Overwrite,
+    /// Keep this memory's chunk
+    KeepExisting,
+    /// Panic if the two chunks hold different data
+    PanicOnConflict,
+}
+
+/// Completed accesses of one requester, see `Mem::per_source_stats`
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct SourceStats {
+    pub accesses: u64,
+    pub average_latency: f64,
+}
+
+/// Read-only window of `len` bytes into a `Mem`, see `Mem::view`. Contents are sparse, so
+/// nothing is assembled up front: each byte access looks up its chunk, and chunks never written
+/// read as the uninitialized pattern.
+pub struct RegionView<'a, const WIDTH: usize> {
+    content: &'a BTreeMap<u64, [u8; WIDTH]>,
+    uninit: &'a [u8; WIDTH],
+    start: u64,
+    len: usize,
+}
+
+impl<'a, const WIDTH: usize> RegionView<'a, WIDTH> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..self.len).map(|idx| self[idx])
+    }
+}
+
+impl<'a, const WIDTH: usize> core::ops::Index<usize> for RegionView<'a, WIDTH> {
+    type Output = u8;
+
+    fn index(&self, idx: usize) -> &u8 {
+        if idx >= self.len {
+            panic!("Index {} out of view of {} bytes", idx, self.len);
+        }
+        let addr = self.start + idx as u64;
+        let offset = (addr % WIDTH as u64) as usize;
+        let chunk = self
+            .content
+            .get(&(addr - offset as u64))
+            .unwrap_or(self.uninit);
+        &chunk[offset]
+    }
+}
+
+type Callback<const WIDTH: usize> = Box<dyn FnOnce(&MemResp<WIDTH>)>;
+
+struct Inflight<const WIDTH: usize> {
+    id: usize,
+    issued: usize,
+    rbe: Option<[bool; WIDTH]>,
+    size: usize,
+    src: u16,
+    cancelled: bool,
+    seq: u64,
+    // Data observed by a read issued inside the forwarding window of an earlier write, captured
+    // under `ReadCapture::CaptureAtIssue`, or completing together with a write under
+    // `SameTickOrder::ReadFirst`
+    stale: Option<[u8; WIDTH]>,
+    // Chunk contents before a write, kept for `SameTickOrder::ReadFirst`
+    old: Option<[u8; WIDTH]>,
+}
+
+enum Held<const WIDTH: usize> {
+    Req(MemReq<WIDTH>),
+    Fence(usize),
+}
+
+pub struct Mem<D: DelaySimulator, const WIDTH: usize> {
+    sim: D,
+    // Ordered so that anything traversing the contents is deterministic. Writing and reading
+    // back 1M dense 64-byte chunks, this is slightly faster than a HashMap for sequential access
+    // and ~2.5x slower for random access, as measured by `bench_content_map`.
+    content: BTreeMap<u64, [u8; WIDTH]>,
+    // Requests to the same address are assumed to complete in the order they were issued
+    inflights: BTreeMap<u64, VecDeque<Inflight<WIDTH>>>,
+    // Completions taken from the simulator in one go, along with their transfer ticks
+    completed: VecDeque<(u64, Option<Vec<usize>>)>,
+    ready: VecDeque<MemResp<WIDTH>>,
+
+    tick: Clock,
+    shared_clock: bool,
+    strict: bool,
+    throughput: Option<(usize, Vec<u64>)>,
+    // Longest window that can be queried, and (tick, bytes) of each completion within it
+    bandwidth: Option<(usize, VecDeque<(usize, usize)>)>,
+    // src -> (completed accesses, total latency)
+    sources: BTreeMap<u16, (u64, u64)>,
+    callbacks: BTreeMap<usize, Callback<WIDTH>>,
+    // Chunk address -> reads and writes issued to it
+    heatmap: Option<BTreeMap<u64, u64>>,
+    uninit: [u8; WIDTH],
+    timeout: Option<usize>,
+    order: SameTickOrder,
+    capture: ReadCapture,
+    forwarding: usize,
+    // addr -> (tick, chunk contents before the write) for writes not yet visible to reads
+    unforwarded: BTreeMap<u64, VecDeque<(usize, [u8; WIDTH])>>,
+    response_cap: Option<(usize, OverflowPolicy)>,
+    // Only filled with `with_transfer_breakdown`
+    breakdowns: Option<BTreeMap<usize, Vec<usize>>>,
+    beats: bool,
+    sorted: bool,
+    // Remaining beat responses of the request completed last
+    pending_beats: VecDeque<MemResp<WIDTH>>,
+
+    seq: u64,
+    // Outstanding fence id, and the sequence number of the first request issued after it
+    fence: Option<(usize, u64)>,
+    held: VecDeque<Held<WIDTH>>,
+}
+
+impl<D: DelaySimulator, const WIDTH: usize> Mem<D, WIDTH> {
+    pub fn new(sim: D) -> Self {
+        Mem {
+            sim,
+            content: BTreeMap::new(),
+            inflights: BTreeMap::new(),
+            completed: VecDeque::new(),
+            ready: VecDeque::new(),
+            tick: Clock::new(),
+            shared_clock: false,
+            strict: false,
+            throughput: None,
+            bandwidth: None,
+            sources: BTreeMap::new(),
+            callbacks: BTreeMap::new(),
+            heatmap: None,
+            uninit: [0; WIDTH],
+            timeout: None,
+            order: SameTickOrder::WriteFirst,
+            capture: ReadCapture::default(),
+            forwarding: 0,
+            unforwarded: BTreeMap::new(),
+            response_cap: None,
+            breakdowns: None,
+            beats: false,
+            sorted: false,
+            pending_beats: VecDeque::new(),
+            seq: 0,
+            fence: None,
+            held: VecDeque::new(),
+        }
+    }
+
+    /// Issues a fence, which completes with a response carrying `id` (and zeroed `rdata`) once
+    /// every request pushed before it has completed. Requests pushed after the fence are held
+    /// back until then, so they observe memory only after all preceding writes are committed.
+    pub fn fence(&mut self, id: usize) {
+        if self.fence.is_some() {
+            self.held.push_back(Held::Fence(id));
+        } else {
+            self.fence = Some((id, self.seq));
+            self.check_fence();
+        }
+    }
+
+    fn check_fence(&mut self) {
+        while let Some((id, boundary)) = self.fence {
+            if self
+                .inflights
+                .values()
+                .flatten()
+                .any(|inflight| inflight.seq < boundary)
+            {
+                return;
+            }
+            self.ready.push_back(MemResp {
+                id,
+                rdata: [0; WIDTH],
+                beat: 0,
+                last: true,
+                src: 0,
+            });
+            self.fence = None;
+            while let Some(held) = self.held.pop_front() {
+                match held {
+                    Held::Req(req) => self.issue(req),
+                    Held::Fence(id) => {
+                        self.fence = Some((id, self.seq));
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Takes time from a shared clock instead of counting ticks, see `Delay::with_clock`
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.tick = clock;
+        self.shared_clock = true;
+        self
+    }
+
+    pub fn current_tick(&self) -> usize {
+        self.tick.now()
+    }
+
+    pub fn with_same_tick_order(mut self, order: SameTickOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    pub fn with_read_capture(mut self, capture: ReadCapture) -> Self {
+        self.capture = capture;
+        self
+    }
+
+    /// Reads issued less than `ticks` ticks after a write to the same chunk still observe the
+    /// data from before the write, modelling a store buffer that takes time to drain
+    pub fn with_forwarding_delay(mut self, ticks: usize) -> Self {
+        self.forwarding = ticks;
+        self
+    }
+
+    /// Drops writes to `addr` that have become visible, returning the data a read issued now
+    /// observes if some haven't
+    fn stale_data(&mut self, addr: u64) -> Option<[u8; WIDTH]> {
+        let now = self.tick.now();
+        let writes = self.unforwarded.get_mut(&addr)?;
+        while writes
+            .front()
+            .is_some_and(|(t, _)| now - t >= self.forwarding)
+        {
+            writes.pop_front();
+        }
+        let stale = writes.front().map(|(_, data)| *data);
+        if writes.is_empty() {
+            self.unforwarded.remove(&addr);
+        }
+        stale
+    }
+
+    /// Responds once per sub-transfer for simulators that split requests (see
+    /// `DelaySimulator::transfer_ticks`), with increasing `beat` and the full line in each `rdata`.
+    /// The beats are delivered together once the last one completes, and `transfer_breakdown`
+    /// has the tick each of them finished at.
+    ///
+    /// This breaks the usual one response per request: only the final beat has `last` set, and
+    /// wrappers retire a request on it (see `Identified::is_last`). `WidthAdapter` collapses the
+    /// beats into one response. `Tagged` and `Checker` expect a single response and don't
+    /// support beats.
+    pub fn with_beat_responses(mut self) -> Self {
+        self.beats = true;
+        self.sim.record_transfer_ticks();
+        self
+    }
+
+    /// Records the completion tick of each sub-transfer for `transfer_breakdown`
+    pub fn with_transfer_breakdown(mut self) -> Self {
+        self.breakdowns = Some(BTreeMap::new());
+        self.sim.record_transfer_ticks();
+        self
+    }
+
+    /// Pops responses in ascending id order: a completed response is held back while any request
+    /// with a lower id is still outstanding. Popping stalls for good if such a request never
+    /// completes, so ids should be unique and every request should eventually finish.
+    pub fn with_sorted_responses(mut self) -> Self {
+        self.sorted = true;
+        self
+    }
+
+    fn pop_sorted(&mut self) -> Option<MemResp<WIDTH>> {
+        while !overflow_blocks(self.response_cap, self.ready.len()) {
+            let Some(resp) = self.complete() else {
+                break;
+            };
+            make_room(&mut self.ready, self.response_cap);
+            self.ready.push_back(resp);
+            self.check_fence();
+        }
+        // The first one with the lowest id, so beats stay in order
+        let (pos, id) = self
+            .ready
+            .iter()
+            .enumerate()
+            .map(|(pos, resp)| (pos, resp.id))
+            .min_by_key(|(_, id)| *id)?;
+        let blocked = self
+            .inflights
+            .values()
+            .flatten()
+            .any(|inflight| !inflight.cancelled && inflight.id < id)
+            || self
+                .held
+                .iter()
+                .any(|held| matches!(held, Held::Req(req) if req.id < id))
+            || self.pending_beats.iter().any(|resp| resp.id < id);
+        if blocked {
+            return None;
+        }
+        self.ready.remove(pos)
+    }
+
+    /// Bounds the number of completed responses waiting to be popped
+    pub fn with_response_cap(mut self, cap: usize, policy: OverflowPolicy) -> Self {
+        self.response_cap = Some((cap, policy));
+        self
+    }
+
+    /// Ids of the requests to `addr` that are still outstanding, in issue order
+    pub fn inflight_ids(&self, addr: u64) -> Vec<usize> {
+        self.inflights
+            .get(&addr)
+            .into_iter()
+            .flatten()
+            .filter(|inflight| !inflight.cancelled)
+            .map(|inflight| inflight.id)
+            .collect()
+    }
+
+    pub fn sim(&self) -> &D {
+        &self.sim
+    }
+
+    /// Iterates over all stored chunks in ascending address order
+    pub fn iter_chunks(&self) -> impl Iterator<Item = (u64, &[u8; WIDTH])> {
+        self.content.iter().map(|(addr, chunk)| (*addr, chunk))
+    }
+
+    /// Copies every chunk stored in `other` into this memory, resolving chunks stored in both
+    /// according to `policy`. Outstanding requests of either memory are not affected.
+    pub fn merge_from<E: DelaySimulator>(&mut self, other: &Mem<E, WIDTH>, policy: MergePolicy) {
+        for (addr, chunk) in other.iter_chunks() {
+            match self.content.entry(addr) {
+                Entry::Vacant(entry) => {
+                    entry.insert(*chunk);
+                }
+                Entry::Occupied(mut entry) => match policy {
+                    MergePolicy::Overwrite => {
+                        entry.insert(*chunk);
+                    }
+                    MergePolicy::KeepExisting => {}
+                    MergePolicy::PanicOnConflict => {
+                        if entry.get() != chunk {
+                            panic!("Merged memories disagree on chunk at {:#x}", addr);
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    /// Borrows `[start, start + len)` for inspection without copying it out
+    pub fn view(&self, start: u64, len: usize) -> RegionView<'_, WIDTH> {
+        if start.checked_add(len as u64).is_none() {
+            panic!("View of {} bytes at {:#x} overflows", len, start);
+        }
+        RegionView {
+            content: &self.content,
+            uninit: &self.uninit,
+            start,
+            len,
+        }
+    }
+
+    /// Moves every stored chunk by `delta` bytes, which must be a multiple of `WIDTH`. Panics if
+    /// any request is outstanding or a chunk would move outside the address space.
+    pub fn rebase(&mut self, delta: i64) {
+        if !self.inflights.is_empty()
+            || !self.held.is_empty()
+            || !self.ready.is_empty()
+            || !self.pending_beats.is_empty()
+        {
+            panic!("Rebasing a memory with outstanding requests");
+        }
+        if !delta.unsigned_abs().is_multiple_of(WIDTH as u64) {
+            panic!(
+                "Rebase delta {} is not a multiple of {} bytes",
+                delta, WIDTH
+            );
+        }
+        let shift = |addr: u64| {
+            addr.checked_add_signed(delta)
+                .unwrap_or_else(|| panic!("Rebasing chunk at {:#x} by {} overflows", addr, delta))
+        };
+        self.content = core::mem::take(&mut self.content)
+            .into_iter()
+            .map(|(addr, chunk)| (shift(addr), chunk))
+            .collect();
+        self.unforwarded = core::mem::take(&mut self.unforwarded)
+            .into_iter()
+            .map(|(addr, writes)| (shift(addr), writes))
+            .collect();
+    }
+
+    /// Sets `[start, start + len)` to `byte` directly in the backing store, bypassing the delay
+    /// simulator. Bytes of partially covered chunks outside the range keep their contents.
+    pub fn fill(&mut self, start: u64, len: usize, byte: u8) {
+        let end = start
+            .checked_add(len as u64)
+            .unwrap_or_else(|| panic!("Fill of {} bytes at {:#x} overflows", len, start));
+        let mut addr = start - start % WIDTH as u64;
+        while addr < end {
+            let lo = start.saturating_sub(addr) as usize;
+            let hi = ((end - addr) as usize).min(WIDTH);
+            if lo == 0 && hi == WIDTH {
+                self.content.insert(addr, [byte; WIDTH]);
+            } else {
+                self.content.entry(addr).or_insert(self.uninit)[lo..hi].fill(byte);
+            }
+            addr += WIDTH as u64;
+        }
+    }
+
+    /// Panics if any request is still outstanding `ticks` ticks after it was pushed
+    pub fn with_timeout(mut self, ticks: usize) -> Self {
+        self.timeout = Some(ticks);
+        self
+    }
+
+    fn check_timeout(&self) {
+        let Some(timeout) = self.timeout else {
+            return;
+        };
+        for (addr, inflight) in self
+            .inflights
+            .iter()
+            .flat_map(|(addr, queue)| queue.iter().map(move |inflight| (addr, inflight)))
+        {
+            if !inflight.cancelled && self.tick.now() - inflight.issued > timeout {
+                panic!(
+                    "Request {} to {:#x} timed out after {} ticks",
+                    inflight.id, addr, timeout
+                );
+            }
+        }
+    }
+
+    /// Takes the completion tick of each sub-transfer of the last completed request `id`, if
+    /// the simulator reports them. Gaps between consecutive entries show where transfers got
+    /// stalled. Needs `with_transfer_breakdown`, and entries are kept until taken.
+    pub fn transfer_breakdown(&mut self, id: usize) -> Option<Vec<usize>> {
+        self.breakdowns
+            .as_mut()
+            .expect("Transfer breakdown not enabled")
+            .remove(&id)
+    }
+
+    /// Contents of never-written chunks, zero by default
+    pub fn set_uninit_pattern(&mut self, pattern: [u8; WIDTH]) {
+        self.uninit = pattern;
+    }
+
+    /// Counts completions in fixed windows of `window` ticks
+    pub fn with_throughput_sampling(mut self, window: usize) -> Self {
+        assert!(window > 0, "window must be positive");
+        self.throughput = Some((window, Vec::new()));
+        self
+    }
+
+    pub fn throughput_series(&self) -> Vec<u64> {
+        self.throughput
+            .as_ref()
+            .map(|(_, series)| series.clone())
+            .unwrap_or_default()
+    }
+
+    /// Keeps track of completions over the last `history` ticks for `effective_bandwidth`
+    pub fn with_bandwidth_history(mut self, history: usize) -> Self {
+        assert!(history > 0, "history must be positive");
+        self.bandwidth = Some((history, VecDeque::new()));
+        self
+    }
+
+    /// Counts the accesses issued to each chunk for `heatmap`
+    pub fn with_heatmap(mut self) -> Self {
+        self.heatmap = Some(BTreeMap::new());
+        self
+    }
+
+    /// Reads and writes issued so far by chunk address. Requests held behind a fence count once
+    /// they are issued. A `BTreeMap` rather than a `HashMap`, as `Mem` works without `std`, and
+    /// it lists chunks in address order.
+    pub fn heatmap(&self) -> BTreeMap<u64, u64> {
+        self.heatmap.clone().expect("Heatmap not enabled")
+    }
+
+    /// Like `push`, and calls `callback` with the response once `req` completes, before it's
+    /// popped. With beat responses, it's called with the first beat. A cancelled request drops
+    /// its callback uncalled.
+    pub fn push_with_callback(
+        &mut self,
+        req: MemReq<WIDTH>,
+        callback: impl FnOnce(&MemResp<WIDTH>) + 'static,
+    ) {
+        self.callbacks.insert(req.id, Box::new(callback));
+        self.push(req);
+    }
+
+    /// Completed accesses and their average latency in ticks, by `MemReq::src`
+    pub fn per_source_stats(&self) -> BTreeMap<u16, SourceStats> {
+        self.sources
+            .iter()
+            .map(|(src, (accesses, latency))| {
+                (
+                    *src,
+                    SourceStats {
+                        accesses: *accesses,
+                        average_latency: *latency as f64 / *accesses as f64,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// Bytes completed per tick over the last `window` ticks, including the current one. Each
+    /// completed request accounts for its `size` bytes.
+    pub fn effective_bandwidth(&self, window: usize) -> f64 {
+        let Some((history, completions)) = self.bandwidth.as_ref() else {
+            panic!("Bandwidth history not enabled");
+        };
+        if window == 0 || window > *history {
+            panic!(
+                "Bandwidth window {} out of range, expected 1 to {}",
+                window, history
+            );
+        }
+        let now = self.tick.now();
+        let bytes: usize = completions
+            .iter()
+            .rev()
+            .take_while(|(tick, _)| now - tick < window)
+            .map(|(_, bytes)| bytes)
+            .sum();
+        bytes as f64 / window as f64
+    }
+
+    /// Panics if a response is produced in the same tick its request was pushed, which usually
+    /// means a zero-latency simulator ended up in a timing-sensitive setup. With `std`, also
+    /// panics when dropped with responses that were never popped.
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    fn issue(&mut self, req: MemReq<WIDTH>) {
+        if let Some(heatmap) = self.heatmap.as_mut() {
+            *heatmap.entry(req.addr).or_default() += 1;
+        }
+        let stale = if self.forwarding == 0 {
+            None
+        } else if req.is_write() {
+            self.stale_data(req.addr);
+            let old = self.content.get(&req.addr).cloned().unwrap_or(self.uninit);
+            self.unforwarded
+                .entry(req.addr)
+                .or_default()
+                .push_back((self.tick.now(), old));
+            None
+        } else {
+            self.stale_data(req.addr)
+        };
+        let stale = match self.capture {
+            ReadCapture::CaptureAtIssue if !req.is_write() => {
+                stale.or_else(|| Some(self.content.get(&req.addr).cloned().unwrap_or(self.uninit)))
+            }
+            _ => stale,
+        };
+        let inflight = Inflight {
+            id: req.id,
+            issued: self.tick.now(),
+            rbe: req.rbe,
+            size: req.size,
+            src: req.src,
+            cancelled: false,
+            seq: self.seq,
+            stale,
+            old: (self.order == SameTickOrder::ReadFirst && req.is_write())
+                .then(|| self.content.get(&req.addr).cloned().unwrap_or(self.uninit)),
+        };
+        self.seq += 1;
+        self.inflights
+            .entry(req.addr)
+            .or_default()
+            .push_back(inflight);
+        match self.content.entry(req.addr) {
+            Entry::Occupied(mut o) => {
+                for (c, (w, be)) in o
+                    .get_mut()
+                    .iter_mut()
+                    .zip(req.wdata.iter().zip(req.wbe.iter()))
+                {
+                    if *be {
+                        *c = *w;
+                    }
+                }
+            }
+            Entry::Vacant(v) => {
+                let mut buf = self.uninit;
+                for (c, (w, be)) in buf.iter_mut().zip(req.wdata.iter().zip(req.wbe.iter())) {
+                    if *be {
+                        *c = *w;
+                    }
+                }
+                v.insert(buf);
+            }
+        }
+        let data = self.content[&req.addr];
+        self.sim
+            .push_with_data(req.addr, req.is_write(), req.size, &data);
+    }
+
+    /// Takes every completion the simulator currently has, so that reads can be ordered
+    /// against writes completing alongside them
+    fn collect_completed(&mut self) {
+        while let Some(addr) = self.sim.pop() {
+            let ticks = self.sim.transfer_ticks(addr);
+            self.completed.push_back((addr, ticks));
+        }
+        if self.order != SameTickOrder::ReadFirst {
+            return;
+        }
+
+        let mut counts: BTreeMap<u64, usize> = BTreeMap::new();
+        for (addr, _) in self.completed.iter() {
+            *counts.entry(*addr).or_default() += 1;
+        }
+        for (addr, n) in counts {
+            let Some(queue) = self.inflights.get_mut(&addr) else {
+                continue;
+            };
+            // The first n inflights are the ones completing, the earliest write among them
+            // holds the data from before all of them
+            let Some(old) = queue.iter().take(n).find_map(|inflight| inflight.old) else {
+                continue;
+            };
+            for inflight in queue.iter_mut().take(n) {
+                if inflight.old.is_none() && inflight.stale.is_none() {
+                    inflight.stale = Some(old);
+                }
+            }
+        }
+    }
+
+    fn complete(&mut self) -> Option<MemResp<WIDTH>> {
+        if let Some(resp) = self.pending_beats.pop_front() {
+            return Some(resp);
+        }
+        loop {
+            if self.completed.is_empty() {
+                self.collect_completed();
+            }
+            let (addr, ticks) = self.completed.pop_front()?;
+            let queue = self
+                .inflights
+                .get_mut(&addr)
+                .expect("Unexpected memory response");
+            let inflight = queue.pop_front().unwrap();
+            if queue.is_empty() {
+                self.inflights.remove(&addr);
+            }
+            if inflight.cancelled {
+                continue;
+            }
+            let beats = match ticks {
+                Some(ticks) => {
+                    let n = ticks.len();
+                    if let Some(breakdowns) = self.breakdowns.as_mut() {
+                        breakdowns.insert(inflight.id, ticks);
+                    }
+                    if self.beats {
+                        n
+                    } else {
+                        1
+                    }
+                }
+                None => 1,
+            };
+
+            let mut rdata = inflight
+                .stale
+                .or_else(|| self.content.get(&addr).cloned())
+                .unwrap_or(self.uninit);
+            if let Some(rbe) = inflight.rbe {
+                for (r, be) in rdata.iter_mut().zip(rbe.iter()) {
+                    if !*be {
+                        *r = 0;
+                    }
+                }
+            }
+            rdata[inflight.size..].fill(0);
+            if self.strict && inflight.issued == self.tick.now() {
+                panic!(
+                    "Response for request {} produced in the same tick it was issued",
+                    inflight.id
+                );
+            }
+            if let Some((window, series)) = self.throughput.as_mut() {
+                let bucket = self.tick.now() / *window;
+                if series.len() <= bucket {
+                    series.resize(bucket + 1, 0);
+                }
+                series[bucket] += 1;
+            }
+            let (accesses, latency) = self.sources.entry(inflight.src).or_default();
+            *accesses += 1;
+            *latency += (self.tick.now() - inflight.issued) as u64;
+            if let Some((history, completions)) = self.bandwidth.as_mut() {
+                let now = self.tick.now();
+                while completions
+                    .front()
+                    .is_some_and(|(tick, _)| now - tick >= *history)
+                {
+                    completions.pop_front();
+                }
+                completions.push_back((now, inflight.size));
+            }
+            for beat in 1..beats {
+                self.pending_beats.push_back(MemResp {
+                    id: inflight.id,
+                    rdata,
+                    beat,
+                    last: beat == beats - 1,
+                    src: inflight.src,
+                });
+            }
+            let resp = MemResp {
+                id: inflight.id,
+                rdata,
+                beat: 0,
+                last: beats == 1,
+                src: inflight.src,
+            };
+            if let Some(callback) = self.callbacks.remove(&inflight.id) {
+                callback(&resp);
+            }
+            return Some(resp);
+        }
+    }
+}
+
+// Can't tell whether we're unwinding without std, and panicking then would abort
+#[cfg(any(feature = "std", test))]
+impl<D: DelaySimulator, const WIDTH: usize> Drop for Mem<D, WIDTH> {
+    fn drop(&mut self) {
+        if !self.strict || std::thread::panicking() {
+            return;
+        }
+        let inflight = self
+            .inflights
+            .values()
+            .flatten()
+            .filter(|inflight| !inflight.cancelled)
+            .count();
+        let unpopped = inflight + self.ready.len() + self.pending_beats.len() + self.held.len();
+        if unpopped > 0 {
+            panic!("Memory dropped with {} response(s) never popped", unpopped);
+        }
+    }
+}
+
+impl<D: DelaySimulator, const WIDTH: usize> Drain for Mem<D, WIDTH> {
+    type Req = MemReq<WIDTH>;
+    type Resp = MemResp<WIDTH>;
+    fn tick(&mut self) {
+        self.tick_begin();
+        self.tick_end();
+    }
+
+    fn tick_begin(&mut self) {
+        if !self.shared_clock {
+            self.tick.advance();
+        }
+        self.sim.tick();
+    }
+
+    fn tick_end(&mut self) {
+        while !overflow_blocks(self.response_cap, self.ready.len()) {
+            let Some(resp) = self.complete() else {
+                break;
+            };
+            make_room(&mut self.ready, self.response_cap);
+            self.ready.push_back(resp);
+            self.check_fence();
+        }
+        self.check_fence();
+        self.check_timeout();
+    }
+
+    /// Skips ahead in one go while nothing is outstanding, as no tick in between could complete
+    /// anything
+    fn tick_n(&mut self, n: usize) {
+        if self.inflights.is_empty() && self.held.is_empty() && self.pending_beats.is_empty() {
+            if !self.shared_clock {
+                self.tick.advance_by(n);
+            }
+            self.sim.tick_n(n);
+            return;
+        }
+        for _ in 0..n {
+            self.tick();
+        }
+    }
+
+    fn push(&mut self, req: MemReq<WIDTH>) {
+        if req.size == 0 || req.size > WIDTH {
+            panic!(
+                "Request {} has size {}, expected 1 to {}",
+                req.id, req.size, WIDTH
+            );
+        }
+        if self.fence.is_some() {
+            self.held.push_back(Held::Req(req));
+        } else {
+            self.issue(req);
+        }
+    }
+
+    fn pop(&mut self) -> Option<MemResp<WIDTH>> {
+        if self.sorted {
+            return self.pop_sorted();
+        }
+        if let Some(resp) = self.ready.pop_front() {
+            return Some(resp);
+        }
+        let resp = self.complete();
+        if resp.is_some() {
+            self.check_fence();
+        }
+        resp
+    }
+
+    fn cancel(&mut self, id: usize) -> bool {
+        // With beat responses, some beats may already be ready while others are still pending
+        let before = self.ready.len() + self.pending_beats.len();
+        self.ready.retain(|resp| resp.id != id);
+        self.pending_beats.retain(|resp| resp.id != id);
+        if self.ready.len() + self.pending_beats.len() < before {
+            return true;
+        }
+        if let Some(pos) = self
+            .held
+            .iter()
+            .position(|held| matches!(held, Held::Req(req) if req.id == id))
+        {
+            self.held.remove(pos);
+            self.callbacks.remove(&id);
+            return true;
+        }
+        // The simulator still owns the access, so keep the address busy until it completes
+        match self
+            .inflights
+            .values_mut()
+            .flatten()
+            .find(|inflight| inflight.id == id && !inflight.cancelled)
+        {
+            Some(inflight) => {
+                inflight.cancelled = true;
+                self.callbacks.remove(&id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Reports the chunk stored at exactly `addr`, counting requests held behind a fence as in
+    /// flight
+    fn probe(&self, addr: u64) -> ProbeResult {
+        let issued = self.inflights.get(&addr).map_or(0, |queue| {
+            queue.iter().filter(|inflight| !inflight.cancelled).count()
+        });
+        let held = self
+            .held
+            .iter()
+            .filter(|held| matches!(held, Held::Req(req) if req.addr == addr))
+            .count();
+        ProbeResult::Present {
+            data: self.content.get(&addr).unwrap_or(&self.uninit).to_vec(),
+            inflight: issued + held,
+        }
+    }
+}
+
+#[test]
+fn test_simple_sram() {
+    let mem: Mem<_, 256> = Mem::new(NoDelay::default());
+    test_simple(mem, 1);
+}
+
+#[test]
+#[should_panic(expected = "same tick")]
+fn test_strict_no_delay() {
+    let mem: Mem<_, 256> = Mem::new(NoDelay::default()).strict();
+    test_simple(mem, 1);
+}
+
+#[test]
+fn test_strict_fixed_delay() {
+    let mem: Mem<_, 256> = Mem::new(FixedDelay::new(1)).strict();
+    test_simple(mem, 2);
+}
+
+#[test]
+fn test_heatmap() {
+    let mut mem: Mem<_, 8> = Mem::new(NoDelay::default()).with_heatmap();
+    let addrs = (0..100)
+        .map(|_| 0x80000040)
+        .chain((0..8).map(|idx| 0x80000000 + 8 * idx));
+    for (id, addr) in addrs.enumerate() {
+        mem.push(MemReq::write(id, addr, [id % 2 == 0; 8], [0; 8]));
+        mem.pop().unwrap();
+    }
+    let heatmap = mem.heatmap();
+    assert_eq!(heatmap.len(), 9);
+    let (hot, count) = heatmap.iter().max_by_key(|(_, count)| **count).unwrap();
+    assert_eq!((*hot, *count), (0x80000040, 100));
+    assert_eq!(heatmap[&0x80000000], 1);
+}
+
+#[test]
+#[should_panic(expected = "Memory dropped with 2 response(s) never popped")]
+fn test_strict_lost_responses() {
+    let mut mem: Mem<_, 8> = Mem::new(FixedDelay::new(2)).strict();
+    for id in 0..3 {
+        mem.push(MemReq::read(id, 0x80000000 + 8 * id as u64));
+    }
+    for _ in 0..2 {
+        mem.tick();
+    }
+    mem.pop();
+}
+
+#[test]
+fn test_strict_all_popped() {
+    let mut mem: Mem<_, 8> = Mem::new(FixedDelay::new(2)).strict();
+    for id in 0..3 {
+        mem.push(MemReq::read(id, 0x80000000 + 8 * id as u64));
+    }
+    for _ in 0..2 {
+        mem.tick();
+    }
+    let popped: Vec<_> = core::iter::from_fn(|| mem.pop())
+        .map(|resp| resp.id)
+        .collect();
+    assert_eq!(popped, [0, 1, 2]);
+}
+
+#[test]
+fn test_completion_callback() {
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+
+    let mut mem: Mem<_, 8> = Mem::new(FixedDelay::new(2));
+    run_one(&mut mem, 0x80000000, Some([5; 8]));
+    let fired = Rc::new(RefCell::new(Vec::new()));
+    let log = fired.clone();
+    mem.push_with_callback(MemReq::read(1, 0x80000000), move |resp| {
+        log.borrow_mut().push((resp.id, resp.rdata))
+    });
+    mem.push(MemReq::read(2, 0x80000008));
+    let log = fired.clone();
+    mem.push_with_callback(MemReq::read(3, 0x80000010), move |resp| {
+        log.borrow_mut().push((resp.id, resp.rdata))
+    });
+    assert!(mem.cancel(3));
+
+    for _ in 0..10 {
+        mem.tick();
+        while mem.pop().is_some() {}
+    }
+    assert_eq!(*fired.borrow(), [(1, [5; 8])]);
+    // Both callbacks have been dropped
+    assert_eq!(Rc::strong_count(&fired), 1);
+}
+
+#[test]
+fn test_queued_delay_backpressure() {
+    let mut sim = QueuedDelay::new(2, 3);
+    assert!(sim.try_push(0x0, false));
+    assert!(sim.try_push(0x40, true));
+    for _ in 0..3 {
+        assert!(!sim.try_push(0x80, false));
+        assert_eq!(sim.pop(), None);
+        sim.tick();
+    }
+    assert_eq!(sim.pop(), Some(0x0));
+    assert!(sim.try_push(0x80, false));
+    assert!(!sim.try_push(0xc0, false));
+}
+
+#[test]
+fn test_chain_latency() {
+    for (a, b) in [(2, 3), (0, 4), (3, 0), (0, 0)] {
+        let mut sim = Chain::new(FixedDelay::new(a), FixedDelay::new(b));
+        sim.push(0x0, false);
+
+        let mut done = Vec::new();
+        for t in 0..20 {
+            if t == 1 {
+                sim.push(0x40, true);
+            }
+            while let Some(addr) = sim.pop() {
+                done.push((t, addr));
+            }
+            sim.tick();
+        }
+        assert_eq!(done, vec![(a + b, 0x0), (a + b + 1, 0x40)]);
+    }
+}
+
+#[test]
+fn test_split_rw() {
+    let mut sim = SplitRw::new(FixedDelay::new(2), FixedDelay::new(5));
+    sim.push(0x0, true);
+    sim.push(0x40, false);
+    // Overtakes the write on the read path, but must not complete before it
+    sim.push(0x0, false);
+
+    let mut done = Vec::new();
+    for t in 0..10 {
+        if t == 1 {
+            sim.push(0x80, true);
+        }
+        while let Some(addr) = sim.pop() {
+            done.push((t, addr));
+        }
+        sim.tick();
+    }
+    assert_eq!(done, vec![(2, 0x40), (5, 0x0), (5, 0x0), (6, 0x80)]);
+}
+
+#[test]
+fn test_serialize_banks() {
+    // 4 banks interleaved by 64 bytes
+    let mut sim = Serialize::new(FixedDelay::new(4), |addr| (addr / 64 % 4) as usize);
+    sim.push(0x0, false);
+    sim.push(0x100, true);
+    sim.push(0x40, false);
+    sim.push(0x80, false);
+
+    let mut done = Vec::new();
+    for t in 0..10 {
+        while let Some(addr) = sim.pop() {
+            done.push((t, addr));
+        }
+        sim.tick();
+    }
+    // 0x100 shares bank 0 with 0x0 and waits for it
+    assert_eq!(done, vec![(4, 0x0), (4, 0x40), (4, 0x80), (8, 0x100)]);
+}
+
+#[cfg(test)]
+fn run_one<D: DelaySimulator>(mem: &mut Mem<D, 8>, addr: u64, wdata: Option<[u8; 8]>) -> [u8; 8] {
+    mem.push(MemReq::write(
+        0,
+        addr,
+        [wdata.is_some(); 8],
+        wdata.unwrap_or_default(),
+    ));
+    loop {
+        mem.tick();
+        if let Some(resp) = mem.pop() {
+            break resp.rdata;
+        }
+    }
+}
+
+#[test]
+fn test_rebase() {
+    let mut mem: Mem<_, 8> = Mem::new(NoDelay::default());
+    run_one(&mut mem, 0x80000000, Some([1, 2, 3, 4, 5, 6, 7, 8]));
+    run_one(&mut mem, 0x8000fff8, Some([8; 8]));
+    mem.rebase(-0x40);
+
+    let chunks: Vec<_> = mem.iter_chunks().map(|(addr, _)| addr).collect();
+    assert_eq!(chunks, [0x7fffffc0, 0x8000ffb8]);
+    assert_eq!(
+        run_one(&mut mem, 0x7fffffc0, None),
+        [1, 2, 3, 4, 5, 6, 7, 8]
+    );
+    assert_eq!(run_one(&mut mem, 0x8000ffb8, None), [8; 8]);
+    assert_eq!(run_one(&mut mem, 0x80000000, None), [0; 8]);
+}
+
+#[test]
+fn test_fill() {
+    let mut mem: Mem<_, 8> = Mem::new(NoDelay::default());
+    run_one(&mut mem, 0x80000000, Some([1; 8]));
+    run_one(&mut mem, 0x80000018, Some([2; 8]));
+    mem.fill(0x80000005, 0x16, 0xcc);
+
+    assert_eq!(
+        mem.iter_chunks().collect::<Vec<_>>(),
+        [
+            (0x80000000, &[1, 1, 1, 1, 1, 0xcc, 0xcc, 0xcc]),
+            (0x80000008, &[0xcc; 8]),
+            (0x80000010, &[0xcc; 8]),
+            (0x80000018, &[0xcc, 0xcc, 0xcc, 2, 2, 2, 2, 2]),
+        ]
+    );
+    assert_eq!(
+        run_one(&mut mem, 0x80000000, None),
+        [1, 1, 1, 1, 1, 0xcc, 0xcc, 0xcc]
+    );
+    assert_eq!(run_one(&mut mem, 0x80000010, None), [0xcc; 8]);
+    assert_eq!(
+        run_one(&mut mem, 0x80000018, None),
+        [0xcc, 0xcc, 0xcc, 2, 2, 2, 2, 2]
+    );
+}
+
+#[cfg(test)]
+fn image(chunks: &[(u64, u8)]) -> Mem<NoDelay, 8> {
+    let mut mem = Mem::new(NoDelay::default());
+    for (addr, byte) in chunks {
+        run_one(&mut mem, *addr, Some([*byte; 8]));
+    }
+    mem
+}
+
+#[test]
+fn test_merge_from() {
+    let merged = |policy| {
+        let mut mem = image(&[(0x80000000, 1), (0x80000008, 2)]);
+        mem.merge_from(&image(&[(0x80000008, 3), (0x90000000, 4)]), policy);
+        mem.iter_chunks()
+            .map(|(addr, chunk)| (addr, chunk[0]))
+            .collect::<Vec<_>>()
+    };
+
+    let mut disjoint = image(&[(0x80000000, 1)]);
+    disjoint.merge_from(&image(&[(0x90000000, 4)]), MergePolicy::PanicOnConflict);
+    assert_eq!(disjoint.iter_chunks().count(), 2);
+    assert_eq!(run_one(&mut disjoint, 0x90000000, None), [4; 8]);
+
+    assert_eq!(
+        merged(MergePolicy::Overwrite),
+        [(0x80000000, 1), (0x80000008, 3), (0x90000000, 4)]
+    );
+    assert_eq!(
+        merged(MergePolicy::KeepExisting),
+        [(0x80000000, 1), (0x80000008, 2), (0x90000000, 4)]
+    );
+    // Identical chunks don't conflict
+    let mut same = image(&[(0x80000008, 3)]);
+    same.merge_from(&image(&[(0x80000008, 3)]), MergePolicy::PanicOnConflict);
+    assert_eq!(same.iter_chunks().count(), 1);
+}
+
+#[test]
+#[should_panic(expected = "Merged memories disagree on chunk at 0x80000008")]
+fn test_merge_conflict() {
+    let mut mem = image(&[(0x80000008, 2)]);
+    mem.merge_from(&image(&[(0x80000008, 3)]), MergePolicy::PanicOnConflict);
+}
+
+#[test]
+fn test_region_view() {
+    let mut mem: Mem<_, 8> = Mem::new(NoDelay::default());
+    run_one(&mut mem, 0x80000000, Some([1, 2, 3, 4, 5, 6, 7, 8]));
+    run_one(&mut mem, 0x80000010, Some([9; 8]));
+    mem.set_uninit_pattern([0xee; 8]);
+
+    let view = mem.view(0x80000004, 0x10);
+    assert_eq!(view.len(), 0x10);
+    assert_eq!(view[0], 5);
+    let expected = mem
+        .iter_chunks()
+        .flat_map(|(addr, chunk)| (0..8).map(move |idx| (addr + idx, chunk[idx as usize])))
+        .collect::<BTreeMap<_, _>>();
+    for (idx, byte) in view.iter().enumerate() {
+        let addr = 0x80000004 + idx as u64;
+        assert_eq!(byte, *expected.get(&addr).unwrap_or(&0xee), "{:#x}", addr);
+    }
+    assert_eq!(view[0xc], 9);
+}
+
+#[test]
+#[should_panic(expected = "Rebasing chunk at 0x8 by -16 overflows")]
+fn test_rebase_underflow() {
+    let mut mem: Mem<_, 8> = Mem::new(NoDelay::default());
+    run_one(&mut mem, 0x8, Some([1; 8]));
+    mem.rebase(-16);
+}
+
+#[test]
+fn test_sorted_responses() {
+    let read = |id| MemReq::read(id, 0x80000000 + 8 * (id as u64 % 5));
+    let run = |mut mem: Mem<RandomDelay, 8>| {
+        for id in 0..20 {
+            mem.push(read(id));
+        }
+        let mut ids = Vec::new();
+        for _ in 0..50 {
+            mem.tick();
+            while let Some(resp) = mem.pop() {
+                ids.push(resp.id);
+            }
+        }
+        ids
+    };
+
+    let unsorted = run(Mem::new(RandomDelay::new(1, 20, 0)));
+    let sorted = run(Mem::new(RandomDelay::new(1, 20, 0)).with_sorted_responses());
+    assert_eq!(unsorted.len(), 20);
+    assert!(unsorted.windows(2).any(|w| w[0] > w[1]));
+    assert_eq!(sorted, (0..20).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_sized_reads() {
+    let mut mem: Mem<_, 8> = Mem::new(FixedDelay::new(2).with_bytes_per_tick(4));
+    run_one(&mut mem, 0x80000000, Some([1, 2, 3, 4, 5, 6, 7, 8]));
+    let mut read = |size| {
+        mem.push(MemReq {
+            size,
+            ..MemReq::read(size, 0x80000000)
+        });
+        let mut ticks = 0;
+        loop {
+            mem.tick();
+            ticks += 1;
+            if let Some(resp) = mem.pop() {
+                assert_eq!(resp.id, size);
+                break (ticks, resp.rdata);
+            }
+        }
+    };
+    assert_eq!(read(1), (2, [1, 0, 0, 0, 0, 0, 0, 0]));
+    assert_eq!(read(4), (2, [1, 2, 3, 4, 0, 0, 0, 0]));
+    assert_eq!(read(8), (3, [1, 2, 3, 4, 5, 6, 7, 8]));
+}
+
+#[test]
+#[should_panic(expected = "Request 0 has size 9, expected 1 to 8")]
+fn test_oversized_request() {
+    let mut mem: Mem<_, 8> = Mem::new(NoDelay::default());
+    mem.push(MemReq {
+        size: 9,
+        ..MemReq::read(0, 0x80000000)
+    });
+}
+
+#[test]
+fn test_read_capture() {
+    let run = |capture| {
+        let mut mem: Mem<_, 8> = Mem::new(FixedDelay::new(5)).with_read_capture(capture);
+        run_one(&mut mem, 0x80000000, Some([1; 8]));
+        let req = |id, wbe| MemReq::write(id, 0x80000000, [wbe; 8], [2; 8]);
+        mem.push(req(0, false));
+        mem.tick();
+        mem.push(req(1, true));
+        loop {
+            mem.tick();
+            if let Some(resp) = mem.pop() {
+                assert_eq!(resp.id, 0);
+                break resp.rdata;
+            }
+        }
+    };
+    assert_eq!(run(ReadCapture::CaptureAtCompletion), [2; 8]);
+    assert_eq!(run(ReadCapture::CaptureAtIssue), [1; 8]);
+}
+
+#[test]
+fn test_max_reorder_distance() {
+    // Push order of each completed request
+    let run = |sim: RandomDelay| {
+        let mut mem: Mem<_, 8> = Mem::new(sim);
+        for id in 0..200 {
+            mem.push(MemReq::read(id, 0x80000000 + 8 * id as u64));
+        }
+        let mut order = Vec::new();
+        while order.len() < 200 {
+            mem.tick();
+            while let Some(resp) = mem.pop() {
+                order.push(resp.id);
+            }
+        }
+        order
+    };
+    // Largest distance between a completed request and an earlier-pushed incomplete one
+    let max_distance = |order: &[usize]| {
+        let mut done = [false; 200];
+        let mut oldest = 0;
+        let mut max = 0;
+        for &id in order {
+            done[id] = true;
+            while oldest < 200 && done[oldest] {
+                oldest += 1;
+            }
+            if id > oldest {
+                max = max.max(id - oldest);
+            }
+        }
+        max
+    };
+
+    assert!(max_distance(&run(RandomDelay::new(1, 50, 3))) > 4);
+    let limited = run(RandomDelay::new(1, 50, 3).with_max_reorder_distance(4));
+    assert!(max_distance(&limited) <= 4);
+    let in_order = run(RandomDelay::new(1, 50, 3).with_max_reorder_distance(0));
+    assert_eq!(in_order, (0..200).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_data_dependent_delay() {
+    // Zero chunks compress well, anything else takes a tick per non-zero byte on top
+    let latency = |data: &[u8; 8]| 1 + data.iter().filter(|b| **b != 0).count();
+    let mut mem: Mem<_, 8> = Mem::new(DataDependentDelay::new(latency));
+    let ticks = |mem: &mut Mem<_, 8>, addr, wdata: Option<[u8; 8]>| {
+        mem.push(MemReq::write(
+            0,
+            addr,
+            [wdata.is_some(); 8],
+            wdata.unwrap_or_default(),
+        ));
+        let mut ticks = 0;
+        while mem.pop().is_none() {
+            mem.tick();
+            ticks += 1;
+        }
+        ticks
+    };
+
+    assert_eq!(ticks(&mut mem, 0x80000000, None), 1);
+    assert_eq!(ticks(&mut mem, 0x80000008, Some([0xff; 8])), 9);
+    assert_eq!(ticks(&mut mem, 0x80000008, None), 9);
+    assert_eq!(ticks(&mut mem, 0x80000000, Some([0; 8])), 1);
+}
+
+#[test]
+fn test_bank_group_spacing() {
+    let completions = |addrs: &[u64]| {
+        let mut sim = BankGroupModel::new(64, 2, 10, 2, 5);
+        for addr in addrs {
+            sim.push(*addr, false);
+        }
+        let mut done = Vec::new();
+        for t in 0..40 {
+            while sim.pop().is_some() {
+                done.push(t);
+            }
+            sim.tick();
+        }
+        done
+    };
+
+    // Same bank group back to back
+    assert_eq!(completions(&[0x0, 0x80, 0x100, 0x180]), [10, 15, 20, 25]);
+    // Alternating bank groups
+    assert_eq!(completions(&[0x0, 0x40, 0x80, 0xc0]), [10, 12, 14, 16]);
+    // Mixed
+    assert_eq!(completions(&[0x0, 0x80, 0xc0, 0x40]), [10, 15, 17, 22]);
+}
+
+#[test]
+fn test_adaptive_delay() {
+    let mut mem: Mem<_, 8> = Mem::new(AdaptiveDelay::new(2, 3));
+    for id in 0..8 {
+        mem.push(MemReq::read(id, 0x80000000 + 8 * id as u64));
+    }
+
+    let mut latencies = [0; 8];
+    let mut popped = 0;
+    let mut t = 0;
+    while popped < 8 {
+        mem.tick();
+        t += 1;
+        while let Some(resp) = mem.pop() {
+            latencies[resp.id] = t;
+            popped += 1;
+        }
+    }
+    assert_eq!(latencies[0], 2);
+    assert_eq!(latencies[7], 2 + 3 * 7);
+    assert!(latencies.windows(2).all(|w| w[0] < w[1]));
+}
+
+#[test]
+fn test_row_buffer_hits() {
+    let run = |stride: u64| {
+        let mut mem: Mem<_, 64> = Mem::new(SimpleDramModel::new(2048, 4, 2, 10));
+        for id in 0..32 {
+            mem.push(MemReq::read(id, 0x80000000 + stride * id as u64));
+            while mem.pop().is_none() {
+                mem.tick();
+            }
+        }
+        (mem.sim().row_buffer_hits(), mem.sim().row_buffer_misses())
+    };
+
+    // 32 chunks per row, so a sequential sweep opens a single row
+    assert_eq!(run(64), (31, 1));
+    // Every access lands on a different row of the same bank
+    assert_eq!(run(2048 * 4), (0, 32));
+}
+
+#[test]
+fn test_fr_fcfs() {
+    let mut mem: Mem<_, 8> = Mem::new(FrFcfsModel::new(0x100, 1, 2, 10));
+    // Request 2 comes after 1 but hits the row opened by 0
+    for (id, addr) in [(0, 0x80000000), (1, 0x80000100), (2, 0x80000040)] {
+        mem.push(MemReq::read(id, addr));
+    }
+    let mut done = Vec::new();
+    for t in 1..=25 {
+        mem.tick();
+        while let Some(resp) = mem.pop() {
+            done.push((t, resp.id));
+        }
+    }
+    assert_eq!(done, [(10, 0), (12, 2), (22, 1)]);
+    assert_eq!(mem.sim().row_buffer_hits(), 1);
+    assert_eq!(mem.sim().row_buffer_misses(), 2);
+}
+
+#[test]
+fn test_inflight_ids() {
+    let mut mem: Mem<_, 8> = Mem::new(FixedDelay::new(3));
+    for id in [4, 7] {
+        mem.push(MemReq::read(id, 0x80000000));
+        mem.tick();
+    }
+    assert_eq!(mem.inflight_ids(0x80000000), [4, 7]);
+    assert!(mem.inflight_ids(0x80000008).is_empty());
+
+    let mut done = Vec::new();
+    while done.len() < 2 {
+        mem.tick();
+        while let Some(resp) = mem.pop() {
+            done.push(resp.id);
+            assert_eq!(mem.inflight_ids(0x80000000).len(), 2 - done.len());
+        }
+    }
+    assert_eq!(done, [4, 7]);
+}
+
+#[test]
+#[should_panic(expected = "Response buffer overflow: 2 responses not popped")]
+fn test_response_cap_panic() {
+    let mut mem: Mem<_, 8> =
+        Mem::new(FixedDelay::new(1)).with_response_cap(2, OverflowPolicy::Panic);
+    for id in 0..3 {
+        mem.push(MemReq::read(id, 0x80000000 + 8 * id as u64));
+    }
+    mem.tick();
+}
+
+#[test]
+fn test_forwarding_delay() {
+    let mut mem: Mem<_, 8> = Mem::new(FixedDelay::new(1)).with_forwarding_delay(3);
+    let access = |mem: &mut Mem<_, 8>, id, wbe| {
+        mem.push(MemReq::write(id, 0x80000000, [wbe; 8], [0xaa; 8]));
+        mem.tick();
+        mem.pop().unwrap().rdata
+    };
+
+    access(&mut mem, 0, true);
+    // Issued one and two ticks after the write
+    assert_eq!(access(&mut mem, 1, false), [0; 8]);
+    assert_eq!(access(&mut mem, 2, false), [0; 8]);
+    // Three ticks after
+    assert_eq!(access(&mut mem, 3, false), [0xaa; 8]);
+}
+
+#[test]
+fn test_same_tick_order() {
+    let run = |order| {
+        let mut mem: Mem<_, 8> = Mem::new(FixedDelay::new(2)).with_same_tick_order(order);
+        for (id, wbe) in [(0, false), (1, true)] {
+            mem.push(MemReq::write(id, 0x80000000, [wbe; 8], [0xaa; 8]));
+        }
+        let mut resps = Vec::new();
+        while resps.len() < 2 {
+            mem.tick();
+            while let Some(resp) = mem.pop() {
+                resps.push(resp);
+            }
+        }
+        resps.into_iter().find(|resp| resp.id == 0).unwrap().rdata
+    };
+
+    assert_eq!(run(SameTickOrder::WriteFirst), [0xaa; 8]);
+    assert_eq!(run(SameTickOrder::ReadFirst), [0; 8]);
+}
+
+#[test]
+fn test_throughput_sampling() {
+    let mut mem: Mem<_, 8> = Mem::new(FixedDelay::new(3)).with_throughput_sampling(10);
+    let mut completed = 0;
+    for i in 0..100 {
+        mem.push(MemReq::read(i, 0x80000000 + 8 * i as u64));
+        mem.tick();
+        while mem.pop().is_some() {
+            completed += 1;
+        }
+    }
+
+    let series = mem.throughput_series();
+    assert_eq!(series.iter().sum::<u64>(), completed);
+    // Apart from the pipeline fill, every full window sees one completion per tick
+    assert_eq!(series[0], 7);
+    assert_eq!(series[1..10], [10; 9]);
+}
+
+#[test]
+fn test_effective_bandwidth() {
+    let mut mem: Mem<_, 8> = Mem::new(FixedDelay::new(3)).with_bandwidth_history(16);
+    // One full read and one 4-byte read issued every other tick: 6 bytes per tick
+    for i in 0..100 {
+        mem.push(MemReq {
+            size: if i % 2 == 0 { 8 } else { 4 },
+            ..MemReq::read(i, 0x80000000 + 8 * i as u64)
+        });
+        mem.tick();
+        while mem.pop().is_some() {}
+    }
+    assert_eq!(mem.effective_bandwidth(16), 6.0);
+    assert_eq!(mem.effective_bandwidth(2), 6.0);
+    assert_eq!(mem.effective_bandwidth(1), 4.0);
+
+    // Idle ticks lower the bandwidth as earlier completions fall out of the window
+    for _ in 0..8 {
+        mem.tick();
+        while mem.pop().is_some() {}
+    }
+    // Requests 90 to 99 complete in the last 16 ticks
+    assert_eq!(mem.effective_bandwidth(16), 60.0 / 16.0);
+    assert_eq!(mem.effective_bandwidth(4), 0.0);
+}
+
+#[test]
+fn test_per_source_stats() {
+    let mut mem: Mem<_, 8> = Mem::new(SplitRw::new(FixedDelay::new(2), FixedDelay::new(6)));
+    // Source 1 only reads and source 2 only writes
+    for id in 0..10 {
+        let src = 1 + (id % 2) as u16;
+        mem.push(MemReq {
+            src,
+            ..MemReq::write(id, 0x80000000 + 8 * id as u64, [src == 2; 8], [0; 8])
+        });
+    }
+    let mut srcs = Vec::new();
+    for _ in 0..6 {
+        mem.tick();
+        while let Some(resp) = mem.pop() {
+            srcs.push((resp.id, resp.src));
+        }
+    }
+    assert_eq!(srcs.len(), 10);
+    assert!(srcs.iter().all(|(id, src)| *src == 1 + (id % 2) as u16));
+
+    let stats = mem.per_source_stats();
+    assert_eq!(stats.len(), 2);
+    assert_eq!(
+        stats[&1],
+        SourceStats {
+            accesses: 5,
+            average_latency: 2.0
+        }
+    );
+    assert_eq!(
+        stats[&2],
+        SourceStats {
+            accesses: 5,
+            average_latency: 6.0
+        }
+    );
+}
+
+#[test]
+fn test_iter_chunks_order() {
+    use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+    let run = |seed| {
+        let mut addrs: Vec<u64> = (0..64).map(|i| 0x80000000 + 8 * i).collect();
+        addrs.shuffle(&mut StdRng::seed_from_u64(seed));
+
+        let mut mem: Mem<_, 8> = Mem::new(NoDelay::default());
+        for (id, addr) in addrs.into_iter().enumerate() {
+            mem.push(MemReq::write(id, addr, [true; 8], [(addr / 8) as u8; 8]));
+            mem.pop().unwrap();
+        }
+        mem.iter_chunks()
+            .map(|(addr, chunk)| (addr, *chunk))
+            .collect::<Vec<_>>()
+    };
+
+    let first = run(1);
+    assert_eq!(first, run(2));
+    assert!(first.windows(2).all(|w| w[0].0 < w[1].0));
+}
+
+// Timing behind the figures on `Mem::content`, run with
+// `cargo test --release bench_content_map -- --ignored --nocapture`
+#[test]
+#[ignore]
+fn bench_content_map() {
+    use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+    use std::collections::HashMap;
+    use std::time::Instant;
+
+    // Writes then reads every chunk, returning the elapsed seconds
+    fn run<M>(
+        addrs: &[u64],
+        mut insert: impl FnMut(&mut M, u64),
+        mut get: impl FnMut(&M, u64) -> u8,
+    ) -> f64
+    where
+        M: Default,
+    {
+        let mut map = M::default();
+        let start = Instant::now();
+        for addr in addrs {
+            insert(&mut map, *addr);
+        }
+        let mut sum = 0u64;
+        for addr in addrs {
+            sum += get(&map, *addr) as u64;
+        }
+        assert_eq!(sum, addrs.len() as u64);
+        start.elapsed().as_secs_f64()
+    }
+
+    let sequential: Vec<u64> = (0..1 << 20).map(|i| 0x80000000 + 64 * i).collect();
+    let mut random = sequential.clone();
+    random.shuffle(&mut StdRng::seed_from_u64(0));
+    for (pattern, addrs) in [("sequential", &sequential), ("random", &random)] {
+        let btree = run::<BTreeMap<u64, [u8; 64]>>(
+            addrs,
+            |map, addr| {
+                map.insert(addr, [1; 64]);
+            },
+            |map, addr| map[&addr][0],
+        );
+        let hash = run::<HashMap<u64, [u8; 64]>>(
+            addrs,
+            |map, addr| {
+                map.insert(addr, [1; 64]);
+            },
+            |map, addr| map[&addr][0],
+        );
+        println!(
+            "{}: BTreeMap {:.3}s, HashMap {:.3}s, {:.2}x",
+            pattern,
+            btree,
+            hash,
+            btree / hash
+        );
+    }
+}
+
+#[cfg(test)]
+struct SlowWrites {
+    tick: usize,
+    queue: Vec<(usize, u64)>,
+}
+
+#[cfg(test)]
+impl DelaySimulator for SlowWrites {
+    fn tick(&mut self) {
+        self.tick += 1;
+    }
+
+    fn push(&mut self, addr: u64, is_write: bool) {
+        let latency = if is_write { 10 } else { 1 };
+        self.queue.push((self.tick + latency, addr));
+    }
+
+    fn pop(&mut self) -> Option<u64> {
+        let pos = self.queue.iter().position(|(t, _)| *t <= self.tick)?;
+        Some(self.queue.remove(pos).1)
+    }
+}
+
+#[test]
+fn test_fence() {
+    let mut mem: Mem<_, 8> = Mem::new(SlowWrites {
+        tick: 0,
+        queue: Vec::new(),
+    });
+    mem.push(MemReq::write(0, 0x80000000, [true; 8], [9; 8]));
+    mem.fence(1);
+    mem.push(MemReq::read(2, 0x80000000));
+    // Unrelated read, which would otherwise overtake the write
+    mem.push(MemReq::read(3, 0x80000040));
+
+    let mut order = Vec::new();
+    for _ in 0..20 {
+        mem.tick();
+        while let Some(resp) = mem.pop() {
+            if resp.id == 2 {
+                assert_eq!(resp.rdata, [9; 8]);
+            }
+            order.push(resp.id);
+        }
+    }
+    assert_eq!(order[..2], [0, 1]);
+    assert_eq!(order.len(), 4);
+}
+
+#[cfg(test)]
+struct Blackhole {
+    inner: NoDelay,
+    dropped: u64,
+}
+
+#[cfg(test)]
+impl DelaySimulator for Blackhole {
+    fn tick(&mut self) {}
+
+    fn push(&mut self, addr: u64, is_write: bool) {
+        if addr != self.dropped {
+            self.inner.push(addr, is_write);
+        }
+    }
+
+    fn pop(&mut self) -> Option<u64> {
+        self.inner.pop()
+    }
+}
+
+// Runs three reads for 8 ticks with a timeout of 8, the second of which is never answered.
+// Returns the memory along with the number of responses popped.
+#[cfg(test)]
+fn run_blackholed() -> (Mem<Blackhole, 8>, usize) {
+    let mut mem = Mem::new(Blackhole {
+        inner: NoDelay::default(),
+        dropped: 0x80000040,
+    })
+    .with_timeout(8);
+    for id in 0..3 {
+        mem.push(MemReq::read(id, 0x80000000 + 0x40 * id as u64));
+    }
+    let mut popped = 0;
+    for _ in 0..8 {
+        mem.tick();
+        while mem.pop().is_some() {
+            popped += 1;
+        }
+    }
+    (mem, popped)
+}
+
+#[test]
+fn test_timeout_in_time() {
+    assert_eq!(run_blackholed().1, 2);
+}
+
+#[test]
+#[should_panic(expected = "Request 1 to 0x80000040 timed out after 8 ticks")]
+fn test_timeout() {
+    run_blackholed().0.tick();
+}
+
+#[test]
+fn test_cancel() {
+    let mut mem: Mem<_, 8> = Mem::new(FixedDelay::new(3));
+    for id in 0..3 {
+        mem.push(MemReq::read(id, 0x80000000 + 8 * id as u64));
+    }
+    assert!(mem.cancel(1));
+    assert!(!mem.cancel(1));
+    assert!(!mem.cancel(42));
+
+    let mut seen = Vec::new();
+    for _ in 0..10 {
+        mem.tick();
+        while let Some(resp) = mem.pop() {
+            seen.push(resp.id);
+        }
+    }
+    assert_eq!(seen, vec![0, 2]);
+}
+
+#[test]
+fn test_uninit_pattern() {
+    let mut mem: Mem<_, 8> = Mem::new(NoDelay::default());
+    mem.set_uninit_pattern([0xde, 0xad, 0xbe, 0xef, 0xde, 0xad, 0xbe, 0xef]);
+    mem.push(MemReq::read(0, 0x80000000));
+    let resp = mem.pop().unwrap();
+    assert_eq!(resp.rdata, [0xde, 0xad, 0xbe, 0xef, 0xde, 0xad, 0xbe, 0xef]);
+
+    mem.push(MemReq::write(
+        1,
+        0x80000008,
+        [true, true, false, false, false, false, false, false],
+        [0; 8],
+    ));
+    mem.pop().unwrap();
+    mem.push(MemReq::read(2, 0x80000008));
+    let resp = mem.pop().unwrap();
+    assert_eq!(resp.rdata, [0, 0, 0xbe, 0xef, 0xde, 0xad, 0xbe, 0xef]);
+}
+
+#[test]
+fn test_masked_read() {
+    let mut mem: Mem<_, 8> = Mem::new(NoDelay::default());
+    mem.push(MemReq::write(
+        0,
+        0x80000000,
+        [true; 8],
+        [1, 2, 3, 4, 5, 6, 7, 8],
+    ));
+    assert!(mem.pop().is_some());
+
+    let rbe = [false, true, true, false, false, false, false, true];
+    mem.push(MemReq {
+        rbe: Some(rbe),
+        ..MemReq::read(1, 0x80000000)
+    });
+    let resp = mem.pop().unwrap();
+    for (i, be) in rbe.iter().enumerate() {
+        if *be {
+            assert_eq!(resp.rdata[i], i as u8 + 1);
+        }
+    }
+}
+
+#[test]
+fn test_two_phase_tick() {
+    let run = |pop_first: bool| {
+        let mut mem: Mem<_, 8> = Mem::new(NoDelay::default());
+        mem.push(MemReq::write(0, 0x80000000, [true; 8], [1; 8]));
+        mem.tick();
+        assert!(mem.pop().is_some());
+
+        mem.push(MemReq::read(1, 0x80000000));
+        mem.tick_begin();
+        mem.tick_end();
+
+        let write = MemReq::write(2, 0x80000000, [true; 8], [2; 8]);
+        let resp = if pop_first {
+            let resp = mem.pop().unwrap();
+            mem.push(write);
+            resp
+        } else {
+            mem.push(write);
+            mem.pop().unwrap()
+        };
+        assert_eq!(resp.id, 1);
+        resp.rdata
+    };
+
+    assert_eq!(run(true), [1; 8]);
+    assert_eq!(run(false), [1; 8]);
+}
+
+#[cfg(test)]
+pub(crate) fn test_simple<D: DelaySimulator>(mut mem: Mem<D, 256>, wait: usize) {
+    use rand::Rng;
+
+    let mut rng = rand::thread_rng();
+    let mut buf = [0; 256];
+    rng.fill(&mut buf);
+
+    mem.tick();
+    mem.push(MemReq::write(1, 0x80004000, [true; 256], buf));
+
+    let mut done = false;
+    for t in 0..wait {
+        if let Some(r) = mem.pop() {
+            assert_eq!(r.id, 1);
+            done = true;
+            println!("Done at tick {}", t);
+            break;
+        }
+        mem.tick();
+    }
+    assert!(done);
+
+    mem.tick();
+    mem.push(MemReq::read(2, 0x80004000));
 
     let mut done = false;
     for t in 0..wait {
@@ -0,0 +1,227 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::OpenOptions,
+    io,
+    os::fd::AsRawFd,
+    path::Path,
+    ptr, slice,
+};
+
+use crate::drain::{Access, Drain, ProbeResult};
+use crate::mem::{DelaySimulator, MemReq, MemResp};
+
+// (id, read byte-enables, size, src)
+type Inflight<const WIDTH: usize> = (usize, Option<[bool; WIDTH]>, usize, u16);
+
+/// Like `Mem`, but backed by a memory-mapped file covering `[base, base + size)`, so that large
+/// memories are paged in on demand and persist on disk. Requests must be `WIDTH`-aligned.
+pub struct MmapMem<D: DelaySimulator, const WIDTH: usize> {
+    sim: D,
+    base: u64,
+    map: *mut u8,
+    len: usize,
+    inflights: BTreeMap<u64, VecDeque<Inflight<WIDTH>>>,
+}
+
+impl<D: DelaySimulator, const WIDTH: usize> MmapMem<D, WIDTH> {
+    /// Maps `path`, creating it or extending it to `size` bytes as needed. Extended parts of the
+    /// file are sparse and read as zero.
+    pub fn new<P: AsRef<Path>>(sim: D, path: P, base: u64, size: usize) -> io::Result<Self> {
+        assert!(
+            size > 0 && size.is_multiple_of(WIDTH),
+            "Mapping size must be a positive multiple of WIDTH"
+        );
+        assert!(
+            base.is_multiple_of(WIDTH as u64),
+            "Mapping base must be WIDTH-aligned"
+        );
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        if file.metadata()?.len() < size as u64 {
+            file.set_len(size as u64)?;
+        }
+        // The mapping stays valid after the file is closed
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(MmapMem {
+            sim,
+            base,
+            map: map as *mut u8,
+            len: size,
+            inflights: BTreeMap::new(),
+        })
+    }
+
+    /// Writes back all modified pages to the file
+    pub fn flush(&self) -> io::Result<()> {
+        if unsafe { libc::msync(self.map as *mut libc::c_void, self.len, libc::MS_SYNC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.base && addr - self.base < self.len as u64
+    }
+
+    fn chunk(&mut self, addr: u64) -> &mut [u8] {
+        let offset = (addr - self.base) as usize;
+        // Safety: the mapping is `len` bytes long and only accessed through `&mut self`
+        let bytes = unsafe { slice::from_raw_parts_mut(self.map, self.len) };
+        &mut bytes[offset..offset + WIDTH]
+    }
+}
+
+impl<D: DelaySimulator, const WIDTH: usize> Drop for MmapMem<D, WIDTH> {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.map as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+impl<D: DelaySimulator, const WIDTH: usize> Drain for MmapMem<D, WIDTH> {
+    type Req = MemReq<WIDTH>;
+    type Resp = MemResp<WIDTH>;
+
+    fn tick(&mut self) {
+        self.sim.tick();
+    }
+
+    fn push(&mut self, req: MemReq<WIDTH>) {
+        if !self.contains(req.addr) {
+            panic!("Out-of-range request address: {:#x}", req.addr);
+        }
+        if !req.addr.is_multiple_of(WIDTH as u64) {
+            panic!("Unaligned request address: {:#x}", req.addr);
+        }
+        if req.size == 0 || req.size > WIDTH {
+            panic!(
+                "Request {} has size {}, expected 1 to {}",
+                req.id, req.size, WIDTH
+            );
+        }
+        self.inflights
+            .entry(req.addr)
+            .or_default()
+            .push_back((req.id, req.rbe, req.size, req.src));
+        self.sim.push_sized(req.addr, req.is_write(), req.size);
+        // Only touch the mapping for writes, so untouched pages of a sparse file stay unallocated
+        if req.is_write() {
+            let chunk = self.chunk(req.addr);
+            for (c, (w, be)) in chunk.iter_mut().zip(req.wdata.iter().zip(req.wbe.iter())) {
+                if *be {
+                    *c = *w;
+                }
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<MemResp<WIDTH>> {
+        let addr = self.sim.pop()?;
+        let queue = self
+            .inflights
+            .get_mut(&addr)
+            .expect("Unexpected memory response");
+        let (id, rbe, size, src) = queue.pop_front().unwrap();
+        if queue.is_empty() {
+            self.inflights.remove(&addr);
+        }
+        let mut rdata = [0; WIDTH];
+        rdata.copy_from_slice(self.chunk(addr));
+        if let Some(rbe) = rbe {
+            for (r, be) in rdata.iter_mut().zip(rbe.iter()) {
+                if !*be {
+                    *r = 0;
+                }
+            }
+        }
+        rdata[size..].fill(0);
+        Some(MemResp {
+            id,
+            rdata,
+            beat: 0,
+            last: true,
+            src,
+        })
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        if !self.contains(addr) || !addr.is_multiple_of(WIDTH as u64) {
+            return ProbeResult::NotPresent;
+        }
+        let offset = (addr - self.base) as usize;
+        // Safety: same as `chunk`, and `&self` rules out concurrent writes
+        let bytes = unsafe { slice::from_raw_parts(self.map.add(offset), WIDTH) };
+        ProbeResult::Present {
+            data: bytes.to_vec(),
+            inflight: self.inflights.get(&addr).map_or(0, VecDeque::len),
+        }
+    }
+}
+
+#[test]
+fn test_mmap_persistence() {
+    use crate::mem::FixedDelay;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("mem.bin");
+    let run = |mem: &mut MmapMem<FixedDelay, 8>, req: MemReq<8>| {
+        mem.push(req);
+        loop {
+            mem.tick();
+            if let Some(resp) = mem.pop() {
+                break resp.rdata;
+            }
+        }
+    };
+
+    let mut mem = MmapMem::new(FixedDelay::new(2), &path, 0x80000000, 1 << 20).unwrap();
+    let mut wbe = [true; 8];
+    wbe[0] = false;
+    run(
+        &mut mem,
+        MemReq::write(0, 0x80080008, wbe, [1, 2, 3, 4, 5, 6, 7, 8]),
+    );
+    let read = MemReq::read(1, 0x80080008);
+    assert_eq!(run(&mut mem, read.clone()), [0, 2, 3, 4, 5, 6, 7, 8]);
+    mem.flush().unwrap();
+    drop(mem);
+
+    let file = std::fs::read(&path).unwrap();
+    assert_eq!(file.len(), 1 << 20);
+    assert_eq!(file[0x80008..0x80010], [0, 2, 3, 4, 5, 6, 7, 8]);
+
+    // Remapping the existing file sees the earlier write
+    let mut mem = MmapMem::new(FixedDelay::new(2), &path, 0x80000000, 1 << 20).unwrap();
+    assert_eq!(run(&mut mem, read), [0, 2, 3, 4, 5, 6, 7, 8]);
+}
+
+#[test]
+#[should_panic(expected = "Mapping base must be WIDTH-aligned")]
+fn test_mmap_unaligned_base() {
+    use crate::mem::FixedDelay;
+
+    let dir = tempfile::tempdir().unwrap();
+    let _ = MmapMem::<_, 8>::new(
+        FixedDelay::new(2),
+        dir.path().join("mem.bin"),
+        0x80000004,
+        4096,
+    );
+}
@@ -0,0 +1,55 @@
+use alloc::boxed::Box;
+
+use crate::crossbar::Crossbar;
+use crate::mem::{DelaySimulator, Mem, MemReq, MemResp, NoDelay};
+
+pub type VirtCrossbar = Crossbar<u64, MemReq<8>, MemResp<8>>;
+
+/// Memory map of QEMU's RISC-V `virt` machine with `dram_size` bytes of DRAM at `0x80000000`.
+/// DRAM is timed by the simulator `dram` builds; the device regions are plain zero-latency
+/// memories standing in for the devices.
+pub fn riscv_virt<D: DelaySimulator + 'static>(
+    dram_size: u64,
+    dram: impl FnOnce() -> D,
+) -> VirtCrossbar {
+    let device = || Box::new(Mem::<_, 8>::new(NoDelay::default()));
+    Crossbar::new()
+        .with_named("mrom", 0x1000, 0x11000, device())
+        .with_named("clint", 0x2000000, 0x2010000, device())
+        .with_named("plic", 0xc000000, 0x10000000, device())
+        .with_named("uart0", 0x10000000, 0x10000100, device())
+        .with_named(
+            "dram",
+            0x80000000,
+            0x80000000 + dram_size,
+            Box::new(Mem::<_, 8>::new(dram())),
+        )
+}
+
+#[test]
+fn test_riscv_virt() {
+    use crate::drain::Drain;
+    use crate::mem::FixedDelay;
+
+    let latency = |crossbar: &mut VirtCrossbar, addr| {
+        crossbar.push(MemReq::read(0, addr));
+        let mut ticks = 0;
+        while crossbar.pop().is_none() {
+            crossbar.tick();
+            ticks += 1;
+        }
+        ticks
+    };
+
+    let mut untimed = riscv_virt(0x8000000, NoDelay::default);
+    let mut timed = riscv_virt(0x8000000, || FixedDelay::new(7));
+    for crossbar in [&untimed, &timed] {
+        let region = |addr| crossbar.region_of(addr).map(|id| crossbar.name(id));
+        assert_eq!(region(0x10000000), Some("uart0"));
+        assert_eq!(region(0x87fffff8), Some("dram"));
+        assert_eq!(region(0x88000000), None);
+    }
+    assert_eq!(latency(&mut untimed, 0x80001000), 0);
+    assert_eq!(latency(&mut timed, 0x80001000), 7);
+    assert_eq!(latency(&mut timed, 0x2000000), 0);
+}
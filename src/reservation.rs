@@ -0,0 +1,222 @@
+use alloc::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    rc::Rc,
+    string::String,
+};
+use core::cell::RefCell;
+
+use crate::drain::{Access, Drain, ProbeResult};
+use crate::mem::{MemReq, MemResp};
+
+/// LR/SC reservations of all cores, shared between their `Reserving` front-ends. Each core holds
+/// at most one reservation, on a `granule`-byte aligned block.
+#[derive(Clone)]
+pub struct ReservationTable {
+    granule: u64,
+    // core -> reserved granule
+    reservations: Rc<RefCell<BTreeMap<usize, u64>>>,
+}
+
+impl ReservationTable {
+    pub fn new(granule: u64) -> ReservationTable {
+        assert!(granule > 0, "granule must be positive");
+        ReservationTable {
+            granule,
+            reservations: Default::default(),
+        }
+    }
+
+    pub fn is_reserved(&self, core: usize, addr: u64) -> bool {
+        self.reservations.borrow().get(&core) == Some(&(addr / self.granule))
+    }
+
+    fn reserve(&self, core: usize, addr: u64) {
+        self.reservations
+            .borrow_mut()
+            .insert(core, addr / self.granule);
+    }
+
+    /// Drops every reservation on a granule overlapping `[addr, addr + len)`
+    fn invalidate(&self, addr: u64, len: usize) {
+        let first = addr / self.granule;
+        let last = (addr + len as u64 - 1) / self.granule;
+        self.reservations
+            .borrow_mut()
+            .retain(|_, granule| *granule < first || *granule > last);
+    }
+
+    fn release(&self, core: usize) {
+        self.reservations.borrow_mut().remove(&core);
+    }
+}
+
+/// One core's port to memory, tracking its LR/SC reservation in a shared `ReservationTable`.
+/// Plain writes pushed through any core's front-end invalidate conflicting reservations as they
+/// are pushed.
+pub struct Reserving<D: Drain, const WIDTH: usize> {
+    inner: D,
+    core: usize,
+    table: ReservationTable,
+
+    // Successful SCs in flight, whose rdata is replaced by the status
+    conditionals: BTreeSet<usize>,
+    failed: VecDeque<MemResp<WIDTH>>,
+}
+
+impl<D, const WIDTH: usize> Reserving<D, WIDTH>
+where
+    D: Drain<Req = MemReq<WIDTH>, Resp = MemResp<WIDTH>>,
+{
+    pub fn new(inner: D, core: usize, table: ReservationTable) -> Self {
+        Reserving {
+            inner,
+            core,
+            table,
+            conditionals: BTreeSet::new(),
+            failed: VecDeque::new(),
+        }
+    }
+
+    /// Reads `req.addr` and reserves its granule, replacing any earlier reservation of this core
+    pub fn load_reserved(&mut self, req: MemReq<WIDTH>) {
+        assert!(
+            !req.is_write(),
+            "Load-reserved request {} is a write",
+            req.id
+        );
+        self.table.reserve(self.core, req.addr);
+        self.inner.push(req);
+    }
+
+    /// Performs the write only if this core still holds a reservation on the granule of
+    /// `req.addr`. Either way the reservation is released. The response carries the status in
+    /// `rdata[0]`: 0 on success and 1 on failure, with the rest of `rdata` zeroed.
+    pub fn store_conditional(&mut self, req: MemReq<WIDTH>) {
+        let reserved = self.table.is_reserved(self.core, req.addr);
+        self.table.release(self.core);
+        if !reserved {
+            let mut rdata = [0; WIDTH];
+            rdata[0] = 1;
+            self.failed.push_back(MemResp {
+                id: req.id,
+                rdata,
+                beat: 0,
+                last: true,
+                src: req.src,
+            });
+            return;
+        }
+        self.table.invalidate(req.addr, WIDTH);
+        self.conditionals.insert(req.id);
+        self.inner.push(req);
+    }
+}
+
+impl<D, const WIDTH: usize> Drain for Reserving<D, WIDTH>
+where
+    D: Drain<Req = MemReq<WIDTH>, Resp = MemResp<WIDTH>>,
+{
+    type Req = MemReq<WIDTH>;
+    type Resp = MemResp<WIDTH>;
+
+    fn tick(&mut self) {
+        self.inner.tick();
+    }
+
+    fn tick_begin(&mut self) {
+        self.inner.tick_begin();
+    }
+
+    fn tick_end(&mut self) {
+        self.inner.tick_end();
+    }
+
+    fn push(&mut self, req: MemReq<WIDTH>) {
+        if req.is_write() {
+            self.table.invalidate(req.addr, WIDTH);
+        }
+        self.inner.push(req);
+    }
+
+    fn pop(&mut self) -> Option<MemResp<WIDTH>> {
+        if let Some(resp) = self.failed.pop_front() {
+            return Some(resp);
+        }
+        let mut resp = self.inner.pop()?;
+        if self.conditionals.remove(&resp.id) {
+            resp.rdata = [0; WIDTH];
+        }
+        Some(resp)
+    }
+
+    fn cancel(&mut self, id: usize) -> bool {
+        if let Some(pos) = self.failed.iter().position(|resp| resp.id == id) {
+            self.failed.remove(pos);
+            return true;
+        }
+        let cancelled = self.inner.cancel(id);
+        if cancelled {
+            self.conditionals.remove(&id);
+        }
+        cancelled
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        self.inner.probe(addr)
+    }
+
+    fn kind_name(&self) -> &'static str {
+        self.inner.kind_name()
+    }
+
+    fn write_dot(&self, node: &str, out: &mut String) {
+        self.inner.write_dot(node, out);
+    }
+}
+
+#[cfg(test)]
+fn wait<D: Drain<Resp = MemResp<8>>>(drain: &mut D) -> MemResp<8> {
+    loop {
+        if let Some(resp) = drain.pop() {
+            break resp;
+        }
+        drain.tick();
+    }
+}
+
+#[test]
+fn test_cross_core_invalidation() {
+    use crate::mem::*;
+    let req = |id, addr, wbe| MemReq::write(id, addr, [wbe; 8], [id as u8; 8]);
+
+    let table = ReservationTable::new(64);
+    // Reservations don't depend on the backing memory, so each core gets its own
+    let mut a = Reserving::new(Mem::<_, 8>::new(FixedDelay::new(2)), 0, table.clone());
+    let mut b = Reserving::new(Mem::<_, 8>::new(FixedDelay::new(2)), 1, table.clone());
+
+    // Uncontended LR/SC succeeds
+    a.load_reserved(req(0, 0x80000000, false));
+    wait(&mut a);
+    a.store_conditional(req(1, 0x80000008, true));
+    assert_eq!(wait(&mut a).rdata, [0; 8]);
+    assert!(!table.is_reserved(0, 0x80000000));
+
+    // A write from core B to another chunk in the same granule breaks A's reservation
+    a.load_reserved(req(2, 0x80000000, false));
+    wait(&mut a);
+    b.load_reserved(req(3, 0x80000100, false));
+    wait(&mut b);
+    b.push(req(4, 0x80000038, true));
+    wait(&mut b);
+    assert!(!table.is_reserved(0, 0x80000000));
+    assert!(table.is_reserved(1, 0x80000100));
+    a.store_conditional(req(5, 0x80000000, true));
+    let resp = wait(&mut a);
+    assert_eq!(resp.id, 5);
+    assert_eq!(resp.rdata, [1, 0, 0, 0, 0, 0, 0, 0]);
+    // Only the successful SC wrote
+    a.push(req(6, 0x80000008, false));
+    assert_eq!(wait(&mut a).rdata, [1; 8]);
+    a.push(req(7, 0x80000000, false));
+    assert_eq!(wait(&mut a).rdata, [0; 8]);
+}
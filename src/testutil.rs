@@ -0,0 +1,432 @@
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    vec::Vec,
+};
+use core::fmt::{Debug, Display};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::drain::{Drain, Identified};
+use crate::mem::{MemReq, MemResp};
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Incomplete {
+    pub outstanding: usize,
+    pub max_ticks: usize,
+}
+
+impl Display for Incomplete {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{} request(s) still outstanding after {} ticks",
+            self.outstanding, self.max_ticks
+        )
+    }
+}
+
+impl core::error::Error for Incomplete {}
+
+/// Pushes all requests at once and ticks until every one of them has a response. Returns the
+/// number of ticks taken, or an error if that exceeds `max_ticks`.
+pub fn assert_completes_within<D: Drain>(
+    drain: &mut D,
+    requests: Vec<D::Req>,
+    max_ticks: usize,
+) -> Result<usize, Incomplete> {
+    let mut outstanding = requests.len();
+    for req in requests {
+        drain.push(req);
+    }
+
+    let mut ticks = 0;
+    loop {
+        while outstanding > 0 && drain.pop().is_some() {
+            outstanding -= 1;
+        }
+        if outstanding == 0 {
+            return Ok(ticks);
+        }
+        if ticks == max_ticks {
+            return Err(Incomplete {
+                outstanding,
+                max_ticks,
+            });
+        }
+        drain.tick();
+        ticks += 1;
+    }
+}
+
+/// Drives `a` and `b` with the same `workload` of `(tick, request)` pairs, sorted by tick, and
+/// panics unless every request completes at the same tick with the same response on both, all
+/// within `max_ticks` ticks.
+pub fn assert_timing_equivalent<A, B>(
+    a: &mut A,
+    b: &mut B,
+    workload: &[(usize, A::Req)],
+    max_ticks: usize,
+) where
+    A: Drain,
+    B: Drain<Req = A::Req, Resp = A::Resp>,
+    A::Req: Clone + Identified,
+    A::Resp: Identified + PartialEq + Debug,
+{
+    assert!(
+        workload.is_sorted_by_key(|(tick, _)| *tick),
+        "Workload is not sorted by tick"
+    );
+    // Completion tick and response of each request, per drain
+    let mut done: [BTreeMap<usize, (usize, A::Resp)>; 2] = Default::default();
+    let mut next = 0;
+    for tick in 0..=max_ticks {
+        while next < workload.len() && workload[next].0 == tick {
+            a.push(workload[next].1.clone());
+            b.push(workload[next].1.clone());
+            next += 1;
+        }
+        while let Some(resp) = a.pop() {
+            done[0].insert(resp.id(), (tick, resp));
+        }
+        while let Some(resp) = b.pop() {
+            done[1].insert(resp.id(), (tick, resp));
+        }
+        if next == workload.len() && done.iter().all(|d| d.len() == workload.len()) {
+            break;
+        }
+        a.tick();
+        b.tick();
+    }
+
+    for (_, req) in workload {
+        let id = req.id();
+        let (a_done, b_done) = (done[0].get(&id), done[1].get(&id));
+        let (Some((a_tick, a_resp)), Some((b_tick, b_resp))) = (a_done, b_done) else {
+            panic!(
+                "Request {} incomplete after {} ticks: completed at {:?} on a and {:?} on b",
+                id,
+                max_ticks,
+                a_done.map(|(tick, _)| tick),
+                b_done.map(|(tick, _)| tick)
+            );
+        };
+        if a_tick != b_tick {
+            panic!(
+                "Request {} completed at tick {} on a but {} on b",
+                id, a_tick, b_tick
+            );
+        }
+        if a_resp != b_resp {
+            panic!(
+                "Request {} returned {:?} on a but {:?} on b",
+                id, a_resp, b_resp
+            );
+        }
+    }
+}
+
+/// Drives `drain` with `count` random reads and writes to `WIDTH`-aligned addresses within
+/// `regions` (as `[start, end)` pairs), keeping up to `max_outstanding` in flight but never two
+/// to the same address. Read data is checked against a shadow copy of memory, initially zero.
+/// Panics on a mismatch or when the drain stops responding, with `seed` in the message so that the
+/// run can be reproduced.
+pub fn stress<D, const WIDTH: usize>(
+    drain: &mut D,
+    regions: &[(u64, u64)],
+    count: usize,
+    max_outstanding: usize,
+    seed: u64,
+) where
+    D: Drain<Req = MemReq<WIDTH>, Resp = MemResp<WIDTH>>,
+{
+    const STALL_TICKS: usize = 10000;
+    assert!(!regions.is_empty() && max_outstanding > 0);
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut shadow: BTreeMap<u64, [u8; WIDTH]> = BTreeMap::new();
+    // id -> (addr, expected data for reads)
+    let mut outstanding: BTreeMap<usize, (u64, Option<[u8; WIDTH]>)> = BTreeMap::new();
+    let mut busy = BTreeSet::new();
+    let mut issued = 0;
+    let mut idle = 0;
+
+    while issued < count || !outstanding.is_empty() {
+        while issued < count && outstanding.len() < max_outstanding {
+            let (start, end) = regions[rng.gen_range(0..regions.len())];
+            let first = start.div_ceil(WIDTH as u64);
+            let last = end / WIDTH as u64;
+            assert!(
+                first < last,
+                "Region {:#x}..{:#x} holds no chunk",
+                start,
+                end
+            );
+            let addr = rng.gen_range(first..last) * WIDTH as u64;
+            if !busy.insert(addr) {
+                break;
+            }
+
+            let mut wbe = [false; WIDTH];
+            let mut wdata = [0; WIDTH];
+            let expected = if rng.gen() {
+                let chunk = shadow.entry(addr).or_insert([0; WIDTH]);
+                rng.fill(&mut wdata[..]);
+                for ((be, w), c) in wbe.iter_mut().zip(wdata.iter()).zip(chunk.iter_mut()) {
+                    *be = rng.gen();
+                    if *be {
+                        *c = *w;
+                    }
+                }
+                None
+            } else {
+                Some(shadow.get(&addr).cloned().unwrap_or([0; WIDTH]))
+            };
+            outstanding.insert(issued, (addr, expected));
+            drain.push(MemReq::write(issued, addr, wbe, wdata));
+            issued += 1;
+        }
+
+        drain.tick();
+        idle += 1;
+        while let Some(resp) = drain.pop() {
+            idle = 0;
+            let (addr, expected) = outstanding
+                .remove(&resp.id)
+                .unwrap_or_else(|| panic!("Seed {}: unexpected response {}", seed, resp.id));
+            busy.remove(&addr);
+            if let Some(expected) = expected {
+                if resp.rdata != expected {
+                    panic!(
+                        "Seed {}: read {} at {:#x} returned {:?}, expected {:?}",
+                        seed, resp.id, addr, resp.rdata, expected
+                    );
+                }
+            }
+        }
+        if idle == STALL_TICKS {
+            panic!(
+                "Seed {}: no response in {} ticks with {} request(s) outstanding",
+                seed,
+                STALL_TICKS,
+                outstanding.len()
+            );
+        }
+    }
+}
+
+/// Issues `offered_rate` reads per tick on average to consecutive `WIDTH`-aligned addresses in
+/// `[start, end)`, wrapping around, and returns the average latency in ticks of the requests
+/// pushed during the `measure` ticks following the first `warmup` ticks. Panics if the rate is
+/// too low for any request to be pushed in that window.
+pub fn measure_steady_latency<D, const WIDTH: usize>(
+    drain: &mut D,
+    (start, end): (u64, u64),
+    offered_rate: f64,
+    warmup: usize,
+    measure: usize,
+) -> f64
+where
+    D: Drain<Req = MemReq<WIDTH>, Resp = MemResp<WIDTH>>,
+{
+    const STALL_TICKS: usize = 10000;
+    assert!(offered_rate > 0.0 && measure > 0);
+    let first = start.div_ceil(WIDTH as u64);
+    let last = end / WIDTH as u64;
+    assert!(
+        first < last,
+        "Region {:#x}..{:#x} holds no chunk",
+        start,
+        end
+    );
+
+    // id -> push tick, for requests pushed during the measurement
+    let mut measured = BTreeMap::new();
+    let mut total = 0;
+    let mut count = 0;
+    let mut credit = 0.0;
+    let mut id = 0;
+    let mut tick = 0;
+    let mut idle = 0;
+    while tick < warmup + measure || !measured.is_empty() {
+        if tick < warmup + measure {
+            credit += offered_rate;
+            while credit >= 1.0 {
+                credit -= 1.0;
+                if tick >= warmup {
+                    measured.insert(id, tick);
+                }
+                let chunk = first + (id as u64 % (last - first));
+                drain.push(MemReq::read(id, chunk * WIDTH as u64));
+                id += 1;
+            }
+        }
+        drain.tick();
+        tick += 1;
+        idle += 1;
+        while let Some(resp) = drain.pop() {
+            idle = 0;
+            if let Some(pushed) = measured.remove(&resp.id) {
+                total += tick - pushed;
+                count += 1;
+            }
+        }
+        if idle == STALL_TICKS {
+            panic!(
+                "No response in {} ticks with {} measured request(s) outstanding",
+                STALL_TICKS,
+                measured.len()
+            );
+        }
+    }
+    if count == 0 {
+        panic!(
+            "No request pushed in the {} measured ticks at {} per tick",
+            measure, offered_rate
+        );
+    }
+    total as f64 / count as f64
+}
+
+#[test]
+fn test_completes_within() {
+    use crate::mem::*;
+    let read = || vec![MemReq::read(0, 0x80000000)];
+
+    let mut mem: Mem<_, 8> = Mem::new(FixedDelay::new(3));
+    assert_eq!(assert_completes_within(&mut mem, read(), 4), Ok(3));
+
+    let mut mem: Mem<_, 8> = Mem::new(FixedDelay::new(3));
+    assert_eq!(
+        assert_completes_within(&mut mem, read(), 2),
+        Err(Incomplete {
+            outstanding: 1,
+            max_ticks: 2
+        })
+    );
+}
+
+#[test]
+fn test_stress_crossbar() {
+    use crate::crossbar::Crossbar;
+    use crate::drain::Delay;
+    use crate::mem::*;
+
+    for seed in 0..4 {
+        let mem = |latency| Mem::<_, 8>::new(FixedDelay::new(latency));
+        let mut crossbar = Crossbar::new()
+            .with(0x80000000, 0x80000400, Box::new(Delay::new(mem(2), 3, 1)))
+            .with_offset(0x90000000, 0x90000100, 0x90000000, Box::new(mem(5)))
+            .with(0xa0000000, 0xa0000040, Box::new(mem(0)));
+        stress(
+            &mut crossbar,
+            &[
+                (0x80000000, 0x80000400),
+                (0x90000000, 0x90000100),
+                (0xa0000000, 0xa0000040),
+            ],
+            2000,
+            8,
+            seed,
+        );
+    }
+}
+
+#[cfg(test)]
+fn mixed_workload() -> Vec<(usize, MemReq<8>)> {
+    (0..64)
+        .map(|id| {
+            let write = id % 3 == 0;
+            (
+                id / 2,
+                MemReq::write(
+                    id,
+                    0x80000000 + 8 * (id as u64 % 5),
+                    [write; 8],
+                    [id as u8; 8],
+                ),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn test_timing_equivalent() {
+    use crate::drain::Delay;
+    use crate::mem::*;
+
+    // Behind the delay, reads see the contents as of when they were pushed on top
+    let mut delayed = Delay::new(Mem::<_, 8>::new(NoDelay::default()), 2, 3);
+    let mut fixed =
+        Mem::<_, 8>::new(FixedDelay::new(5)).with_read_capture(ReadCapture::CaptureAtIssue);
+    assert_timing_equivalent(&mut delayed, &mut fixed, &mixed_workload(), 100);
+}
+
+#[test]
+#[should_panic(expected = "Request 0 completed at tick 5 on a but 6 on b")]
+fn test_timing_divergence() {
+    use crate::drain::Delay;
+    use crate::mem::*;
+
+    let mut delayed = Delay::new(Mem::<_, 8>::new(NoDelay::default()), 2, 3);
+    let mut fixed =
+        Mem::<_, 8>::new(FixedDelay::new(6)).with_read_capture(ReadCapture::CaptureAtIssue);
+    assert_timing_equivalent(&mut delayed, &mut fixed, &mixed_workload(), 100);
+}
+
+#[test]
+fn test_steady_latency() {
+    use crate::drain::IssueWindow;
+    use crate::mem::*;
+
+    let region = (0x80000000, 0x80001000);
+    let mut mem: Mem<_, 8> = Mem::new(FixedDelay::new(5));
+    assert_eq!(measure_steady_latency(&mut mem, region, 0.5, 20, 100), 5.0);
+    let mut mem: Mem<_, 8> = Mem::new(FixedDelay::new(5));
+    assert_eq!(measure_steady_latency(&mut mem, region, 3.0, 20, 100), 5.0);
+
+    // Offering two requests per tick to a port issuing one: every request waits behind half of
+    // those pushed before it
+    let mem: Mem<_, 8> = Mem::new(FixedDelay::new(5));
+    let mut window = IssueWindow::new(mem, 64, 1);
+    let latency = measure_steady_latency(&mut window, region, 2.0, 20, 100);
+    assert!(latency > 5.0 + 20.0, "{}", latency);
+}
+
+#[test]
+#[should_panic(expected = "No request pushed in the 5 measured ticks at 0.1 per tick")]
+fn test_steady_latency_empty_window() {
+    use crate::mem::*;
+    let mut mem: Mem<_, 8> = Mem::new(FixedDelay::new(5));
+    measure_steady_latency(&mut mem, (0x80000000, 0x80001000), 0.1, 0, 5);
+}
+
+/// Drops every write on the floor
+#[cfg(test)]
+struct Forgetful(crate::mem::Mem<crate::mem::NoDelay, 8>);
+
+#[cfg(test)]
+impl Drain for Forgetful {
+    type Req = MemReq<8>;
+    type Resp = MemResp<8>;
+
+    fn tick(&mut self) {
+        self.0.tick();
+    }
+
+    fn push(&mut self, mut req: MemReq<8>) {
+        req.wbe = [false; 8];
+        self.0.push(req);
+    }
+
+    fn pop(&mut self) -> Option<MemResp<8>> {
+        self.0.pop()
+    }
+}
+
+#[test]
+#[should_panic(expected = "Seed 7: read")]
+fn test_stress_detects_corruption() {
+    use crate::mem::*;
+    let mut mem = Forgetful(Mem::new(NoDelay::default()));
+    stress(&mut mem, &[(0x0, 0x100)], 1000, 4, 7);
+}
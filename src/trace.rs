@@ -0,0 +1,556 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use crate::crossbar::Routable;
+use crate::drain::{Drain, Identified, ProbeResult};
+use crate::mem::DelaySimulator;
+
+const MAGIC: &[u8; 4] = b"UST1";
+const RECORD_LEN: usize = 8 + 1 + 8 + 8;
+const LATENCY_MAGIC: &[u8; 4] = b"USL1";
+const LATENCY_RECORD_LEN: usize = 8 + 8;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum EventKind {
+    Push = 0,
+    Pop = 1,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TraceEvent {
+    pub tick: u64,
+    pub kind: EventKind,
+    pub addr: u64,
+    pub id: usize,
+}
+
+/// Where a `Tracer` puts the events it records
+pub trait TraceSink {
+    fn record(&mut self, ev: TraceEvent);
+}
+
+impl TraceSink for Vec<TraceEvent> {
+    fn record(&mut self, ev: TraceEvent) {
+        self.push(ev);
+    }
+}
+
+/// Keeps only the last `capacity` events
+pub struct Ring {
+    capacity: usize,
+    events: VecDeque<TraceEvent>,
+}
+
+impl TraceSink for Ring {
+    fn record(&mut self, ev: TraceEvent) {
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(ev);
+    }
+}
+
+/// Records every push and pop passing through to `inner` into `S`
+pub struct Tracer<D: Drain, S: TraceSink> {
+    inner: D,
+    tick: u64,
+    addrs: HashMap<usize, u64>,
+    sink: S,
+}
+
+pub type TraceWriter<D> = Tracer<D, Vec<TraceEvent>>;
+
+/// Like `TraceWriter`, but only retains the last `capacity` events
+pub type RingTracer<D> = Tracer<D, Ring>;
+
+impl<D: Drain, S: TraceSink> Tracer<D, S> {
+    pub fn with_sink(inner: D, sink: S) -> Self {
+        Tracer {
+            inner,
+            tick: 0,
+            addrs: HashMap::new(),
+            sink,
+        }
+    }
+
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    fn record(&mut self, kind: EventKind, addr: u64, id: usize) {
+        self.sink.record(TraceEvent {
+            tick: self.tick,
+            kind,
+            addr,
+            id,
+        });
+    }
+}
+
+impl<D: Drain> TraceWriter<D> {
+    pub fn new(inner: D) -> Self {
+        Tracer::with_sink(inner, Vec::new())
+    }
+
+    pub fn events(&self) -> &[TraceEvent] {
+        &self.sink
+    }
+
+    /// Binary format: the magic `UST1`, followed by one little-endian
+    /// `(tick: u64, kind: u8, addr: u64, id: u64)` record per event
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(MAGIC)?;
+        for ev in self.sink.iter() {
+            w.write_all(&ev.tick.to_le_bytes())?;
+            w.write_all(&[ev.kind as u8])?;
+            w.write_all(&ev.addr.to_le_bytes())?;
+            w.write_all(&(ev.id as u64).to_le_bytes())?;
+        }
+        w.flush()
+    }
+}
+
+impl<D: Drain> RingTracer<D> {
+    pub fn new(inner: D, capacity: usize) -> Self {
+        assert!(capacity > 0, "capacity must be positive");
+        Tracer::with_sink(
+            inner,
+            Ring {
+                capacity,
+                events: VecDeque::with_capacity(capacity),
+            },
+        )
+    }
+
+    /// Retained events, oldest first
+    pub fn recent(&self) -> Vec<TraceEvent> {
+        self.sink.events.iter().copied().collect()
+    }
+}
+
+pub fn read<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<TraceEvent>> {
+    let invalid = |msg| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+
+    let mut buf = Vec::new();
+    BufReader::new(File::open(path)?).read_to_end(&mut buf)?;
+    if buf.len() < MAGIC.len() || &buf[..MAGIC.len()] != MAGIC {
+        return Err(invalid("Not a trace file"));
+    }
+    let body = &buf[MAGIC.len()..];
+    if body.len() % RECORD_LEN != 0 {
+        return Err(invalid("Truncated trace record"));
+    }
+
+    body.chunks_exact(RECORD_LEN)
+        .map(|rec| {
+            let kind = match rec[8] {
+                0 => EventKind::Push,
+                1 => EventKind::Pop,
+                _ => return Err(invalid("Unknown trace event kind")),
+            };
+            Ok(TraceEvent {
+                tick: u64::from_le_bytes(rec[0..8].try_into().unwrap()),
+                kind,
+                addr: u64::from_le_bytes(rec[9..17].try_into().unwrap()),
+                id: u64::from_le_bytes(rec[17..25].try_into().unwrap()) as usize,
+            })
+        })
+        .collect()
+}
+
+impl<D: Drain, S: TraceSink> Drain for Tracer<D, S>
+where
+    D::Req: Routable<u64> + Identified,
+    D::Resp: Identified,
+{
+    type Req = D::Req;
+    type Resp = D::Resp;
+
+    fn tick(&mut self) {
+        self.tick += 1;
+        self.inner.tick();
+    }
+
+    fn tick_begin(&mut self) {
+        self.tick += 1;
+        self.inner.tick_begin();
+    }
+
+    fn tick_end(&mut self) {
+        self.inner.tick_end();
+    }
+
+    fn push(&mut self, req: Self::Req) {
+        self.addrs.insert(req.id(), req.addr());
+        self.record(EventKind::Push, req.addr(), req.id());
+        self.inner.push(req);
+    }
+
+    fn pop(&mut self) -> Option<Self::Resp> {
+        let resp = self.inner.pop()?;
+        let addr = self.addrs.remove(&resp.id()).unwrap_or_default();
+        self.record(EventKind::Pop, addr, resp.id());
+        Some(resp)
+    }
+
+    fn cancel(&mut self, id: usize) -> bool {
+        let cancelled = self.inner.cancel(id);
+        if cancelled {
+            self.addrs.remove(&id);
+        }
+        cancelled
+    }
+
+    fn probe(&self, addr: u64) -> ProbeResult {
+        self.inner.probe(addr)
+    }
+
+    fn kind_name(&self) -> &'static str {
+        self.inner.kind_name()
+    }
+
+    fn write_dot(&self, node: &str, out: &mut String) {
+        self.inner.write_dot(node, out);
+    }
+}
+
+/// Latency of each request in `events`, as `(addr, latency)` in push order. Requests that never
+/// completed are left out.
+pub fn latencies(events: &[TraceEvent]) -> Vec<(u64, usize)> {
+    let mut pushed = HashMap::new();
+    let mut records = Vec::new();
+    for ev in events {
+        match ev.kind {
+            EventKind::Push => {
+                pushed.insert(ev.id, records.len());
+                records.push((ev.addr, ev.tick, None));
+            }
+            EventKind::Pop => {
+                if let Some(idx) = pushed.remove(&ev.id) {
+                    records[idx].2 = Some(ev.tick);
+                }
+            }
+        }
+    }
+    records
+        .into_iter()
+        .filter_map(|(addr, issued, done)| Some((addr, (done? - issued) as usize)))
+        .collect()
+}
+
+/// An event that differs between two traces, or is only present in one of them
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Divergence {
+    /// Earliest tick of the two events
+    pub tick: u64,
+    pub id: usize,
+    pub kind: EventKind,
+    pub a: Option<TraceEvent>,
+    pub b: Option<TraceEvent>,
+}
+
+/// Aligns the events of `a` and `b` by id and kind, the n-th push (or pop) of an id in `a` with
+/// the n-th in `b`, and reports those whose tick or address differ, earliest first
+pub fn diff(a: &[TraceEvent], b: &[TraceEvent]) -> Vec<Divergence> {
+    // Each event of `b` along with its position in the trace
+    let mut others: HashMap<(usize, EventKind), VecDeque<(usize, TraceEvent)>> = HashMap::new();
+    for (idx, ev) in b.iter().enumerate() {
+        others
+            .entry((ev.id, ev.kind))
+            .or_default()
+            .push_back((idx, *ev));
+    }
+
+    let mut divergences = Vec::new();
+    for ev in a {
+        let other = others
+            .get_mut(&(ev.id, ev.kind))
+            .and_then(VecDeque::pop_front)
+            .map(|(_, other)| other);
+        if other.is_some_and(|other| other.tick == ev.tick && other.addr == ev.addr) {
+            continue;
+        }
+        divergences.push(Divergence {
+            tick: other.map_or(ev.tick, |other| other.tick.min(ev.tick)),
+            id: ev.id,
+            kind: ev.kind,
+            a: Some(*ev),
+            b: other,
+        });
+    }
+    // Left over in `b` only, kept in trace order
+    let mut extra: Vec<_> = others.into_values().flatten().collect();
+    extra.sort_by_key(|(idx, ev)| (ev.tick, *idx));
+    divergences.extend(extra.into_iter().map(|(_, ev)| Divergence {
+        tick: ev.tick,
+        id: ev.id,
+        kind: ev.kind,
+        a: None,
+        b: Some(ev),
+    }));
+    divergences.sort_by_key(|d| d.tick);
+    divergences
+}
+
+/// Binary format: the magic `USL1`, followed by one little-endian `(addr: u64, latency: u64)`
+/// record per access
+pub fn write_latencies<P: AsRef<Path>>(path: P, records: &[(u64, usize)]) -> std::io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    w.write_all(LATENCY_MAGIC)?;
+    for (addr, latency) in records {
+        w.write_all(&addr.to_le_bytes())?;
+        w.write_all(&(*latency as u64).to_le_bytes())?;
+    }
+    w.flush()
+}
+
+/// Replays recorded latencies: the n-th access to an address takes the n-th latency recorded
+/// for it, and accesses beyond the recording take `default`
+pub struct TraceDelay {
+    default: usize,
+    recorded: HashMap<u64, VecDeque<usize>>,
+    tick: usize,
+    // Ordered by completion tick, ties in issue order
+    queue: VecDeque<(usize, u64)>,
+}
+
+impl TraceDelay {
+    pub fn new(records: &[(u64, usize)], default: usize) -> Self {
+        let mut recorded: HashMap<u64, VecDeque<usize>> = HashMap::new();
+        for (addr, latency) in records {
+            recorded.entry(*addr).or_default().push_back(*latency);
+        }
+        TraceDelay {
+            default,
+            recorded,
+            tick: 0,
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// Loads a file written by `write_latencies`
+    pub fn load<P: AsRef<Path>>(path: P, default: usize) -> std::io::Result<Self> {
+        let invalid = |msg| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+
+        let mut buf = Vec::new();
+        BufReader::new(File::open(path)?).read_to_end(&mut buf)?;
+        if buf.len() < LATENCY_MAGIC.len() || &buf[..LATENCY_MAGIC.len()] != LATENCY_MAGIC {
+            return Err(invalid("Not a latency trace file"));
+        }
+        let body = &buf[LATENCY_MAGIC.len()..];
+        if body.len() % LATENCY_RECORD_LEN != 0 {
+            return Err(invalid("Truncated latency record"));
+        }
+
+        let records: Vec<_> = body
+            .chunks_exact(LATENCY_RECORD_LEN)
+            .map(|rec| {
+                (
+                    u64::from_le_bytes(rec[0..8].try_into().unwrap()),
+                    u64::from_le_bytes(rec[8..16].try_into().unwrap()) as usize,
+                )
+            })
+            .collect();
+        Ok(Self::new(&records, default))
+    }
+}
+
+impl DelaySimulator for TraceDelay {
+    fn tick(&mut self) {
+        self.tick += 1;
+    }
+
+    fn push(&mut self, addr: u64, _is_write: bool) {
+        let latency = self
+            .recorded
+            .get_mut(&addr)
+            .and_then(VecDeque::pop_front)
+            .unwrap_or(self.default);
+        let at = self.tick + latency;
+        let idx = self.queue.partition_point(|(t, _)| *t <= at);
+        self.queue.insert(idx, (at, addr));
+    }
+
+    fn pop(&mut self) -> Option<u64> {
+        if self.queue.front().is_some_and(|(t, _)| *t <= self.tick) {
+            self.queue.pop_front().map(|(_, addr)| addr)
+        } else {
+            None
+        }
+    }
+}
+
+#[test]
+fn test_ring_tracer() {
+    use crate::mem::*;
+    let mem: Mem<_, 8> = Mem::new(NoDelay::default());
+    let mut tracer = RingTracer::new(mem, 5);
+
+    for id in 0..6 {
+        tracer.push(MemReq::read(id, 0x80000000 + 8 * id as u64));
+        tracer.tick();
+        tracer.pop().unwrap();
+    }
+
+    // 12 events in total, of which the 5 most recent are kept
+    let recent = tracer.recent();
+    let summary: Vec<_> = recent.iter().map(|ev| (ev.kind, ev.id)).collect();
+    assert_eq!(
+        summary,
+        [
+            (EventKind::Pop, 3),
+            (EventKind::Push, 4),
+            (EventKind::Pop, 4),
+            (EventKind::Push, 5),
+            (EventKind::Pop, 5),
+        ]
+    );
+    assert_eq!(recent[0].addr, 0x80000018);
+    assert_eq!(recent[4].tick, 6);
+}
+
+#[test]
+fn test_trace_diff() {
+    use crate::mem::*;
+    let mem: Mem<_, 8> = Mem::new(FixedDelay::new(2));
+    let mut tracer = TraceWriter::new(mem);
+    for id in 0..4 {
+        tracer.push(MemReq::read(id, 0x80000000 + 8 * id as u64));
+        while tracer.pop().is_none() {
+            tracer.tick();
+        }
+    }
+    let a = tracer.events().to_vec();
+    assert!(diff(&a, &a).is_empty());
+
+    // Request 2 completes a tick later
+    let mut b = a.clone();
+    assert_eq!((b[5].id, b[5].kind), (2, EventKind::Pop));
+    b[5].tick += 1;
+    assert_eq!(
+        diff(&a, &b),
+        [Divergence {
+            tick: a[5].tick,
+            id: 2,
+            kind: EventKind::Pop,
+            a: Some(a[5]),
+            b: Some(b[5]),
+        }]
+    );
+
+    // Request 1 went elsewhere, and request 3 never completed
+    let mut c = a.clone();
+    c[2].addr = 0x80001008;
+    c.pop();
+    let divergences = diff(&a, &c);
+    assert_eq!(divergences.len(), 2);
+    assert_eq!(
+        (divergences[0].id, divergences[0].kind),
+        (1, EventKind::Push)
+    );
+    assert_eq!(divergences[0].b.unwrap().addr, 0x80001008);
+    assert_eq!((divergences[1].id, divergences[1].b), (3, None));
+}
+
+#[test]
+fn test_trace_diff_extra_order() {
+    let b: Vec<_> = (0..16)
+        .map(|id| TraceEvent {
+            tick: 1 + id as u64 / 4,
+            kind: EventKind::Push,
+            addr: 0x80000000,
+            id,
+        })
+        .collect();
+    // Events only in `b` keep their trace order, even within a tick
+    let extra: Vec<_> = diff(&[], &b).into_iter().map(|d| d.b.unwrap()).collect();
+    assert_eq!(extra, b);
+}
+
+#[test]
+fn test_trace_roundtrip() {
+    use crate::drain::Delay;
+    use crate::mem::*;
+    let mem: Mem<_, 8> = Mem::new(NoDelay::default());
+    let mut tracer = TraceWriter::new(Delay::new(mem, 2, 3));
+
+    for id in 0..4 {
+        tracer.push(MemReq::read(id, 0x80000000 + 8 * id as u64));
+        tracer.tick();
+    }
+    let mut popped = 0;
+    while popped < 4 {
+        tracer.tick();
+        while tracer.pop().is_some() {
+            popped += 1;
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("trace.bin");
+    tracer.write(&path).unwrap();
+    let events = read(&path).unwrap();
+
+    assert_eq!(events, tracer.events());
+    assert_eq!(events.len(), 8);
+    let pushes: Vec<_> = events
+        .iter()
+        .filter(|e| e.kind == EventKind::Push)
+        .collect();
+    assert_eq!(pushes.len(), 4);
+    for (i, ev) in pushes.iter().enumerate() {
+        assert_eq!(ev.tick, i as u64);
+        assert_eq!(ev.id, i);
+    }
+    for ev in events.iter().filter(|e| e.kind == EventKind::Pop) {
+        let push = pushes.iter().find(|p| p.id == ev.id).unwrap();
+        assert_eq!(ev.addr, push.addr);
+        assert_eq!(ev.tick, push.tick + 5);
+    }
+}
+
+#[test]
+fn test_trace_delay_replay() {
+    use crate::drain::Delay;
+    use crate::mem::*;
+    let addrs = [0x80000000, 0x80000040, 0x80000000];
+
+    // Record a run through a delayed memory, issuing one request at a time
+    let mem: Mem<_, 8> = Mem::new(FixedDelay::new(3));
+    let mut tracer = TraceWriter::new(Delay::new(mem, 1, 2));
+    for (id, addr) in addrs.into_iter().enumerate() {
+        tracer.push(MemReq::read(id, addr));
+        while tracer.pop().is_none() {
+            tracer.tick();
+        }
+    }
+    let records = latencies(tracer.events());
+    assert_eq!(records, [(0x80000000, 6), (0x80000040, 6), (0x80000000, 6)]);
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("latency.bin");
+    write_latencies(&path, &[(0x80000000, 4), (0x80000040, 9), (0x80000000, 1)]).unwrap();
+    let mut mem: Mem<_, 8> = Mem::new(TraceDelay::load(&path, 2).unwrap());
+    let mut run = |req| {
+        mem.push(req);
+        let mut ticks = 0;
+        loop {
+            mem.tick();
+            ticks += 1;
+            if mem.pop().is_some() {
+                break ticks;
+            }
+        }
+    };
+    assert_eq!(run(MemReq::read(0, 0x80000000)), 4);
+    assert_eq!(run(MemReq::read(1, 0x80000040)), 9);
+    assert_eq!(run(MemReq::read(2, 0x80000000)), 1);
+    // Past the recording, and never recorded
+    assert_eq!(run(MemReq::read(3, 0x80000000)), 2);
+    assert_eq!(run(MemReq::read(4, 0x80000080)), 2);
+}
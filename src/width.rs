@@ -0,0 +1,294 @@
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    vec::Vec,
+};
+
+use crate::drain::Drain;
+use crate::mem::{MemReq, MemResp};
+
+/// Order in which the narrow sub-transfers of a wide request are issued
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SubOrder {
+    Ascending,
+    Descending,
+    /// Starts from the sub-transfer containing the given byte offset, then wraps around in
+    /// ascending order
+    CriticalWordFirst(usize),
+}
+
+/// Splits `WIDE`-byte requests into `NARROW`-byte requests to the inner drain, and reassembles
+/// the responses. Each sub-request carries its slice of `wbe` and `rbe`, so masks may straddle
+/// narrow chunk boundaries.
+pub struct WidthAdapter<D, const WIDE: usize, const NARROW: usize>
+where
+    D: Drain<Req = MemReq<NARROW>, Resp = MemResp<NARROW>>,
+{
+    inner: D,
+    order: SubOrder,
+
+    next_id: usize,
+    // Sub-request id -> (request id, sub-transfer index)
+    subs: BTreeMap<usize, (usize, usize)>,
+    // Request id -> (data so far, outstanding sub-requests)
+    partial: BTreeMap<usize, ([u8; WIDE], usize)>,
+    done: VecDeque<MemResp<WIDE>>,
+}
+
+impl<D, const WIDE: usize, const NARROW: usize> WidthAdapter<D, WIDE, NARROW>
+where
+    D: Drain<Req = MemReq<NARROW>, Resp = MemResp<NARROW>>,
+{
+    pub fn new(inner: D, order: SubOrder) -> Self {
+        assert!(
+            NARROW > 0 && WIDE.is_multiple_of(NARROW),
+            "WIDE must be a multiple of NARROW"
+        );
+        WidthAdapter {
+            inner,
+            order,
+            next_id: 0,
+            subs: BTreeMap::new(),
+            partial: BTreeMap::new(),
+            done: VecDeque::new(),
+        }
+    }
+
+    fn sub_index(&self, i: usize) -> usize {
+        let n = WIDE / NARROW;
+        match self.order {
+            SubOrder::Ascending => i,
+            SubOrder::Descending => n - 1 - i,
+            SubOrder::CriticalWordFirst(offset) => {
+                assert!(
+                    offset < WIDE,
+                    "Critical word offset out of range: {}",
+                    offset
+                );
+                (offset / NARROW + i) % n
+            }
+        }
+    }
+}
+
+impl<D, const WIDE: usize, const NARROW: usize> Drain for WidthAdapter<D, WIDE, NARROW>
+where
+    D: Drain<Req = MemReq<NARROW>, Resp = MemResp<NARROW>>,
+{
+    type Req = MemReq<WIDE>;
+    type Resp = MemResp<WIDE>;
+
+    fn tick(&mut self) {
+        self.inner.tick();
+    }
+
+    fn tick_begin(&mut self) {
+        self.inner.tick_begin();
+    }
+
+    fn tick_end(&mut self) {
+        self.inner.tick_end();
+    }
+
+    fn push(&mut self, req: MemReq<WIDE>) {
+        if req.size == 0 || req.size > WIDE {
+            panic!(
+                "Request {} has size {}, expected 1 to {}",
+                req.id, req.size, WIDE
+            );
+        }
+        // Sub-transfers past `size` are left out entirely
+        let subs: Vec<usize> = (0..WIDE / NARROW)
+            .map(|i| self.sub_index(i))
+            .filter(|idx| idx * NARROW < req.size)
+            .collect();
+        if self
+            .partial
+            .insert(req.id, ([0; WIDE], subs.len()))
+            .is_some()
+        {
+            panic!("Duplicated inflight request id: {}", req.id);
+        }
+        for idx in subs {
+            let bytes = idx * NARROW..(idx + 1) * NARROW;
+            let size = (req.size - bytes.start).min(NARROW);
+            let id = self.next_id;
+            self.next_id = self.next_id.wrapping_add(1);
+            self.subs.insert(id, (req.id, idx));
+            self.inner.push(MemReq {
+                id,
+                addr: req.addr + bytes.start as u64,
+                wbe: req.wbe[bytes.clone()].try_into().unwrap(),
+                wdata: req.wdata[bytes.clone()].try_into().unwrap(),
+                rbe: req.rbe.map(|rbe| rbe[bytes].try_into().unwrap()),
+                size,
+                src: req.src,
+            });
+        }
+    }
+
+    fn pop(&mut self) -> Option<MemResp<WIDE>> {
+        while let Some(resp) = self.inner.pop() {
+            // Every beat carries the whole narrow chunk, so only the last one is used
+            if !resp.last {
+                assert!(
+                    self.subs.contains_key(&resp.id),
+                    "Response for unknown request id"
+                );
+                continue;
+            }
+            let (id, idx) = self
+                .subs
+                .remove(&resp.id)
+                .expect("Response for unknown request id");
+            let (rdata, outstanding) = self.partial.get_mut(&id).unwrap();
+            rdata[idx * NARROW..(idx + 1) * NARROW].copy_from_slice(&resp.rdata);
+            *outstanding -= 1;
+            if *outstanding == 0 {
+                let (rdata, _) = self.partial.remove(&id).unwrap();
+                self.done.push_back(MemResp {
+                    id,
+                    rdata,
+                    beat: 0,
+                    last: true,
+                    src: resp.src,
+                });
+            }
+        }
+        self.done.pop_front()
+    }
+
+    fn cancel(&mut self, id: usize) -> bool {
+        if let Some(idx) = self.done.iter().position(|resp| resp.id == id) {
+            self.done.remove(idx);
+            return true;
+        }
+        if self.partial.remove(&id).is_none() {
+            return false;
+        }
+        let subs: Vec<usize> = self
+            .subs
+            .iter()
+            .filter(|(_, (parent, _))| *parent == id)
+            .map(|(sub, _)| *sub)
+            .collect();
+        for sub in subs {
+            self.subs.remove(&sub);
+            self.inner.cancel(sub);
+        }
+        true
+    }
+
+    fn kind_name(&self) -> &'static str {
+        self.inner.kind_name()
+    }
+
+    fn write_dot(&self, node: &str, out: &mut String) {
+        self.inner.write_dot(node, out);
+    }
+}
+
+#[cfg(test)]
+struct Recorder<D: Drain<Req = MemReq<8>>> {
+    inner: D,
+    seen: Vec<u64>,
+}
+
+#[cfg(test)]
+impl<D: Drain<Req = MemReq<8>>> Drain for Recorder<D> {
+    type Req = D::Req;
+    type Resp = D::Resp;
+
+    fn tick(&mut self) {
+        self.inner.tick();
+    }
+
+    fn push(&mut self, req: Self::Req) {
+        self.seen.push(req.addr);
+        self.inner.push(req);
+    }
+
+    fn pop(&mut self) -> Option<Self::Resp> {
+        self.inner.pop()
+    }
+}
+
+#[test]
+fn test_sub_order() {
+    use crate::mem::{Mem, NoDelay};
+
+    let mut wdata = [0; 32];
+    for (i, b) in wdata.iter_mut().enumerate() {
+        *b = i as u8 + 1;
+    }
+
+    let read = |order| {
+        let mem: Mem<_, 8> = Mem::new(NoDelay::default());
+        let mut adapter: WidthAdapter<_, 32, 8> = WidthAdapter::new(
+            Recorder {
+                inner: mem,
+                seen: Vec::new(),
+            },
+            order,
+        );
+        let mut run = |req| {
+            adapter.push(req);
+            loop {
+                adapter.tick();
+                if let Some(resp) = adapter.pop() {
+                    break resp.rdata;
+                }
+            }
+        };
+        run(MemReq::write(0, 0x80000000, [true; 32], wdata));
+        let rdata = run(MemReq::read(1, 0x80000000));
+        (rdata, adapter.inner.seen.split_off(4))
+    };
+
+    let (asc, asc_order) = read(SubOrder::Ascending);
+    let (desc, desc_order) = read(SubOrder::Descending);
+    let (cwf, cwf_order) = read(SubOrder::CriticalWordFirst(20));
+    assert_eq!(asc, wdata);
+    assert_eq!(desc, wdata);
+    assert_eq!(cwf, wdata);
+
+    assert_eq!(asc_order, [0x80000000, 0x80000008, 0x80000010, 0x80000018]);
+    assert_eq!(desc_order, [0x80000018, 0x80000010, 0x80000008, 0x80000000]);
+    assert_eq!(cwf_order, [0x80000010, 0x80000018, 0x80000000, 0x80000008]);
+}
+
+#[test]
+fn test_straddling_mask() {
+    use crate::mem::{Mem, NoDelay};
+
+    let mem: Mem<_, 8> = Mem::new(NoDelay::default());
+    let mut adapter: WidthAdapter<_, 16, 8> = WidthAdapter::new(mem, SubOrder::Ascending);
+    let mut run = |req| {
+        adapter.push(req);
+        loop {
+            adapter.tick();
+            if let Some(resp) = adapter.pop() {
+                break resp.rdata;
+            }
+        }
+    };
+
+    run(MemReq::write(0, 0x80000000, [true; 16], [0xaa; 16]));
+    // Bytes 6..10, across the boundary between the two chunks
+    let mut wbe = [false; 16];
+    wbe[6..10].fill(true);
+    let wdata: [u8; 16] = core::array::from_fn(|i| i as u8);
+    run(MemReq::write(1, 0x80000000, wbe, wdata));
+    let rdata = run(MemReq::read(2, 0x80000000));
+
+    let mut expected = [0xaa; 16];
+    expected[6..10].copy_from_slice(&[6, 7, 8, 9]);
+    assert_eq!(rdata, expected);
+    assert_eq!(
+        adapter.inner.iter_chunks().collect::<Vec<_>>(),
+        [
+            (0x80000000, &[0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 6, 7]),
+            (0x80000008, &[8, 9, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa]),
+        ]
+    );
+}